@@ -0,0 +1,299 @@
+use clap::Args;
+use config::{Config, Layered};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use std::{
+    fmt::Display,
+    fs::{OpenOptions, Permissions},
+    io::{self, BufRead, IsTerminal, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use storage::{AuditAction, EMail, Registry, Storages};
+use toml::Value;
+
+use crate::{audit, CLIError, UserCommandsError};
+
+const SECRET_KEY_LENGTH: usize = 64;
+const PASSWORD_LENGTH: usize = 20;
+
+#[derive(Args, Clone, Debug)]
+pub(crate) struct Init {
+    /// The hostname of the api, which the tablets connect to, like rmcloud.example.com.
+    #[clap(long)]
+    api_url: Option<String>,
+    /// The hostname of the admin UI, like admin.rmcloud.example.com.
+    #[clap(long)]
+    ui_url: Option<String>,
+    /// The port for api and admin UI, which are told apart by the hostname.
+    #[clap(long)]
+    port: Option<u16>,
+    /// Serves only the api on this port, if there are no DNS names for the hostnames.
+    #[clap(long)]
+    api_port: Option<u16>,
+    /// Serves only the admin UI on this port.
+    #[clap(long)]
+    ui_port: Option<u16>,
+    /// The directory of users, documents and codes.
+    #[clap(long, value_parser)]
+    data_dir: Option<PathBuf>,
+    /// Creates the first admin with this email.
+    #[clap(long)]
+    admin_email: Option<String>,
+    /// Reads the password of the admin from the first line of stdin, instead of asking for it.
+    /// A random one is printed, if it is neither given nor entered.
+    #[clap(long, requires = "admin-email")]
+    admin_password_stdin: bool,
+    /// Takes the defaults for everything, which is not given as a flag, instead of asking.
+    #[clap(long, short)]
+    yes: bool,
+    /// Overwrites an existing config file.
+    #[clap(long)]
+    force: bool,
+}
+
+/// Asks for the values, which are not given as flags. Without a terminal, the defaults are taken.
+struct Prompt {
+    interactive: bool,
+}
+
+impl Prompt {
+    fn ask<T: FromStr + Display>(
+        &self,
+        question: &str,
+        flag: Option<T>,
+        default: T,
+    ) -> io::Result<T> {
+        if let Some(v) = flag {
+            return Ok(v);
+        }
+        Ok(self
+            .ask_optional(&format!("{} [{}]", question, default), None)?
+            .unwrap_or(default))
+    }
+
+    /// An empty answer means none.
+    fn ask_optional<T: FromStr>(&self, question: &str, flag: Option<T>) -> io::Result<Option<T>> {
+        if flag.is_some() {
+            return Ok(flag);
+        }
+        loop {
+            match self.read(question)? {
+                None => return Ok(None),
+                Some(v) => match T::from_str(&v) {
+                    Ok(v) => return Ok(Some(v)),
+                    Err(_) => println!("`{}` is not valid, try again.", v),
+                },
+            }
+        }
+    }
+
+    /// The trimmed answer, none for an empty one or without a terminal.
+    fn read(&self, question: &str) -> io::Result<Option<String>> {
+        if !self.interactive {
+            return Ok(None);
+        }
+        print!("{}: ", question);
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        Ok(Some(answer.trim().to_string()).filter(|v| !v.is_empty()))
+    }
+
+    /// Like `read`, but the answer is not echoed and kept as it is typed.
+    fn read_password(&self, question: &str) -> io::Result<Option<String>> {
+        if !self.interactive {
+            return Ok(None);
+        }
+        let answer = rpassword::prompt_password(format!("{}: ", question))?;
+        Ok(Some(answer).filter(|v| !v.is_empty()))
+    }
+}
+
+/// The first line of stdin without its line break, so it works with `echo` and files alike.
+fn read_password_stdin() -> Result<String, CLIError> {
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    let password = line.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        return Err(CLIError::EmptyPassword);
+    }
+    Ok(password.to_string())
+}
+
+fn random(length: usize) -> String {
+    OsRng
+        .sample_iter(&Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+/// A quoted toml string, so hostnames and paths need no escaping by hand.
+fn quote(value: &str) -> String {
+    Value::String(value.to_string()).to_string()
+}
+
+/// An optional key is written commented out with an example, if it is not given.
+fn optional_port(key: &str, port: Option<u16>, example: u16) -> String {
+    match port {
+        Some(v) => format!("{} = {}", key, v),
+        None => format!("#{} = {}", key, example),
+    }
+}
+
+struct Settings {
+    api_url: String,
+    ui_url: String,
+    port: u16,
+    api_port: Option<u16>,
+    ui_port: Option<u16>,
+    data_dir: PathBuf,
+    secret_key: String,
+}
+
+impl Settings {
+    fn render(&self) -> String {
+        let data_dir = self.data_dir.to_string_lossy();
+        let socket = self.data_dir.join("rmcloud.sock");
+        format!(
+            r#"# Written by `rmcloud config init`, `rmcloud config check` validates it after changes.
+# Every key can be overridden with an env var like RMCLOUD_COMMON_PORT for COMMON.PORT.
+# The config.example.toml of the repository lists all keys.
+[COMMON]
+LOGLEVEL = "info"
+PORT = {port}
+# the unix socket of the cli, it is only accessible for the user running the server
+SOCKET = {socket}
+# optional, the addresses to listen on, defaults to 127.0.0.1. Use ["0.0.0.0", "::"] for all ipv4 and ipv6 interfaces.
+#BIND = ["0.0.0.0", "::"]
+# optional, serve API and UI on their own ports, if there are no DNS names for host based routing
+{api_port}
+{ui_port}
+
+[UI]
+# the hostname of the admin UI, without http://
+URL = {ui_url}
+
+[API]
+# a long random string, keep it secret
+SECRET_KEY = {secret_key}
+# the hostname, which the tablets connect to, without http://
+URL = {api_url}
+# users, documents and codes are kept here
+DATADIR = {data_dir}
+
+# optional, sends the mails of the admin UI
+#[API.SMTP]
+#SERVER = "smtp.example.com:465"
+#USERNAME = "rmcloud@example.com"
+#PASSWORD = "MY_PASSWORD"
+
+# optional, the handwriting recognition of MyScript
+#[API.HWR]
+#APPLICATIONKEY = "SOME_KEY"
+#HMAC = "SOME_KEY"
+"#,
+            port = self.port,
+            socket = quote(&socket.to_string_lossy()),
+            api_port = optional_port("API_PORT", self.api_port, 8081),
+            ui_port = optional_port("UI_PORT", self.ui_port, 8082),
+            ui_url = quote(&self.ui_url),
+            secret_key = quote(&self.secret_key),
+            api_url = quote(&self.api_url),
+            data_dir = quote(&data_dir),
+        )
+    }
+}
+
+impl Init {
+    /// Writes the config, only readable by the current user as it holds the SECRET_KEY.
+    /// Nothing is written, if the config is not valid.
+    pub(crate) fn run(&self, config_path: &Path, backends: &Registry) -> Result<(), CLIError> {
+        if config_path.exists() && !self.force {
+            return Err(CLIError::ConfigExists(config_path.to_path_buf()));
+        }
+
+        let prompt = Prompt {
+            interactive: !self.yes && io::stdin().is_terminal(),
+        };
+        let port = prompt.ask("Port", self.port, 8080)?;
+        // api and admin UI are told apart by the host header on the same port
+        let api_url = prompt.ask(
+            "Hostname of the api, which the tablets connect to",
+            self.api_url.clone(),
+            format!("api.localhost:{}", port),
+        )?;
+        let ui_url = prompt.ask(
+            "Hostname of the admin UI",
+            self.ui_url.clone(),
+            format!("admin.localhost:{}", port),
+        )?;
+        let api_port = prompt.ask_optional("Port for only the api [none]", self.api_port)?;
+        let ui_port = prompt.ask_optional("Port for only the admin UI [none]", self.ui_port)?;
+        let data_dir = match &self.data_dir {
+            Some(v) => v.clone(),
+            None => PathBuf::from(prompt.ask("Data dir", None, "./data".to_string())?),
+        };
+        let admin = loop {
+            let email =
+                prompt.ask_optional("Email of the first admin [none]", self.admin_email.clone())?;
+            match email.as_deref().map(EMail::create).transpose() {
+                Ok(v) => break v,
+                Err(_) if prompt.interactive && self.admin_email.is_none() => {
+                    println!("The email is not valid, try again.")
+                }
+                Err(v) => return Err(UserCommandsError::from(v).into()),
+            }
+        };
+        // none takes a random password
+        let password = match (&admin, self.admin_password_stdin) {
+            (None, _) => None,
+            (Some(_), true) => Some(read_password_stdin()?),
+            (Some(_), false) => prompt.read_password("Password of the admin [random]")?,
+        };
+
+        let settings = Settings {
+            api_url,
+            ui_url,
+            port,
+            api_port,
+            ui_port,
+            data_dir,
+            secret_key: random(SECRET_KEY_LENGTH),
+        };
+        let text = settings.render();
+        Config::create(&text)?;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(config_path)?;
+        // the mode only applies to a new file, an overwritten one may be readable by others
+        file.set_permissions(Permissions::from_mode(0o600))?;
+        file.write_all(text.as_bytes())?;
+        println!("Config written to {}.", config_path.display());
+
+        if let Some(email) = admin {
+            let password = password.unwrap_or_else(|| {
+                let password = random(PASSWORD_LENGTH);
+                println!("Password of {}: {}", email.0, password);
+                password
+            });
+            let Storages {
+                user, audit: log, ..
+            } = backends.build(&Layered::load(config_path)?.config)?;
+            user.create_user(&email, &password, &true, &false)
+                .map_err(UserCommandsError::from)?;
+            audit(log.as_ref(), AuditAction::UserCreated, &email.0);
+        }
+
+        println!(
+            "Start the server with `rmcloud -c {}`.",
+            config_path.display()
+        );
+        Ok(())
+    }
+}
@@ -1,9 +1,19 @@
+mod init;
+
+use chrono::{DateTime, Utc};
 use clap::{Args, Parser, Subcommand};
-use config::Config;
-use std::{io::Write, net::TcpStream, path::PathBuf};
-use storage::{CodeStorage, EMail, EMailError, LocalStorageError, StoragesError, UserStorage};
+use config::{Config, ConfigError, Layered, Source};
+use control::{ControlError, ErrorCode, Reply, Request, Response};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use storage::{
+    AttemptStorage, AuditAction, AuditEvent, AuditFilter, AuditSource, AuditStorage, CodeStorage,
+    EMail, EMailError, LocalStorageError, Storages, StoragesError, UserStorage,
+};
 use thiserror::Error;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
 
 #[derive(Error, Debug)]
 pub enum CLIError {
@@ -17,6 +27,18 @@ pub enum CLIError {
     StoragesError(#[from] StoragesError),
     #[error("LocalStorage had an error")]
     LocalStorageError(#[from] LocalStorageError),
+    #[error("Config had an error")]
+    ConfigError(#[from] config::ConfigError),
+    #[error("Cannot talk to the server, is it running?")]
+    ControlError(#[from] ControlError),
+    #[error("Server answered with {}: {1}", .0.name())]
+    ServerRefused(ErrorCode, String),
+    #[error("{0} exists already, use --force to overwrite it")]
+    ConfigExists(PathBuf),
+    #[error("The password on stdin is empty")]
+    EmptyPassword,
+    #[error("Cannot ask for the settings or write the config")]
+    IoError(#[from] std::io::Error),
 }
 
 #[derive(Error, Debug)]
@@ -25,6 +47,8 @@ pub enum UserCommandsError {
     LocalStorageError(#[from] LocalStorageError),
     #[error("Error occurred in email validation")]
     EMailError(#[from] EMailError),
+    #[error("Users cannot be written as json")]
+    JsonError(#[from] serde_json::Error),
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -40,15 +64,128 @@ pub struct CliArgs {
     )]
     pub config_path: PathBuf,
 
+    /// Overrides a key of the config file and the RMCLOUD_* env vars, like --set COMMON.PORT=8080.
+    /// Lists are separated by commas.
+    #[clap(long = "set", value_name = "KEY=VALUE", value_parser = parse_override, global = true)]
+    pub overrides: Vec<(String, String)>,
+
     #[clap(subcommand)]
     command: Option<Commands>,
 }
 
+fn parse_override(value: &str) -> Result<(String, String), String> {
+    value
+        .split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("`{}` is not KEY=VALUE", value))
+}
+
 #[derive(Subcommand, Clone, Debug)]
 enum Commands {
     /// All user relevant commands.
     #[clap(arg_required_else_help = true)]
     User(User),
+    /// List or clear the lockouts after failed logins.
+    #[clap(arg_required_else_help = true)]
+    Lockout(Lockout),
+    /// Search the audit log, e.g. to find out who deleted a user.
+    Audit(Audit),
+    /// Talk to the running server through its cli socket.
+    #[clap(arg_required_else_help = true)]
+    Server(Server),
+    /// Create or inspect the config, as it is merged from the file, env vars and flags.
+    #[clap(arg_required_else_help = true)]
+    Config(ConfigCmd),
+}
+
+#[derive(Args, Clone, Debug)]
+struct ConfigCmd {
+    #[clap(subcommand)]
+    command: Option<ConfigCommands>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum ConfigCommands {
+    /// Show the keys, which are set by the file, env vars or flags, and where they come from.
+    Show {
+        /// Show all keys with the values in use, including the defaults.
+        #[clap(long)]
+        effective: bool,
+    },
+    /// Check the config and report every problem with its line, env var or flag.
+    Check,
+    /// Write a new config with a random SECRET_KEY and create the first admin.
+    /// Asks for everything, which is not given as a flag.
+    Init(init::Init),
+}
+
+#[derive(Args, Clone, Debug)]
+struct Server {
+    #[clap(subcommand)]
+    command: Option<ServerCommands>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum ServerCommands {
+    /// Show version, uptime and open sessions.
+    Status,
+    /// List the logged in sessions of the admin UI.
+    Sessions,
+    /// Revoke the device in the running server, so all of its tokens are not valid anymore.
+    KickDevice { email: String, id: String },
+    /// Remove expired codes, UI sessions and forgotten login attempts.
+    Gc,
+    /// Make the server read users, codes or its config again.
+    Reload {
+        #[clap(value_enum)]
+        target: ReloadTarget,
+    },
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum ReloadTarget {
+    Users,
+    Codes,
+    Config,
+}
+
+#[derive(Args, Clone, Debug)]
+struct Audit {
+    /// Only events of this actor, like an email or cli:<os user>.
+    #[clap(long)]
+    actor: Option<String>,
+    /// Only events on this user or device.
+    #[clap(long)]
+    target: Option<String>,
+    /// Only this action or all actions with this prefix, like user.deleted or user.
+    #[clap(long)]
+    action: Option<String>,
+    /// Only events from cli, api or ui.
+    #[clap(long, value_parser)]
+    source: Option<AuditSource>,
+    /// Only events at or after this time, like 2022-08-01T00:00:00Z.
+    #[clap(long, value_parser)]
+    since: Option<DateTime<Utc>>,
+    /// Only events at or before this time.
+    #[clap(long, value_parser)]
+    until: Option<DateTime<Utc>>,
+    /// Show only the newest events.
+    #[clap(long, value_parser)]
+    limit: Option<usize>,
+}
+
+#[derive(Args, Clone, Debug)]
+struct Lockout {
+    #[clap(subcommand)]
+    command: Option<LockoutCommands>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum LockoutCommands {
+    /// List all accounts and ips with failed logins.
+    List,
+    /// Clear the lockout of the given email or ip.
+    Clear { target: String },
 }
 
 #[derive(Args, Clone, Debug)]
@@ -61,6 +198,8 @@ struct User {
 enum UserCommands {
     /// Show all informations for the given email.
     Show { email: String },
+    /// List the users, e.g. to reconcile them with another system.
+    List(ListUsers),
     /// Edit the user with the given email and all relevant informations.
     Edit {
         email: String,
@@ -79,143 +218,685 @@ enum UserCommands {
     Delete { email: String },
     /// Generate a code to access.
     Generate { email: String },
-    /// Validate a code.
+    /// Validate a code without using it.
     Validate { email: String, code: String },
+    /// Remove the second factor, e.g. if the authenticator and the recovery codes are lost.
+    ResetSecondFactor { email: String },
+    /// List, rename or revoke the paired devices of a user.
+    Devices {
+        #[clap(subcommand)]
+        command: DeviceCommands,
+    },
+}
+
+#[derive(Args, Clone, Debug)]
+struct ListUsers {
+    /// Only admins.
+    #[clap(long)]
+    admins: bool,
+    /// Only users, who sync with the 1.5 protocol.
+    #[clap(long)]
+    sync15: bool,
+    /// Only users created after this time, like 2022-08-01T00:00:00Z.
+    /// Users of older versions are created when their folder was.
+    #[clap(long, value_parser)]
+    created_after: Option<DateTime<Utc>>,
+    #[clap(long, value_enum, default_value = "email")]
+    sort: UserSort,
+    /// Reverse the order.
+    #[clap(long)]
+    reverse: bool,
+    #[clap(long, value_enum, default_value = "table")]
+    format: OutputFormat,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum UserSort {
+    Email,
+    /// oldest first, users with an unknown time last
+    Created,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+}
+
+/// A user as `user list` prints it, without the password.
+#[derive(Serialize, Debug)]
+struct ListedUser {
+    email: String,
+    is_admin: bool,
+    sync15: bool,
+    created: Option<DateTime<Utc>>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum DeviceCommands {
+    /// List all paired devices of the user with the given email.
+    List { email: String },
+    /// Change the description, which the device lists show.
+    Rename {
+        email: String,
+        id: String,
+        description: String,
+    },
+    /// Revoke the device, so all of its tokens are not valid anymore.
+    Revoke { email: String, id: String },
 }
 
 pub struct CLI {}
 
 impl CLI {
-    pub fn parse_args<U: UserStorage, C: CodeStorage>(
-    ) -> Result<(CliArgs, Box<U>, Box<C>), CLIError> {
-        // TODO: Add here the workflow to add a new user (as admin)
-        let args = CliArgs::parse();
-
+    /// Logs everything until the server applies LOGLEVEL through the returned handle.
+    pub fn init_logging() -> reload::Handle<EnvFilter, Registry> {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("debug"));
         tracing_subscriber::registry()
-            .with(tracing_subscriber::EnvFilter::new("debug"))
-            .with(tracing_subscriber::fmt::layer())
+            .with(filter)
+            // stdout is kept for the output of the commands, like the json of `user list`
+            .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
             .init();
+        handle
+    }
 
-        let mut user_storage = U::create(&args.config_path)?;
-        let mut code_storage = C::create(&args.config_path)?;
+    /// Executes the command with the storages of the registry. Returns the args, if there was none.
+    pub fn parse_args(backends: &storage::Registry) -> Result<CliArgs, CLIError> {
+        // TODO: Add here the workflow to add a new user (as admin)
+        let args = CliArgs::parse();
+        config::set_flags(args.overrides.clone())?;
+
+        // needs no storage, so it works with a broken data dir as well
+        if let Some(Commands::Config(c)) = &args.command {
+            c.parse(&args.config_path, backends)?;
+            return Err(CLIError::CommandFound);
+        }
 
         if let Some(cmd) = &args.command {
+            let config = load_config(&args.config_path)?;
+            let Storages {
+                user: mut user_storage,
+                code: mut code_storage,
+                attempt: attempt_storage,
+                audit: audit_storage,
+            } = backends.build(&config)?;
+
             match cmd {
                 Commands::User(u) => {
-                    u.parse(user_storage.as_mut(), code_storage.as_mut())?;
-                    let config = Config::create(
-                        &std::fs::read_to_string(&args.config_path)
-                            .expect("cannot read in config file."),
-                    )
-                    .expect("Cannot create config.");
-
-                    let mut stream =
-                        TcpStream::connect(format!("127.0.0.1:{}", config.common.socket))
-                            .expect("Cannot connect to cli socket.");
-
-                    stream
-                        .write("reload user 0".as_bytes())
-                        .expect("Cannot write to cli socket.");
+                    u.parse(
+                        user_storage.as_mut(),
+                        code_storage.as_mut(),
+                        audit_storage.as_ref(),
+                    )?;
+                    if let Some(request) = u.reload() {
+                        notify_server(&config.common.socket, &request);
+                    }
                 }
+                Commands::Lockout(l) => l.parse(attempt_storage.as_ref())?,
+                Commands::Audit(a) => a.query(audit_storage.as_ref())?,
+                Commands::Server(s) => s.parse(&config.common.socket, audit_storage.as_ref())?,
+                Commands::Config(_) => unreachable!("handled before the storages are created"),
             }
             return Err(CLIError::CommandFound);
         }
 
-        Ok((args, user_storage, code_storage))
+        Ok(args)
         //   Err(CLIError::ParseError)
     }
 }
 
+/// Reads the config and logs its warnings, like unknown keys.
+fn load_config(path: &Path) -> Result<Config, ConfigError> {
+    let layered = Layered::load(path)?;
+    log_warnings(&layered);
+    Ok(layered.config)
+}
+
+fn log_warnings(layered: &Layered) {
+    for v in &layered.warnings {
+        tracing::warn! {%v, "config warning"};
+    }
+}
+
+impl ConfigCmd {
+    fn parse(&self, config_path: &Path, backends: &storage::Registry) -> Result<(), CLIError> {
+        match &self.command {
+            Some(ConfigCommands::Show { effective }) => {
+                let layered = Layered::load(config_path)?;
+                log_warnings(&layered);
+                for (key, value, source) in layered.entries() {
+                    if *effective || source != Source::Default {
+                        println!("{} = {}  # {}", key, value, source);
+                    }
+                }
+            }
+            // the errors are returned, the warnings are part of the output
+            Some(ConfigCommands::Check) => {
+                let layered = Layered::load(config_path)?;
+                for warning in &layered.warnings {
+                    println!("warning: {}", warning);
+                }
+                println!(
+                    "{} is valid, {} warning(s)",
+                    config_path.display(),
+                    layered.warnings.len()
+                );
+            }
+            Some(ConfigCommands::Init(init)) => init.run(config_path, backends)?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+impl Lockout {
+    fn parse(&self, attempt_storage: &dyn AttemptStorage) -> Result<(), LocalStorageError> {
+        if let Some(v) = &self.command {
+            match v {
+                LockoutCommands::List => self.list(attempt_storage)?,
+                LockoutCommands::Clear { target } => self.clear(target, attempt_storage)?,
+            }
+        };
+
+        Ok(())
+    }
+
+    fn list(&self, attempt_storage: &dyn AttemptStorage) -> Result<(), LocalStorageError> {
+        let attempts = attempt_storage.list_attempts()?;
+        if attempts.is_empty() {
+            println!("No failed logins recorded.");
+        }
+        for (key, attempt) in attempts {
+            println!(
+                "{}  failures: {}  last failure: {}  locked until: {}",
+                key,
+                attempt.failures,
+                attempt.last_failure,
+                attempt
+                    .locked_until
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+            );
+        }
+        Ok(())
+    }
+
+    /// An email clears the account and second factor lockout, everything else is taken as an ip.
+    fn clear(
+        &self,
+        target: &str,
+        attempt_storage: &dyn AttemptStorage,
+    ) -> Result<(), LocalStorageError> {
+        let keys = match EMail::create(target) {
+            Ok(email) => vec![
+                format!("account:{}", email.0),
+                format!("second-factor:account:{}", email.0),
+            ],
+            Err(_) => vec![format!("ip:{}", target)],
+        };
+        let mut cleared = false;
+        for key in keys {
+            cleared |= attempt_storage.clear_attempts(&key)?;
+        }
+        if cleared {
+            println!("Lockout for {} cleared.", target);
+        } else {
+            println!("No lockout found for {}.", target);
+        }
+        Ok(())
+    }
+}
+
+impl Audit {
+    fn query(&self, audit_storage: &dyn AuditStorage) -> Result<(), LocalStorageError> {
+        let filter = AuditFilter {
+            actor: self.actor.clone(),
+            target: self.target.clone(),
+            action: self.action.clone(),
+            source: self.source,
+            since: self.since,
+            until: self.until,
+            limit: self.limit,
+        };
+        let events = audit_storage.query(&filter)?;
+        if events.is_empty() {
+            println!("No audit events found.");
+        }
+        for event in events {
+            println!(
+                "{}  {:<3}  {:<24}  {}  ->  {}  {}{}",
+                event.time.format("%Y-%m-%d %H:%M:%S"),
+                event.source.name(),
+                event.action.name(),
+                event.actor,
+                event.target.as_deref().unwrap_or("-"),
+                if event.success { "ok" } else { "failed" },
+                event
+                    .ip
+                    .map(|ip| format!("  ip: {}", ip))
+                    .unwrap_or_default(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Sends the request and turns an error response into an error.
+fn request_server(socket: &Path, request: &Request) -> Result<Reply, CLIError> {
+    match control::send(socket, request)? {
+        Response::Ok(reply) => Ok(reply),
+        Response::Error(e) => Err(CLIError::ServerRefused(e.code, e.message)),
+    }
+}
+
+/// Tells a running server about the changes of the cli. Without a server, there is nobody to tell.
+fn notify_server(socket: &Path, request: &Request) {
+    match request_server(socket, request) {
+        Ok(_) => tracing::debug! {?request, "server reloaded"},
+        Err(CLIError::ControlError(ControlError::IoError(v))) => {
+            tracing::debug! {?v, "no running server to notify"}
+        }
+        Err(v) => println!("The running server did not reload: {}", v),
+    }
+}
+
+impl Server {
+    fn parse(&self, socket: &Path, audit_storage: &dyn AuditStorage) -> Result<(), CLIError> {
+        if let Some(v) = &self.command {
+            match v {
+                ServerCommands::Status => {
+                    if let Reply::Status(status) = request_server(socket, &Request::Status)? {
+                        println!(
+                            "rmcloud {} running since {}, {} ui sessions, {} screenshare sessions",
+                            status.version,
+                            status.started_at.format("%Y-%m-%d %H:%M:%S"),
+                            status.ui_sessions,
+                            status.screenshare_sessions,
+                        );
+                    }
+                }
+                ServerCommands::Sessions => {
+                    if let Reply::Sessions { sessions } =
+                        request_server(socket, &Request::ListSessions)?
+                    {
+                        if sessions.is_empty() {
+                            println!("No sessions.");
+                        }
+                        for s in sessions {
+                            println!(
+                                "{}  {}  since: {}  last seen: {}  ip: {}  agent: {}",
+                                s.handle,
+                                s.email,
+                                s.created_at.format("%Y-%m-%d %H:%M:%S"),
+                                s.last_seen.format("%Y-%m-%d %H:%M:%S"),
+                                s.ip.as_deref().unwrap_or("-"),
+                                s.user_agent.as_deref().unwrap_or("-"),
+                            );
+                        }
+                    }
+                }
+                ServerCommands::KickDevice { email, id } => {
+                    let request = Request::KickDevice {
+                        email: email.clone(),
+                        device: id.clone(),
+                    };
+                    request_server(socket, &request)?;
+                    audit(
+                        audit_storage,
+                        AuditAction::DeviceRevoked,
+                        &format!("{}/{}", email, id),
+                    );
+                    println!("Device {} revoked.", id);
+                }
+                ServerCommands::Gc => {
+                    if let Reply::Collected(c) = request_server(socket, &Request::Gc)? {
+                        println!(
+                            "Expired codes cleaned, {} ui sessions and {} login attempts removed.",
+                            c.ui_sessions, c.attempts
+                        );
+                    }
+                }
+                ServerCommands::Reload { target } => {
+                    let request = match target {
+                        ReloadTarget::Users => Request::ReloadUsers,
+                        ReloadTarget::Codes => Request::ReloadCodes,
+                        ReloadTarget::Config => Request::ReloadConfig,
+                    };
+                    match request_server(socket, &request)? {
+                        Reply::Reloaded { restart_required } if !restart_required.is_empty() => {
+                            println!(
+                                "Reloaded, a restart is needed to apply: {}",
+                                restart_required.join(", ")
+                            )
+                        }
+                        _ => println!("Reloaded."),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The os user, who runs the cli, so the audit log tells who did it.
+fn cli_actor() -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".to_string());
+    format!("cli:{}", user)
+}
+
+/// The action is already done, so a failing audit log is only reported.
+fn audit(audit_storage: &dyn AuditStorage, action: AuditAction, target: &str) {
+    let event = AuditEvent::new(AuditSource::Cli, &cli_actor(), action).target(target);
+    if let Err(v) = audit_storage.append(&event) {
+        tracing::error! {?v, ?event, "cannot write audit event"};
+    }
+}
+
+/// Quotes the field, if it holds a separator, quote or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl ListUsers {
+    fn print(&self, user_storage: &dyn UserStorage) -> Result<(), UserCommandsError> {
+        let mut users: Vec<ListedUser> = user_storage
+            .list_users()?
+            .into_iter()
+            .filter(|v| !self.admins || v.is_admin())
+            .filter(|v| !self.sync15 || v.using_sync15())
+            .filter(|v| match self.created_after {
+                Some(after) => v.created().map(|v| v > after).unwrap_or(false),
+                None => true,
+            })
+            .map(|v| ListedUser {
+                email: v.get_email(),
+                is_admin: v.is_admin(),
+                sync15: v.using_sync15(),
+                created: v.created(),
+            })
+            .collect();
+
+        match self.sort {
+            // the storage lists them by email already
+            UserSort::Email => {}
+            UserSort::Created => users.sort_by_key(|v| (v.created.is_none(), v.created)),
+        }
+        if self.reverse {
+            users.reverse();
+        }
+
+        match self.format {
+            OutputFormat::Table => {
+                if users.is_empty() {
+                    println!("No users found.");
+                    return Ok(());
+                }
+                let width = users.iter().map(|v| v.email.len()).max().unwrap_or(0);
+                let yes_no = |v: bool| if v { "yes" } else { "no" };
+                println!("{:<width$}  ADMIN  SYNC15  CREATED", "EMAIL", width = width);
+                for user in users {
+                    println!(
+                        "{:<width$}  {:<5}  {:<6}  {}",
+                        user.email,
+                        yes_no(user.is_admin),
+                        yes_no(user.sync15),
+                        user.created
+                            .map(|v| v.format("%Y-%m-%d %H:%M:%S").to_string())
+                            .unwrap_or_else(|| "-".to_string()),
+                        width = width
+                    );
+                }
+            }
+            OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&users)?),
+            OutputFormat::Csv => {
+                println!("email,is_admin,sync15,created");
+                for user in users {
+                    println!(
+                        "{},{},{},{}",
+                        csv_field(&user.email),
+                        user.is_admin,
+                        user.sync15,
+                        user.created.map(|v| v.to_rfc3339()).unwrap_or_default()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
 impl User {
-    fn parse<U: UserStorage, C: CodeStorage>(
+    fn parse(
         &self,
-        user_storage: &mut U,
-        code_storage: &mut C,
+        user_storage: &mut dyn UserStorage,
+        code_storage: &mut dyn CodeStorage,
+        audit_storage: &dyn AuditStorage,
     ) -> Result<(), UserCommandsError> {
         if let Some(v) = &self.command {
             match v {
                 UserCommands::Show { email } => self.show_user(&email, user_storage)?,
+                UserCommands::List(list) => list.print(user_storage)?,
                 UserCommands::Edit {
                     email,
                     password,
                     is_admin,
                     sync15,
-                } => self.edit_user(email, password, is_admin, sync15, user_storage)?,
+                } => {
+                    self.edit_user(email, password, is_admin, sync15, user_storage)?;
+                    audit(audit_storage, AuditAction::UserEdited, email);
+                }
                 UserCommands::Add {
                     email,
                     password,
                     is_admin,
                     sync15,
-                } => self.create_user(&email, &password, is_admin, sync15, user_storage)?,
-                UserCommands::Delete { email } => self.delete_user(email, user_storage)?,
-                UserCommands::Generate { email } => self.generate_code(email, code_storage)?,
+                } => {
+                    self.create_user(&email, &password, is_admin, sync15, user_storage)?;
+                    audit(audit_storage, AuditAction::UserCreated, email);
+                }
+                UserCommands::Delete { email } => {
+                    self.delete_user(email, user_storage)?;
+                    audit(audit_storage, AuditAction::UserDeleted, email);
+                }
+                UserCommands::Generate { email } => {
+                    if self.generate_code(email, code_storage)? {
+                        audit(audit_storage, AuditAction::CodeCreated, email);
+                    }
+                }
                 UserCommands::Validate { email, code } => {
                     self.validate(email, code, code_storage)?
                 }
+                UserCommands::ResetSecondFactor { email } => {
+                    if self.reset_second_factor(email, user_storage)? {
+                        audit(audit_storage, AuditAction::SecondFactorReset, email);
+                    }
+                }
+                UserCommands::Devices { command } => match command {
+                    DeviceCommands::List { email } => self.list_devices(email, user_storage)?,
+                    DeviceCommands::Rename {
+                        email,
+                        id,
+                        description,
+                    } => {
+                        self.rename_device(email, id, description, user_storage)?;
+                        audit(
+                            audit_storage,
+                            AuditAction::DeviceRenamed,
+                            &format!("{}/{}", email, id),
+                        );
+                    }
+                    DeviceCommands::Revoke { email, id } => {
+                        self.revoke_device(email, id, user_storage)?;
+                        audit(
+                            audit_storage,
+                            AuditAction::DeviceRevoked,
+                            &format!("{}/{}", email, id),
+                        );
+                    }
+                },
             }
         };
 
         Ok(())
     }
 
-    fn show_user<U: UserStorage>(
+    /// The reload, which makes a running server see the changes of the command.
+    fn reload(&self) -> Option<Request> {
+        match self.command.as_ref()? {
+            UserCommands::Show { .. }
+            | UserCommands::List(_)
+            | UserCommands::Validate { .. }
+            | UserCommands::Devices {
+                command: DeviceCommands::List { .. },
+            } => None,
+            UserCommands::Generate { .. } => Some(Request::ReloadCodes),
+            _ => Some(Request::ReloadUsers),
+        }
+    }
+
+    fn show_user(
         &self,
         email: &str,
-        user_storage: &U,
+        user_storage: &dyn UserStorage,
     ) -> Result<(), UserCommandsError> {
         let user = user_storage.get_user(&EMail::create(email)?)?;
         println!("User: {:?}", user);
         Ok(())
     }
 
-    fn delete_user<U: UserStorage>(
+    fn delete_user(
         &self,
         email: &str,
-        user_storage: &U,
+        user_storage: &dyn UserStorage,
     ) -> Result<(), UserCommandsError> {
         user_storage.delete_user(&EMail::create(email)?)?;
         Ok(())
     }
 
-    fn create_user<U: UserStorage>(
+    fn create_user(
         &self,
         email: &str,
         password: &str,
         is_admin: &bool,
         sync15: &bool,
-        user_storage: &U,
+        user_storage: &dyn UserStorage,
     ) -> Result<(), UserCommandsError> {
         let _user = user_storage.create_user(&EMail::create(email)?, password, is_admin, sync15)?;
         Ok(())
     }
 
-    fn edit_user<U: UserStorage>(
+    fn edit_user(
         &self,
         email: &str,
         password: &str,
         is_admin: &bool,
         sync15: &bool,
-        user_storage: &U,
+        user_storage: &dyn UserStorage,
     ) -> Result<(), UserCommandsError> {
         let _user = user_storage.edit_user(&EMail::create(email)?, password, is_admin, sync15)?;
         Ok(())
     }
 
-    fn generate_code<C: CodeStorage>(
+    /// Returns false, if there was no second factor.
+    fn reset_second_factor(
+        &self,
+        email: &str,
+        user_storage: &dyn UserStorage,
+    ) -> Result<bool, UserCommandsError> {
+        let removed = user_storage.reset_second_factor(&EMail::create(email)?)?;
+        if removed {
+            println!(
+                "Second factor of {} removed, it has to be enrolled on the next login.",
+                email
+            );
+        } else {
+            println!("{} has no second factor.", email);
+        }
+        Ok(removed)
+    }
+
+    fn list_devices(
         &self,
         email: &str,
-        code_storage: &mut C,
+        user_storage: &dyn UserStorage,
     ) -> Result<(), UserCommandsError> {
-        let code = code_storage.create_code(&EMail::create(email)?)?;
-        println!("Code generated for id {}: {}", email, code);
+        let devices = user_storage.get_devices(&EMail::create(email)?)?;
+        if devices.is_empty() {
+            println!("No paired devices for {}.", email);
+        }
+        for device in devices {
+            println!(
+                "{}  {}  paired: {}  last seen: {}  ip: {}  firmware: {}",
+                device.id,
+                device.description,
+                device.paired_at,
+                device.last_seen,
+                device.ip.as_deref().unwrap_or("-"),
+                device.firmware_version.as_deref().unwrap_or("-"),
+            );
+        }
+        Ok(())
+    }
+
+    fn rename_device(
+        &self,
+        email: &str,
+        id: &str,
+        description: &str,
+        user_storage: &dyn UserStorage,
+    ) -> Result<(), UserCommandsError> {
+        user_storage.rename_device(&EMail::create(email)?, id, description)?;
+        println!("Device {} renamed to {}.", id, description);
+        Ok(())
+    }
+
+    fn revoke_device(
+        &self,
+        email: &str,
+        id: &str,
+        user_storage: &dyn UserStorage,
+    ) -> Result<(), UserCommandsError> {
+        user_storage.revoke_device(&EMail::create(email)?, id)?;
+        println!("Device {} revoked.", id);
         Ok(())
     }
 
-    fn validate<C: CodeStorage>(
+    /// Returns false, if the user has too many unused codes.
+    fn generate_code(
+        &self,
+        email: &str,
+        code_storage: &mut dyn CodeStorage,
+    ) -> Result<bool, UserCommandsError> {
+        let created = match code_storage.create_code(&EMail::create(email)?) {
+            Err(LocalStorageError::CodeLimitReached) => {
+                println!(
+                    "Too many unused codes for {}, use or let one expire first.",
+                    email
+                );
+                Ok(false)
+            }
+            Err(v) => Err(v),
+            Ok(code) => {
+                println!("Code generated for id {}: {}", email, code);
+                Ok(true)
+            }
+        }?;
+        Ok(created)
+    }
+
+    fn validate(
         &self,
         email: &str,
         code: &str,
-        code_storage: &mut C,
+        code_storage: &mut dyn CodeStorage,
     ) -> Result<(), UserCommandsError> {
         match code_storage.validate_code(&EMail::create(email)?, code) {
             Err(LocalStorageError::CodeExpired) => {
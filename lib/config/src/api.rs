@@ -1,15 +1,11 @@
-use crate::TomlError;
-use std::env;
-use thiserror::Error;
-use toml::Value;
+use lettre::message::Mailbox;
+use serde::Deserialize;
+use std::{fs::metadata, path::Path};
 
-#[derive(Error, Debug)]
-pub enum ApiError {
-    #[error("error in api yaml config")]
-    YamlError(#[from] TomlError),
-    #[error("API.URL must not contain protocol like http")]
-    UrlContainsProtocol,
-}
+use crate::report::{join, Checked, Report, Unknown};
+
+/// The SECRET_KEY of the config.example.toml, which everybody knows.
+const EXAMPLE_SECRET_KEY: &str = "SOME_KEY";
 
 /// Represents the config for API which communicates with the remarkable tablets
 #[derive(Debug)]
@@ -17,146 +13,407 @@ pub struct Api {
     pub url: String,
     pub secret_key: String,
     pub data_dir: String,
-    pub hwr: Option<HWR>,
-    pub smtp: Option<SMTP>,
+    pub hwr: Option<Hwr>,
+    pub smtp: Option<Smtp>,
+    pub webhooks: Vec<Webhook>,
+    pub codes: CodePolicy,
 }
 
 /// Represents all config for HWR functionalities
 #[derive(Debug)]
-pub struct HWR {
+pub struct Hwr {
     pub app_key: String,
     pub hmac: String,
 }
 
 /// Represents all config for SMTP functionalities
 #[derive(Debug)]
-pub struct SMTP {
+pub struct Smtp {
     pub server: String,
     pub username: String,
     pub password: String,
+    /// sender address of all mails, the username is used without it
+    pub from: String,
+    pub tls: SmtpTls,
+}
+
+/// How the connection to the SMTP server is secured
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// TLS from the first byte, usually on port 465
+    Implicit,
+    /// upgrade with STARTTLS, usually on port 587
+    StartTls,
+    /// plaintext, only for local relays
+    None,
+}
+
+impl SmtpTls {
+    /// the value of API.SMTP.TLS
+    pub fn name(&self) -> &'static str {
+        match self {
+            SmtpTls::Implicit => "implicit",
+            SmtpTls::StartTls => "starttls",
+            SmtpTls::None => "none",
+        }
+    }
+}
+
+/// Represents the policy for the one-time codes, which pair devices with an account
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodePolicy {
+    pub length: usize,
+    /// every character is drawn with the same probability
+    pub alphabet: Vec<char>,
+    pub ttl_seconds: u64,
+    /// codes per user, which are not used and not expired yet
+    pub max_per_user: usize,
+}
+
+impl Default for CodePolicy {
+    fn default() -> Self {
+        Self {
+            length: 8,
+            alphabet: ('A'..='Z').collect(),
+            ttl_seconds: 3 * 60 * 60,
+            max_per_user: 5,
+        }
+    }
+}
+
+/// The `[API]` section as it is written in the file
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub(crate) struct ApiFile {
+    url: Option<Checked<String>>,
+    secret_key: Option<Checked<String>>,
+    datadir: Option<Checked<String>>,
+    hwr: Option<Checked<HwrFile>>,
+    smtp: Option<Checked<SmtpFile>>,
+    webhooks: Option<Checked<Vec<Checked<WebhookFile>>>>,
+    codes: Option<Checked<CodesFile>>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+struct HwrFile {
+    #[serde(rename = "APPLICATIONKEY")]
+    app_key: Option<Checked<String>>,
+    hmac: Option<Checked<String>>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+struct SmtpFile {
+    server: Option<Checked<String>>,
+    username: Option<Checked<String>>,
+    password: Option<Checked<String>>,
+    from: Option<Checked<String>>,
+    tls: Option<Checked<SmtpTls>>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+struct CodesFile {
+    length: Option<Checked<usize>>,
+    alphabet: Option<Checked<String>>,
+    ttl: Option<Checked<u64>>,
+    max_per_user: Option<Checked<usize>>,
+    #[serde(flatten)]
+    unknown: Unknown,
 }
 
-impl HWR {
-    fn create(yaml: &Value) -> Result<Self, ApiError> {
-        let hwr = yaml
-            .get("HWR")
-            .ok_or_else(|| TomlError::KeyNotFound("API.HWR"))?;
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+struct WebhookFile {
+    url: Option<Checked<String>>,
+    secret: Option<Checked<String>>,
+    events: Option<Checked<Vec<String>>>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
 
-        let app_key = hwr
-            .get("APPLICATIONKEY")
-            .ok_or_else(|| TomlError::KeyNotFound("API.HWR.APPLICATIONKEY"))?
-            .as_str()
-            .ok_or_else(|| TomlError::WrongType("API.HWR.APPLICATIONKEY", "String"))?
-            .to_string();
+impl CodePolicy {
+    const MIN_LENGTH: usize = 6;
+    const MAX_LENGTH: usize = 64;
+    /// Longer lived codes are rather a password, so pairing needs a fresh one.
+    const MAX_TTL_SECONDS: u64 = 30 * 24 * 60 * 60;
 
-        let hmac = hwr
-            .get("HMAC")
-            .ok_or_else(|| TomlError::KeyNotFound("API.HWR.HMAC"))?
-            .as_str()
-            .ok_or_else(|| TomlError::WrongType("API.HWR.HMAC", "String"))?
-            .to_string();
+    fn from_file(file: Option<Checked<CodesFile>>, report: &mut Report) -> Self {
+        let default = Self::default();
+        let file = match report.optional("API.CODES", file) {
+            Some(v) => v,
+            None => return default,
+        };
+        report.unknown("API.CODES", &file.unknown);
 
-        Ok(Self { app_key, hmac })
+        let length = report
+            .optional("API.CODES.LENGTH", file.length)
+            .unwrap_or(default.length);
+        if !(Self::MIN_LENGTH..=Self::MAX_LENGTH).contains(&length) {
+            report.error(
+                "API.CODES.LENGTH",
+                format!(
+                    "must be between {} and {}",
+                    Self::MIN_LENGTH,
+                    Self::MAX_LENGTH
+                ),
+            );
+        }
+
+        let alphabet: Vec<char> = match report.optional("API.CODES.ALPHABET", file.alphabet) {
+            Some(v) => v.chars().collect(),
+            None => default.alphabet,
+        };
+        let mut distinct = alphabet.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        if distinct.len() < 2 || distinct.len() != alphabet.len() {
+            report.error(
+                "API.CODES.ALPHABET",
+                "needs at least two distinct characters and no duplicates",
+            );
+        }
+
+        let ttl_seconds = report
+            .optional("API.CODES.TTL", file.ttl)
+            .unwrap_or(default.ttl_seconds);
+        if !(1..=Self::MAX_TTL_SECONDS).contains(&ttl_seconds) {
+            report.error(
+                "API.CODES.TTL",
+                format!("must be between 1 and {}", Self::MAX_TTL_SECONDS),
+            );
+        }
+
+        let max_per_user = report
+            .optional("API.CODES.MAX_PER_USER", file.max_per_user)
+            .unwrap_or(default.max_per_user);
+        if max_per_user == 0 {
+            report.error("API.CODES.MAX_PER_USER", "must be greater than zero");
+        }
+
+        Self {
+            length,
+            alphabet,
+            ttl_seconds,
+            max_per_user,
+        }
     }
 }
 
-impl SMTP {
-    fn create(yaml: &Value) -> Result<Self, ApiError> {
-        let smtp = yaml
-            .get("SMTP")
-            .ok_or_else(|| TomlError::KeyNotFound("API.SMTP"))?;
-
-        let server = smtp
-            .get("SERVER")
-            .ok_or_else(|| TomlError::KeyNotFound("API.SMTP.SERVER"))?
-            .as_str()
-            .ok_or_else(|| TomlError::WrongType("API.SMTP.SERVER", "String"))?
-            .to_string();
-
-        let username = smtp
-            .get("USERNAME")
-            .ok_or_else(|| TomlError::KeyNotFound("API.SMTP.USERNAME"))?
-            .as_str()
-            .ok_or_else(|| TomlError::WrongType("API.SMTP.USERNAME", "String"))?
-            .to_string();
-
-        let password = smtp
-            .get("PASSWORD")
-            .ok_or_else(|| TomlError::KeyNotFound("API.SMTP.PASSWORD"))?
-            .as_str()
-            .ok_or_else(|| TomlError::WrongType("API.SMTP.PASSWORD", "String"))?
-            .to_string();
-
-        Ok(Self {
+/// Represents a global webhook, which gets notified about the events of all users
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub url: String,
+    pub secret: String,
+    /// names of the events, which should be sent. Empty means all events.
+    pub events: Vec<String>,
+}
+
+impl Webhook {
+    fn from_files(
+        files: Option<Checked<Vec<Checked<WebhookFile>>>>,
+        report: &mut Report,
+    ) -> Vec<Self> {
+        let files = report.optional("API.WEBHOOKS", files).unwrap_or_default();
+        files
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, file)| {
+                let prefix = format!("API.WEBHOOKS.{}", index);
+                let file = report.optional(&prefix, Some(file))?;
+                report.unknown(&prefix, &file.unknown);
+
+                let url = report.required(&join(&prefix, "URL"), file.url);
+                let secret = report.required(&join(&prefix, "SECRET"), file.secret);
+                let events = report
+                    .optional(&join(&prefix, "EVENTS"), file.events)
+                    .unwrap_or_default();
+                Some(Self {
+                    url: url?,
+                    secret: secret?,
+                    events,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Hwr {
+    fn from_file(file: Option<Checked<HwrFile>>, report: &mut Report) -> Option<Self> {
+        let file = report.optional("API.HWR", file)?;
+        report.unknown("API.HWR", &file.unknown);
+
+        let app_key = report.required("API.HWR.APPLICATIONKEY", file.app_key);
+        let hmac = report.required("API.HWR.HMAC", file.hmac);
+        Some(Self {
+            app_key: app_key?,
+            hmac: hmac?,
+        })
+    }
+}
+
+impl Smtp {
+    fn from_file(file: Option<Checked<SmtpFile>>, report: &mut Report) -> Option<Self> {
+        let file = report.optional("API.SMTP", file)?;
+        report.unknown("API.SMTP", &file.unknown);
+
+        let server = report.required("API.SMTP.SERVER", file.server);
+        let username = report.required("API.SMTP.USERNAME", file.username);
+        let password = report.required("API.SMTP.PASSWORD", file.password);
+        let from = report.optional("API.SMTP.FROM", file.from);
+        let tls = report.optional("API.SMTP.TLS", file.tls);
+
+        let server = server?;
+        let username = username?;
+        let (from_key, from) = match from {
+            Some(v) => ("API.SMTP.FROM", v),
+            None => ("API.SMTP.USERNAME", username.clone()),
+        };
+        if let Err(v) = from.parse::<Mailbox>() {
+            report.error(
+                from_key,
+                format!(
+                    "is not a sender address like rmcloud <noreply@example.com>: {}",
+                    v
+                ),
+            );
+        }
+        Some(Self {
+            tls: tls.unwrap_or(match server.ends_with(":465") {
+                true => SmtpTls::Implicit,
+                false => SmtpTls::StartTls,
+            }),
             server,
+            from,
             username,
-            password,
+            password: password?,
         })
     }
 }
 
-impl Default for Api {
-    fn default() -> Self {
-        Self {
-            url: env::var("API_URL").expect("API_URL not found in environment variables."),
-            secret_key: env::var("API_SECRET_KEY")
-                .expect("API_SECRET_KEY not found in environment variables."),
-            data_dir: env::var("API_DATA_DIR")
-                .expect("API_DATA_DIR not found in environment variables."),
-            hwr: None,
-            smtp: None,
-        }
+/// Checks DATADIR, or the directory it will be created in, without writing to it.
+fn check_writable(data_dir: &Path) -> Result<(), String> {
+    let dir = data_dir
+        .ancestors()
+        .map(|v| match v.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => v,
+        })
+        .find(|v| v.exists())
+        .ok_or("does not exist")?;
+    let metadata = metadata(dir).map_err(|v| format!("{} cannot be read: {}", dir.display(), v))?;
+    if !metadata.is_dir() {
+        return Err(format!("{} is not a directory", dir.display()));
     }
+    if metadata.permissions().readonly() {
+        return Err(format!("{} is not writable", dir.display()));
+    }
+    Ok(())
 }
 
 impl Api {
     /// Creates the Api config struct and checks for required and optional fields
-    pub fn create(yaml: &Value) -> Result<Self, ApiError> {
-        let api = yaml
-            .get("API")
-            .ok_or_else(|| TomlError::KeyNotFound("API.API"))?;
-
-        let url = api
-            .get("URL")
-            .ok_or_else(|| TomlError::KeyNotFound("API.URL"))?
-            .as_str()
-            .ok_or_else(|| TomlError::WrongType("API.URL", "String"))?
-            .to_string();
-
-        if url.contains("://") {
-            return Err(ApiError::UrlContainsProtocol);
+    pub(crate) fn from_file(file: Option<Checked<ApiFile>>, report: &mut Report) -> Option<Self> {
+        let file = report.required("API", file)?;
+        report.unknown("API", &file.unknown);
+
+        let url = report.required("API.URL", file.url);
+        if url.as_ref().filter(|v| v.contains("://")).is_some() {
+            report.error("API.URL", "must not contain a protocol like http");
         }
 
-        let secret_key = api
-            .get("SECRET_KEY")
-            .ok_or_else(|| TomlError::KeyNotFound("API.SECRET_KEY"))?
-            .as_str()
-            .ok_or_else(|| TomlError::WrongType("API.SECRET_KEY", "String"))?
-            .to_string();
-
-        let data_dir = api
-            .get("DATADIR")
-            .ok_or_else(|| TomlError::KeyNotFound("API.DATADIR"))?
-            .as_str()
-            .ok_or_else(|| TomlError::WrongType("API.DATADIR", "String"))?
-            .to_string();
-
-        let smtp = match SMTP::create(api) {
-            Err(ApiError::YamlError(TomlError::KeyNotFound("API.SMTP"))) => None,
-            v => Some(v?),
-        };
+        let secret_key = report.required("API.SECRET_KEY", file.secret_key);
+        match secret_key.as_deref() {
+            Some("") => report.error("API.SECRET_KEY", "must not be empty"),
+            Some(EXAMPLE_SECRET_KEY) => report.error(
+                "API.SECRET_KEY",
+                "is still the example value, set a long random key",
+            ),
+            _ => {}
+        }
 
-        let hwr = match HWR::create(api) {
-            Err(ApiError::YamlError(TomlError::KeyNotFound("API.HWR"))) => None,
-            v => Some(v?),
-        };
+        let data_dir = report.required("API.DATADIR", file.datadir);
+        if let Some(Err(v)) = data_dir.as_ref().map(|v| check_writable(Path::new(v))) {
+            report.error("API.DATADIR", v);
+        }
+
+        let smtp = Smtp::from_file(file.smtp, report);
+        let hwr = Hwr::from_file(file.hwr, report);
+        let webhooks = Webhook::from_files(file.webhooks, report);
+        let codes = CodePolicy::from_file(file.codes, report);
 
-        Ok(Self {
-            url,
-            secret_key,
-            data_dir,
+        Some(Self {
+            url: url?,
+            secret_key: secret_key?,
+            data_dir: data_dir?,
             smtp,
             hwr,
+            webhooks,
+            codes,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_smtp(text: &str) -> (Option<Smtp>, Report) {
+        let mut report = Report::default();
+        let file = toml::from_str(text).unwrap();
+        (Smtp::from_file(Some(file), &mut report), report)
+    }
+
+    #[test]
+    fn sender_is_a_mailbox() {
+        let (smtp, report) = read_smtp(
+            r#"
+SERVER = "mail:465"
+USERNAME = "me@example.com"
+PASSWORD = "secret"
+"#,
+        );
+        assert!(report.errors.is_empty());
+        assert_eq!(smtp.unwrap().from, "me@example.com");
+
+        let (_, report) = read_smtp(
+            r#"
+SERVER = "mail:465"
+USERNAME = "MY_EMAIL_ADDRESS"
+PASSWORD = "secret"
+"#,
+        );
+        let keys: Vec<_> = report.errors.iter().map(|v| v.key.as_str()).collect();
+        assert_eq!(keys, vec!["API.SMTP.USERNAME"]);
+
+        let (_, report) = read_smtp(
+            r#"
+SERVER = "mail:465"
+USERNAME = "MY_EMAIL_ADDRESS"
+PASSWORD = "secret"
+FROM = "rmcloud <noreply>"
+"#,
+        );
+        let keys: Vec<_> = report.errors.iter().map(|v| v.key.as_str()).collect();
+        assert_eq!(keys, vec!["API.SMTP.FROM"]);
+    }
+
+    #[test]
+    fn data_dir_is_not_written() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_writable(&dir.path().join("new/data")).is_ok());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(check_writable(&file).is_err());
+    }
+}
@@ -1,78 +1,163 @@
-use std::env;
-use thiserror::Error;
-use toml::Value;
+use serde::Deserialize;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+};
 
-use crate::TomlError;
-
-#[derive(Error, Debug)]
-pub enum CommonError {
-    #[error("error in common toml config")]
-    TomlError(#[from] TomlError),
-}
+use crate::report::{Checked, Report, Unknown};
 
 /// Represents all configs for admin UI
 #[derive(Debug)]
 pub struct Common {
     pub port: u16,
     pub loglevel: String,
-    pub socket: u16,
+    /// the unix socket of the cli, only the user running the server can connect
+    pub socket: PathBuf,
+    /// the addresses to listen on with PORT, API_PORT and UI_PORT.
+    /// An ipv6 address does not accept ipv4 connections, list both for dual stack.
+    pub bind: Vec<IpAddr>,
+    /// serves only the api on this port, without checking the host
+    pub api_port: Option<u16>,
+    /// serves only the admin UI on this port, without checking the host
+    pub ui_port: Option<u16>,
+    /// serves the prometheus metrics on this port, they are not reachable elsewhere
+    pub admin_port: Option<u16>,
+    /// the address of the admin listener
+    pub admin_bind: IpAddr,
+    /// serves https instead of http, if given
+    pub tls: Option<Tls>,
+    /// seconds, which running requests get to finish on shutdown
+    pub shutdown_timeout_seconds: u64,
 }
 
-impl Common {
-    /// Creates the UI config struct and checks for required and optional fields
-    pub fn create(yaml: &Value) -> Result<Self, CommonError> {
-        let common_config = yaml.get("COMMON").ok_or(TomlError::KeyNotFound("COMMON"))?;
+/// Used without COMMON.SHUTDOWN_TIMEOUT, docker waits 10 seconds before it kills.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 8;
+
+/// The `[COMMON]` section as it is written in the file
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub(crate) struct CommonFile {
+    port: Option<Checked<u16>>,
+    loglevel: Option<Checked<String>>,
+    socket: Option<Checked<PathBuf>>,
+    bind: Option<Checked<Bind>>,
+    api_port: Option<Checked<u16>>,
+    ui_port: Option<Checked<u16>>,
+    admin_port: Option<Checked<u16>>,
+    admin_bind: Option<Checked<IpAddr>>,
+    tls_cert: Option<Checked<PathBuf>>,
+    tls_key: Option<Checked<PathBuf>>,
+    tls_generate: Option<Checked<bool>>,
+    shutdown_timeout: Option<Checked<u64>>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
 
-        let port = common_config
-            .get("PORT")
-            .ok_or(TomlError::KeyNotFound("COMMON.PORT"))?
-            .as_integer()
-            .ok_or(TomlError::WrongType("COMMON.PORT", "Integer"))?
-            .try_into()
-            .map_err(|e| {
-                println!("error in config while trying to read port: {:?}", e);
-                TomlError::WrongType("COMMON.PORT", "Integer")
-            })?;
+/// BIND takes a single address, too
+#[derive(Deserialize)]
+#[serde(
+    untagged,
+    expecting = "must be an ip address or a list of ip addresses"
+)]
+enum Bind {
+    One(IpAddr),
+    Many(Vec<IpAddr>),
+}
 
-        let loglevel = common_config
-            .get("LOGLEVEL")
-            .ok_or(TomlError::KeyNotFound("COMMON.LOGLEVEL"))?
-            .as_str()
-            .ok_or(TomlError::WrongType("COMMON.LOGLEVEL", "String"))?
-            .to_string();
+/// Represents the certificate and key for https
+#[derive(Debug, Clone)]
+pub struct Tls {
+    /// pem file with the certificate chain
+    pub cert: PathBuf,
+    /// pem file with the private key
+    pub key: PathBuf,
+    /// creates a local CA and a host certificate for API.URL, if the files do not exist,
+    /// and renews the certificate, when it expires or the urls change
+    pub generate: bool,
+}
 
-        let socket = common_config
-            .get("SOCKET")
-            .ok_or(TomlError::KeyNotFound("COMMON.SOCKET"))?
-            .as_integer()
-            .ok_or(TomlError::WrongType("COMMON.SOCKET", "Integer"))?
-            .try_into()
-            .map_err(|e| {
-                println!("error in config while trying to read socketport: {:?}", e);
-                TomlError::WrongType("COMMON.SOCKET", "Integer")
-            })?;
+impl Tls {
+    fn from_file(file: &mut CommonFile, report: &mut Report) -> Option<Self> {
+        let cert = report.optional("COMMON.TLS_CERT", file.tls_cert.take());
+        let key = report.optional("COMMON.TLS_KEY", file.tls_key.take());
+        let generate = report
+            .optional("COMMON.TLS_GENERATE", file.tls_generate.take())
+            .unwrap_or(false);
 
-        Ok(Self {
-            port,
-            loglevel,
-            socket,
-        })
+        match (cert, key) {
+            (Some(cert), Some(key)) => Some(Self {
+                cert,
+                key,
+                generate,
+            }),
+            (Some(_), None) => {
+                report.error("COMMON.TLS_KEY", "is required with COMMON.TLS_CERT");
+                None
+            }
+            (None, Some(_)) => {
+                report.error("COMMON.TLS_CERT", "is required with COMMON.TLS_KEY");
+                None
+            }
+            (None, None) => None,
+        }
     }
 }
 
-impl Default for Common {
-    fn default() -> Self {
-        Self {
-            // this will be used, when no config file should be used.
-            port: env::var("PORT")
-                .expect("PORT not found in environment variables.")
-                .parse::<u16>()
-                .expect("PORT not valid number."),
-            loglevel: env::var("LOGLEVEL").expect("LOGLEVEL not found in environment variables."),
-            socket: env::var("SOCKET_PORT")
-                .expect("SOCKET_PORT not found in environment variables.")
-                .parse::<u16>()
-                .expect("SOCKET_PORT not valid number."),
+impl Common {
+    /// Creates the common config struct and checks for required and optional fields
+    pub(crate) fn from_file(
+        file: Option<Checked<CommonFile>>,
+        report: &mut Report,
+    ) -> Option<Self> {
+        let mut file = report.required("COMMON", file)?;
+        report.unknown("COMMON", &file.unknown);
+
+        let port = report.required("COMMON.PORT", file.port.take());
+        let loglevel = report.required("COMMON.LOGLEVEL", file.loglevel.take());
+        let socket = report.required("COMMON.SOCKET", file.socket.take());
+        let bind = match report.optional("COMMON.BIND", file.bind.take()) {
+            Some(Bind::One(v)) => vec![v],
+            Some(Bind::Many(v)) => v,
+            None => vec![IpAddr::V4(Ipv4Addr::LOCALHOST)],
+        };
+        let admin_bind = report
+            .optional("COMMON.ADMIN_BIND", file.admin_bind.take())
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        let api_port = report.optional("COMMON.API_PORT", file.api_port.take());
+        let ui_port = report.optional("COMMON.UI_PORT", file.ui_port.take());
+        let admin_port = report.optional("COMMON.ADMIN_PORT", file.admin_port.take());
+        let ports = [
+            ("COMMON.PORT", port),
+            ("COMMON.API_PORT", api_port),
+            ("COMMON.UI_PORT", ui_port),
+            ("COMMON.ADMIN_PORT", admin_port),
+        ];
+        for (index, (key, port)) in ports.iter().enumerate() {
+            let used = ports[..index]
+                .iter()
+                .find(|(_, other)| port.is_some() && other == port);
+            if let (Some(port), Some((other, _))) = (port, used) {
+                report.error(key, format!("{} is already the port of {}", port, other));
+            }
         }
+
+        let tls = Tls::from_file(&mut file, report);
+        let shutdown_timeout_seconds = report
+            .optional("COMMON.SHUTDOWN_TIMEOUT", file.shutdown_timeout.take())
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+
+        Some(Self {
+            port: port?,
+            loglevel: loglevel?,
+            socket: socket?,
+            bind,
+            api_port,
+            ui_port,
+            admin_port,
+            admin_bind,
+            tls,
+            shutdown_timeout_seconds,
+        })
     }
 }
@@ -1,39 +1,27 @@
-use crate::{Api, Common, Ui};
-use std::path::PathBuf;
+use crate::{
+    api::ApiFile,
+    common::CommonFile,
+    report::{Checked, Problems, Report, Unknown},
+    storage::StorageFile,
+    ui::UiFile,
+    Api, Common, StorageConfig, Ui,
+};
+use serde::Deserialize;
 use thiserror::Error;
 use toml::Value;
 
-#[derive(Error, Debug)]
-
-pub enum TomlError {
-    #[error("Required `{0}` field in toml file is missing")]
-    KeyNotFound(&'static str),
-    #[error("Required `{0}` field in toml file is not the correct type: {1}")]
-    WrongType(&'static str, &'static str),
-}
-
 #[derive(Error, Debug)]
 pub enum ConfigError {
-    #[error("Required PORT field cannot be found in toml file")]
-    PortKeyNotFound,
-    #[error("Required PORT field in toml file is not a number")]
-    PortKeyNotNumber,
-    #[error("Required PORT field in toml file is not a valid port: Between 1-65535")]
-    PortKeyExceedsPortRange,
-    #[error("Required LOGLEVEL field cannot be found in toml file")]
-    LogLevelKeyNotFound,
-    #[error("Required LOGLEVEL field in toml file is not a string")]
-    LogLevelKeyNotString,
-    #[error("UI config is not valid")]
-    UiError(#[from] crate::ui::UiError),
-    #[error("API config is not valid")]
-    ApiError(#[from] crate::api::ApiError),
-    #[error("Common config is not valid")]
-    CommonError(#[from] crate::common::CommonError),
+    #[error("config is not valid:{0}")]
+    NotValid(Problems),
     #[error("Given toml string was not valid")]
     NotValidToml(#[from] toml::de::Error),
     #[error("There was an io error")]
     IoError(#[from] std::io::Error),
+    #[error("{0} is no known config key")]
+    UnknownKey(String),
+    #[error("{0} is not a valid {1}")]
+    OverrideNotValid(String, &'static str),
 }
 
 /// Represents the global config struct, which holds all configuration
@@ -42,31 +30,161 @@ pub struct Config {
     pub api: Api,
     pub ui: Ui,
     pub common: Common,
+    pub storage: StorageConfig,
+}
+
+/// The config file as it is written, every section and key may be missing or invalid
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+struct ConfigFile {
+    common: Option<Checked<CommonFile>>,
+    ui: Option<Checked<UiFile>>,
+    api: Option<Checked<ApiFile>>,
+    storage: Option<Checked<StorageFile>>,
+    #[serde(flatten)]
+    unknown: Unknown,
 }
 
 impl Config {
     /// Creates the config object from the given toml object.
     pub fn create(toml_str: &str) -> Result<Self, ConfigError> {
-        let toml: Value = toml::from_str(toml_str).map_err(|v| ConfigError::NotValidToml(v))?;
+        let toml: Value = toml::from_str(toml_str).map_err(ConfigError::NotValidToml)?;
+        Self::from_toml(&toml)
+    }
 
-        Ok(Self {
-            ui: Ui::create(&toml)?,
-            api: Api::create(&toml)?,
-            common: Common::create(&toml)?,
-        })
+    pub fn from_toml(toml: &Value) -> Result<Self, ConfigError> {
+        let (config, report) = Self::check(toml);
+        config.ok_or(ConfigError::NotValid(Problems(report.errors)))
     }
-}
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            api: Default::default(),
-            ui: Default::default(),
-            common: Default::default(),
-        }
+    /// Checks every key, the config is only created without errors.
+    pub(crate) fn check(toml: &Value) -> (Option<Self>, Report) {
+        let mut report = Report::default();
+        let file: ConfigFile = match toml.clone().try_into() {
+            Ok(v) => v,
+            Err(v) => {
+                report.error("", v.to_string());
+                return (None, report);
+            }
+        };
+        report.unknown("", &file.unknown);
+
+        let common = Common::from_file(file.common, &mut report);
+        let ui = Ui::from_file(file.ui, &mut report);
+        let api = Api::from_file(file.api, &mut report);
+        let storage = StorageConfig::from_file(file.storage, &mut report);
+
+        let config = match (common, ui, api) {
+            (Some(common), Some(ui), Some(api)) if report.errors.is_empty() => Some(Self {
+                api,
+                ui,
+                common,
+                storage,
+            }),
+            _ => None,
+        };
+        (config, report)
     }
-}
 
-pub fn read_config(path: &PathBuf) -> Result<Config, ConfigError> {
-    Config::create(&std::fs::read_to_string(path)?)
+    /// All effective values by their dotted key, including the defaults.
+    pub fn values(&self) -> Vec<(String, Value)> {
+        let mut values = vec![];
+        let mut add = |key: &str, value: Value| values.push((key.to_string(), value));
+        let string = |v: &str| Value::String(v.to_string());
+        let strings = |v: &[String]| Value::Array(v.iter().map(|v| string(v)).collect());
+        let integer = |v: u64| Value::Integer(v as i64);
+
+        let common = &self.common;
+        add("COMMON.LOGLEVEL", string(&common.loglevel));
+        add("COMMON.PORT", integer(common.port.into()));
+        add("COMMON.SOCKET", string(&common.socket.to_string_lossy()));
+        let bind: Vec<String> = common.bind.iter().map(|v| v.to_string()).collect();
+        add("COMMON.BIND", strings(&bind));
+        for (key, port) in [
+            ("COMMON.API_PORT", common.api_port),
+            ("COMMON.UI_PORT", common.ui_port),
+            ("COMMON.ADMIN_PORT", common.admin_port),
+        ] {
+            if let Some(port) = port {
+                add(key, integer(port.into()));
+            }
+        }
+        add("COMMON.ADMIN_BIND", string(&common.admin_bind.to_string()));
+        if let Some(tls) = &common.tls {
+            add("COMMON.TLS_CERT", string(&tls.cert.to_string_lossy()));
+            add("COMMON.TLS_KEY", string(&tls.key.to_string_lossy()));
+            add("COMMON.TLS_GENERATE", Value::Boolean(tls.generate));
+        }
+        add(
+            "COMMON.SHUTDOWN_TIMEOUT",
+            integer(common.shutdown_timeout_seconds),
+        );
+
+        add("UI.URL", string(&self.ui.url));
+        if let Some(oidc) = &self.ui.oidc {
+            add("UI.OIDC.ISSUER", string(&oidc.issuer));
+            add("UI.OIDC.CLIENT_ID", string(&oidc.client_id));
+            if let Some(v) = &oidc.client_secret {
+                add("UI.OIDC.CLIENT_SECRET", string(v));
+            }
+            add("UI.OIDC.REDIRECT_URL", string(&oidc.redirect_url));
+            add("UI.OIDC.SCOPES", strings(&oidc.scopes));
+            add("UI.OIDC.PROVISION", Value::Boolean(oidc.provision));
+            add(
+                "UI.OIDC.TRUST_UNVERIFIED_EMAIL",
+                Value::Boolean(oidc.trust_unverified_email),
+            );
+            add("UI.OIDC.GROUPS_CLAIM", string(&oidc.groups_claim));
+            if let Some(v) = &oidc.admin_group {
+                add("UI.OIDC.ADMIN_GROUP", string(v));
+            }
+        }
+
+        let api = &self.api;
+        add("API.URL", string(&api.url));
+        add("API.SECRET_KEY", string(&api.secret_key));
+        add("API.DATADIR", string(&api.data_dir));
+        if let Some(smtp) = &api.smtp {
+            add("API.SMTP.SERVER", string(&smtp.server));
+            add("API.SMTP.USERNAME", string(&smtp.username));
+            add("API.SMTP.PASSWORD", string(&smtp.password));
+            add("API.SMTP.FROM", string(&smtp.from));
+            add("API.SMTP.TLS", string(smtp.tls.name()));
+        }
+        if let Some(hwr) = &api.hwr {
+            add("API.HWR.APPLICATIONKEY", string(&hwr.app_key));
+            add("API.HWR.HMAC", string(&hwr.hmac));
+        }
+        add("API.CODES.LENGTH", integer(api.codes.length as u64));
+        add(
+            "API.CODES.ALPHABET",
+            string(&api.codes.alphabet.iter().collect::<String>()),
+        );
+        add("API.CODES.TTL", integer(api.codes.ttl_seconds));
+        add("API.CODES.MAX_PER_USER", integer(api.codes.max_per_user as u64));
+        for (index, webhook) in api.webhooks.iter().enumerate() {
+            add(&format!("API.WEBHOOKS.{}.URL", index), string(&webhook.url));
+            add(&format!("API.WEBHOOKS.{}.SECRET", index), string(&webhook.secret));
+            if !webhook.events.is_empty() {
+                add(
+                    &format!("API.WEBHOOKS.{}.EVENTS", index),
+                    strings(&webhook.events),
+                );
+            }
+        }
+
+        let storage = &self.storage;
+        add("STORAGE.USERS", string(&storage.users));
+        add("STORAGE.CODES", string(&storage.codes));
+        add("STORAGE.ATTEMPTS", string(&storage.attempts));
+        add("STORAGE.AUDIT", string(&storage.audit));
+        for (backend, options) in &storage.options {
+            for (key, value) in options.as_table().into_iter().flatten() {
+                let key = format!("STORAGE.{}.{}", backend.to_uppercase(), key);
+                add(&key, value.clone());
+            }
+        }
+
+        values
+    }
 }
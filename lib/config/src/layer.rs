@@ -0,0 +1,315 @@
+use std::{collections::BTreeMap, env, fmt, io, path::Path, sync::OnceLock};
+use toml::{value::Table, Value};
+
+use crate::{
+    report::{key_lines, Problems},
+    Config, ConfigError, Problem,
+};
+
+/// Env vars override the file, e.g. `RMCLOUD_API_SMTP_PASSWORD` for `API.SMTP.PASSWORD`.
+pub const ENV_PREFIX: &str = "RMCLOUD_";
+
+/// Shown instead of the value of a secret.
+pub const REDACTED: &str = "<redacted>";
+
+/// Where the effective value of a key comes from, later ones win.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    File,
+    /// the name of the env var
+    Env(String),
+    Flag,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File => write!(f, "file"),
+            Source::Env(name) => write!(f, "env {}", name),
+            Source::Flag => write!(f, "flag"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    String,
+    Integer,
+    Boolean,
+    /// comma separated in env vars and flags
+    Array,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::String => "String",
+            Kind::Integer => "Integer",
+            Kind::Boolean => "Boolean",
+            Kind::Array => "Array",
+        }
+    }
+
+    fn parse(&self, value: &str) -> Option<Value> {
+        match self {
+            Kind::String => Some(Value::String(value.to_string())),
+            Kind::Integer => value.trim().parse().ok().map(Value::Integer),
+            Kind::Boolean => value.trim().parse().ok().map(Value::Boolean),
+            Kind::Array => Some(Value::Array(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .map(|v| Value::String(v.to_string()))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+/// All keys, which env vars and flags can set, and whether they are secret.
+/// `[[API.WEBHOOKS]]` is a list of tables and can only be given in the file.
+const KEYS: &[(&str, Kind, bool)] = &[
+    ("COMMON.LOGLEVEL", Kind::String, false),
+    ("COMMON.PORT", Kind::Integer, false),
+    ("COMMON.SOCKET", Kind::String, false),
+    ("COMMON.BIND", Kind::Array, false),
+    ("COMMON.API_PORT", Kind::Integer, false),
+    ("COMMON.UI_PORT", Kind::Integer, false),
+    ("COMMON.ADMIN_PORT", Kind::Integer, false),
+    ("COMMON.ADMIN_BIND", Kind::String, false),
+    ("COMMON.TLS_CERT", Kind::String, false),
+    ("COMMON.TLS_KEY", Kind::String, false),
+    ("COMMON.TLS_GENERATE", Kind::Boolean, false),
+    ("COMMON.SHUTDOWN_TIMEOUT", Kind::Integer, false),
+    ("UI.URL", Kind::String, false),
+    ("UI.OIDC.ISSUER", Kind::String, false),
+    ("UI.OIDC.CLIENT_ID", Kind::String, false),
+    ("UI.OIDC.CLIENT_SECRET", Kind::String, true),
+    ("UI.OIDC.REDIRECT_URL", Kind::String, false),
+    ("UI.OIDC.SCOPES", Kind::Array, false),
+    ("UI.OIDC.PROVISION", Kind::Boolean, false),
+    ("UI.OIDC.TRUST_UNVERIFIED_EMAIL", Kind::Boolean, false),
+    ("UI.OIDC.GROUPS_CLAIM", Kind::String, false),
+    ("UI.OIDC.ADMIN_GROUP", Kind::String, false),
+    ("API.URL", Kind::String, false),
+    ("API.SECRET_KEY", Kind::String, true),
+    ("API.DATADIR", Kind::String, false),
+    ("API.SMTP.SERVER", Kind::String, false),
+    ("API.SMTP.USERNAME", Kind::String, false),
+    ("API.SMTP.PASSWORD", Kind::String, true),
+    ("API.SMTP.FROM", Kind::String, false),
+    ("API.SMTP.TLS", Kind::String, false),
+    ("API.HWR.APPLICATIONKEY", Kind::String, true),
+    ("API.HWR.HMAC", Kind::String, true),
+    ("API.CODES.LENGTH", Kind::Integer, false),
+    ("API.CODES.ALPHABET", Kind::String, false),
+    ("API.CODES.TTL", Kind::Integer, false),
+    ("API.CODES.MAX_PER_USER", Kind::Integer, false),
+    ("STORAGE.USERS", Kind::String, false),
+    ("STORAGE.CODES", Kind::String, false),
+    ("STORAGE.ATTEMPTS", Kind::String, false),
+    ("STORAGE.AUDIT", Kind::String, false),
+];
+
+/// The `--set KEY=VALUE` flags of the cli, they apply to every config read by this process.
+static FLAGS: OnceLock<Vec<(String, String)>> = OnceLock::new();
+
+/// Sets the flags, which override the file and the env vars. Only the first call counts.
+pub fn set_flags(flags: Vec<(String, String)>) -> Result<(), ConfigError> {
+    for (key, value) in &flags {
+        let (_, kind, _) = key_of(key).ok_or_else(|| ConfigError::UnknownKey(key.clone()))?;
+        kind.parse(value)
+            .ok_or_else(|| ConfigError::OverrideNotValid(key.clone(), kind.name()))?;
+    }
+    // the cli sets them once on startup
+    let _ = FLAGS.set(flags);
+    Ok(())
+}
+
+fn key_of(key: &str) -> Option<&'static (&'static str, Kind, bool)> {
+    KEYS.iter().find(|(k, _, _)| k.eq_ignore_ascii_case(key))
+}
+
+pub fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_"))
+}
+
+pub fn is_secret(key: &str) -> bool {
+    // keys without a fixed name, like API.WEBHOOKS.0.SECRET or the options of a storage backend
+    key.ends_with(".SECRET")
+        || key.ends_with(".PASSWORD")
+        || key_of(key).map(|(_, _, secret)| *secret).unwrap_or(false)
+}
+
+/// The config and where each of its keys comes from.
+#[derive(Debug)]
+pub struct Layered {
+    pub config: Config,
+    sources: BTreeMap<String, Source>,
+    /// a missing file and unknown keys, which are likely typos. The caller logs them.
+    pub warnings: Vec<Problem>,
+}
+
+impl Layered {
+    /// Reads the file, then applies the env vars and the flags on top of it.
+    /// A missing file is taken as empty, the env vars may give everything.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut missing = None;
+        let (mut toml, lines) = match std::fs::read_to_string(path) {
+            Ok(v) => (toml::from_str(&v)?, key_lines(&v)),
+            Err(v) if v.kind() == io::ErrorKind::NotFound => {
+                missing = Some(Problem {
+                    key: path.display().to_string(),
+                    message: "not found, only env vars and flags are used".to_string(),
+                    location: None,
+                });
+                (Value::Table(Table::new()), BTreeMap::new())
+            }
+            Err(v) => return Err(v.into()),
+        };
+
+        let mut sources = BTreeMap::new();
+        collect_file_keys(&toml, "", &mut sources);
+
+        for (key, kind, _) in KEYS {
+            let name = env_name(key);
+            if let Ok(value) = env::var(&name) {
+                let value = kind
+                    .parse(&value)
+                    .ok_or_else(|| ConfigError::OverrideNotValid(name.clone(), kind.name()))?;
+                set(&mut toml, key, value);
+                sources.insert(key.to_string(), Source::Env(name));
+            }
+        }
+
+        for (key, value) in FLAGS.get().into_iter().flatten() {
+            let (key, kind, _) = key_of(key).ok_or_else(|| ConfigError::UnknownKey(key.clone()))?;
+            let value = kind
+                .parse(value)
+                .ok_or_else(|| ConfigError::OverrideNotValid(key.to_string(), kind.name()))?;
+            set(&mut toml, key, value);
+            sources.insert(key.to_string(), Source::Flag);
+        }
+
+        let (config, mut report) = Config::check(&toml);
+        report.locate(&sources, &lines);
+        // a missing file explains the errors best, without them it is only a warning
+        let config = match config {
+            Some(v) => v,
+            None => {
+                report.errors.splice(0..0, missing);
+                return Err(ConfigError::NotValid(Problems(report.errors)));
+            }
+        };
+        report.warnings.splice(0..0, missing);
+        Ok(Self {
+            config,
+            sources,
+            warnings: report.warnings,
+        })
+    }
+
+    /// All effective values with their source, secrets are redacted.
+    pub fn entries(&self) -> Vec<(String, Value, Source)> {
+        self.config
+            .values()
+            .into_iter()
+            .map(|(key, value)| {
+                let value = if is_secret(&key) {
+                    Value::String(REDACTED.to_string())
+                } else {
+                    value
+                };
+                let source = self.sources.get(&key).cloned().unwrap_or(Source::Default);
+                (key, value, source)
+            })
+            .collect()
+    }
+}
+
+fn collect_file_keys(value: &Value, prefix: &str, sources: &mut BTreeMap<String, Source>) {
+    let join = |key: &str| match prefix {
+        "" => key.to_string(),
+        _ => format!("{}.{}", prefix, key),
+    };
+    match value {
+        Value::Table(table) => {
+            for (key, value) in table {
+                collect_file_keys(value, &join(key), sources);
+            }
+        }
+        // only the list of webhooks has tables in an array
+        Value::Array(array) if array.iter().any(Value::is_table) => {
+            for (index, value) in array.iter().enumerate() {
+                collect_file_keys(value, &join(&index.to_string()), sources);
+            }
+        }
+        _ => {
+            sources.insert(prefix.to_string(), Source::File);
+        }
+    }
+}
+
+/// Sets the value of the dotted key, missing tables are created.
+fn set(toml: &mut Value, key: &str, value: Value) {
+    let mut parts: Vec<&str> = key.split('.').collect();
+    let last = parts.pop().unwrap_or_default();
+    let mut table = toml;
+    for part in parts {
+        let tables = match table {
+            Value::Table(v) => v,
+            // a key of the file has the name of a table, it is replaced
+            v => {
+                *v = Value::Table(Table::new());
+                v.as_table_mut().unwrap()
+            }
+        };
+        table = tables
+            .entry(part.to_string())
+            .or_insert_with(|| Value::Table(Table::new()));
+    }
+    if !table.is_table() {
+        *table = Value::Table(Table::new());
+    }
+    table.as_table_mut().unwrap().insert(last.to_string(), value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warnings_are_returned() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"[COMMON]
+LOGLEVEL = "info"
+PORT = 8080
+SOCKET = "{dir}/rmcloud.sock"
+TYPO = 1
+
+[UI]
+URL = "ui.local"
+
+[API]
+SECRET_KEY = "layer-test-secret"
+URL = "api.local"
+DATADIR = "{dir}"
+"#,
+                dir = dir.path().display()
+            ),
+        )
+        .unwrap();
+
+        let layered = Layered::load(&path).unwrap();
+        let warnings: Vec<_> = layered.warnings.iter().map(|v| v.to_string()).collect();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("COMMON.TYPO (line 5)"), "{:?}", warnings);
+    }
+}
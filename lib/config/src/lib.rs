@@ -1,10 +1,15 @@
 mod api;
 mod common;
 mod config;
+mod layer;
+mod report;
+mod storage;
 mod ui;
 
-pub use api::Api;
-pub use common::{Common, CommonError};
-pub use config::read_config;
-pub use config::{Config, ConfigError, TomlError};
-pub use ui::Ui;
+pub use api::{Api, CodePolicy, Smtp, SmtpTls, Webhook};
+pub use common::{Common, Tls};
+pub use config::{Config, ConfigError};
+pub use report::{Problem, Problems};
+pub use storage::{StorageConfig, DEFAULT_BACKEND};
+pub use layer::{env_name, is_secret, set_flags, Layered, Source, ENV_PREFIX, REDACTED};
+pub use ui::{Oidc, Ui};
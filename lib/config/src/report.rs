@@ -0,0 +1,246 @@
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use std::{collections::BTreeMap, fmt};
+use toml::Value;
+
+use crate::Source;
+
+/// A value of the file, which keeps its error instead of failing the whole config.
+/// This way every key is checked and all problems are reported at once.
+#[derive(Debug)]
+pub(crate) enum Checked<T> {
+    Valid(T),
+    Invalid(String),
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for Checked<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        Ok(match value.try_into() {
+            Ok(v) => Checked::Valid(v),
+            Err(v) => Checked::Invalid(v.to_string()),
+        })
+    }
+}
+
+/// The keys of a table, which no field takes.
+pub(crate) type Unknown = BTreeMap<String, Value>;
+
+/// A problem with a key of the config.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// the dotted key, like `API.SMTP.SERVER`
+    pub key: String,
+    pub message: String,
+    /// the line in the file, the env var or the flag, which the value comes from
+    pub location: Option<String>,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{} ({}): {}", self.key, location, self.message),
+            None => write!(f, "{}: {}", self.key, self.message),
+        }
+    }
+}
+
+/// All errors of a config, one per line.
+#[derive(Debug)]
+pub struct Problems(pub Vec<Problem>);
+
+impl fmt::Display for Problems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in &self.0 {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects the problems, while the config is created.
+#[derive(Debug, Default)]
+pub(crate) struct Report {
+    pub errors: Vec<Problem>,
+    pub warnings: Vec<Problem>,
+}
+
+fn problem(key: &str, message: impl Into<String>) -> Problem {
+    Problem {
+        key: key.to_string(),
+        message: message.into(),
+        location: None,
+    }
+}
+
+impl Report {
+    pub fn error(&mut self, key: &str, message: impl Into<String>) {
+        self.errors.push(problem(key, message));
+    }
+
+    pub fn warn(&mut self, key: &str, message: impl Into<String>) {
+        self.warnings.push(problem(key, message));
+    }
+
+    /// Warns about every key, which is not known, as it is likely a typo.
+    pub fn unknown(&mut self, prefix: &str, unknown: &Unknown) {
+        for key in unknown.keys() {
+            self.warn(&join(prefix, key), "is no known key, is it a typo?");
+        }
+    }
+
+    /// The value of an optional key, an invalid value is recorded.
+    pub fn optional<T>(&mut self, key: &str, value: Option<Checked<T>>) -> Option<T> {
+        match value? {
+            Checked::Valid(v) => Some(v),
+            Checked::Invalid(v) => {
+                self.error(key, v);
+                None
+            }
+        }
+    }
+
+    /// The value of a required key, a missing or invalid value is recorded.
+    pub fn required<T>(&mut self, key: &str, value: Option<Checked<T>>) -> Option<T> {
+        if value.is_none() {
+            self.error(key, "is required");
+        }
+        self.optional(key, value)
+    }
+
+    /// Adds where the value of each problem comes from. Missing keys point to their table.
+    pub fn locate(&mut self, sources: &BTreeMap<String, Source>, lines: &BTreeMap<String, usize>) {
+        let locate = |key: &str| -> Option<String> {
+            match sources.get(key) {
+                Some(Source::Env(name)) => return Some(format!("env {}", name)),
+                Some(Source::Flag) => return Some("flag".to_string()),
+                _ => {}
+            }
+            let mut key = key;
+            loop {
+                if let Some(line) = lines.get(key) {
+                    return Some(format!("line {}", line));
+                }
+                key = &key[..key.rfind('.')?];
+            }
+        };
+        for problem in self.errors.iter_mut().chain(self.warnings.iter_mut()) {
+            problem.location = locate(&problem.key);
+        }
+    }
+}
+
+pub(crate) fn join(prefix: &str, key: &str) -> String {
+    match prefix {
+        "" => key.to_string(),
+        _ => format!("{}.{}", prefix, key),
+    }
+}
+
+/// The line of every key and table in the file. A list of tables gets the index of the entry.
+pub(crate) fn key_lines(text: &str) -> BTreeMap<String, usize> {
+    let mut lines = BTreeMap::new();
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    let mut table = String::new();
+
+    let name = |v: &str| -> String {
+        v.split('.')
+            .map(|v| v.trim().trim_matches('"'))
+            .collect::<Vec<_>>()
+            .join(".")
+    };
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if let Some(v) = line.strip_prefix("[[") {
+            let array = name(v.split("]]").next().unwrap_or_default());
+            let index = counts.entry(array.clone()).or_default();
+            table = format!("{}.{}", array, index);
+            *index += 1;
+        } else if let Some(v) = line.strip_prefix('[') {
+            table = name(v.split(']').next().unwrap_or_default());
+        } else if let Some((key, _)) = line.split_once('=').filter(|_| !line.starts_with('#')) {
+            lines.entry(join(&table, &name(key))).or_insert(number + 1);
+            continue;
+        } else {
+            continue;
+        }
+        lines.entry(table.clone()).or_insert(number + 1);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = r#"PORT = 8080
+# LOGLEVEL = "debug"
+
+[API]
+URL = "api.localhost"
+  "DATADIR" = "./data"
+
+[API.SMTP]
+SERVER = "mail"
+
+[[API.WEBHOOKS]]
+URL = "https://a"
+
+[[API.WEBHOOKS]]
+URL = "https://b"
+"#;
+
+    #[test]
+    fn lines_of_keys_and_tables() {
+        let lines = key_lines(TEXT);
+        assert_eq!(lines.get("PORT"), Some(&1));
+        assert_eq!(lines.get("LOGLEVEL"), None);
+        assert_eq!(lines.get("API"), Some(&4));
+        assert_eq!(lines.get("API.URL"), Some(&5));
+        assert_eq!(lines.get("API.DATADIR"), Some(&6));
+        assert_eq!(lines.get("API.SMTP"), Some(&8));
+        assert_eq!(lines.get("API.SMTP.SERVER"), Some(&9));
+        assert_eq!(lines.get("API.WEBHOOKS.0"), Some(&11));
+        assert_eq!(lines.get("API.WEBHOOKS.0.URL"), Some(&12));
+        assert_eq!(lines.get("API.WEBHOOKS.1.URL"), Some(&15));
+    }
+
+    #[test]
+    fn problems_point_to_their_source() {
+        let mut report = Report::default();
+        report.error("API.URL", "is not valid");
+        report.error("API.SMTP.PORT", "is required");
+        report.warn("API.WEBHOOKS.1.URL", "is not https");
+        report.error("API.SECRET", "is too short");
+        report.error("API.TLS", "is set");
+        report.error("UI.URL", "is required");
+
+        let sources = BTreeMap::from([
+            (
+                "API.SECRET".to_string(),
+                Source::Env("RMCLOUD_API_SECRET".to_string()),
+            ),
+            ("API.TLS".to_string(), Source::Flag),
+            ("API.URL".to_string(), Source::File),
+        ]);
+        report.locate(&sources, &key_lines(TEXT));
+
+        let locations: Vec<_> = report
+            .errors
+            .iter()
+            .chain(report.warnings.iter())
+            .map(|v| v.location.as_deref())
+            .collect();
+        assert_eq!(
+            locations,
+            vec![
+                Some("line 5"),
+                Some("line 8"),
+                Some("env RMCLOUD_API_SECRET"),
+                Some("flag"),
+                None,
+                Some("line 15"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use toml::Value;
+
+use crate::report::{join, Checked, Report, Unknown};
+
+/// The backend, which is used without a `[STORAGE]` section.
+pub const DEFAULT_BACKEND: &str = "local";
+
+/// Represents the backends of the storages, by the names they are registered with
+#[derive(Debug, Clone, PartialEq)]
+pub struct StorageConfig {
+    pub users: String,
+    pub codes: String,
+    pub attempts: String,
+    pub audit: String,
+    /// the tables of the section, like `[STORAGE.SQLITE]`, by the lower case name of the backend
+    pub options: BTreeMap<String, Value>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            users: DEFAULT_BACKEND.to_string(),
+            codes: DEFAULT_BACKEND.to_string(),
+            attempts: DEFAULT_BACKEND.to_string(),
+            audit: DEFAULT_BACKEND.to_string(),
+            options: BTreeMap::new(),
+        }
+    }
+}
+
+/// The `[STORAGE]` section as it is written in the file
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub(crate) struct StorageFile {
+    users: Option<Checked<String>>,
+    codes: Option<Checked<String>>,
+    attempts: Option<Checked<String>>,
+    audit: Option<Checked<String>>,
+    /// the options of the backends and unknown keys
+    #[serde(flatten)]
+    other: Unknown,
+}
+
+impl StorageConfig {
+    /// Creates the storage config struct, the whole section is optional
+    pub(crate) fn from_file(file: Option<Checked<StorageFile>>, report: &mut Report) -> Self {
+        let file = match report.optional("STORAGE", file) {
+            Some(v) => v,
+            None => return Self::default(),
+        };
+
+        let mut backend = |key: &str, value: Option<Checked<String>>| -> String {
+            report
+                .optional(&join("STORAGE", key), value)
+                .map(|v| v.to_lowercase())
+                .unwrap_or_else(|| DEFAULT_BACKEND.to_string())
+        };
+        let users = backend("USERS", file.users);
+        let codes = backend("CODES", file.codes);
+        let attempts = backend("ATTEMPTS", file.attempts);
+        let audit = backend("AUDIT", file.audit);
+
+        let (options, unknown): (Unknown, Unknown) =
+            file.other.into_iter().partition(|(_, v)| v.is_table());
+        report.unknown("STORAGE", &unknown);
+
+        Self {
+            users,
+            codes,
+            attempts,
+            audit,
+            options: options
+                .into_iter()
+                .map(|(k, v)| (k.to_lowercase(), v))
+                .collect(),
+        }
+    }
+}
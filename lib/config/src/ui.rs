@@ -1,46 +1,115 @@
-use std::env;
-use thiserror::Error;
-use toml::Value;
-
-use crate::TomlError;
-
-#[derive(Error, Debug)]
-pub enum UiError {
-    #[error("error in ui yaml config")]
-    YamlError(#[from] TomlError),
-    #[error("UI.URL must not contain protocol like http")]
-    UrlContainsProtocol,
-}
+use serde::Deserialize;
+
+use crate::report::{Checked, Report, Unknown};
 
 /// Represents all configs for admin UI
 #[derive(Debug)]
 pub struct Ui {
     pub url: String,
+    pub oidc: Option<Oidc>,
 }
 
-impl Ui {
-    /// Creates the UI config struct and checks for required and optional fields
-    pub fn create(yaml: &Value) -> Result<Self, UiError> {
-        let ui_config = yaml.get("UI").ok_or(TomlError::KeyNotFound("UI.URL"))?;
-        let url = ui_config
-            .get("URL")
-            .ok_or(TomlError::KeyNotFound("UI.URL"))?
-            .as_str()
-            .ok_or(TomlError::WrongType("UI.URL", "String"))?
-            .to_string();
-
-        if url.contains("://") {
-            return Err(UiError::UrlContainsProtocol);
-        }
-        Ok(Self { url })
+/// Represents the config for the login through an OpenID Connect provider
+#[derive(Debug, Clone)]
+pub struct Oidc {
+    /// the issuer url, the provider metadata is discovered from it
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    /// defaults to `https://<UI.URL>/api/auth/oidc/callback`
+    pub redirect_url: String,
+    /// additional scopes to `openid`, defaults to `email` and `profile`
+    pub scopes: Vec<String>,
+    /// creates a user on the first login, if there is none for the email
+    pub provision: bool,
+    /// takes the email without `email_verified`, for providers which never send the claim
+    pub trust_unverified_email: bool,
+    pub groups_claim: String,
+    /// members of this group get `is_admin`, the others lose it.
+    /// Without it, `is_admin` is not changed by the login.
+    pub admin_group: Option<String>,
+}
+
+/// The `[UI]` section as it is written in the file
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub(crate) struct UiFile {
+    url: Option<Checked<String>>,
+    oidc: Option<Checked<OidcFile>>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+struct OidcFile {
+    issuer: Option<Checked<String>>,
+    client_id: Option<Checked<String>>,
+    client_secret: Option<Checked<String>>,
+    redirect_url: Option<Checked<String>>,
+    scopes: Option<Checked<Vec<String>>>,
+    provision: Option<Checked<bool>>,
+    trust_unverified_email: Option<Checked<bool>>,
+    groups_claim: Option<Checked<String>>,
+    admin_group: Option<Checked<String>>,
+    #[serde(flatten)]
+    unknown: Unknown,
+}
+
+impl Oidc {
+    fn from_file(file: Option<Checked<OidcFile>>, url: &str, report: &mut Report) -> Option<Self> {
+        let file = report.optional("UI.OIDC", file)?;
+        report.unknown("UI.OIDC", &file.unknown);
+
+        let issuer = report.required("UI.OIDC.ISSUER", file.issuer);
+        let client_id = report.required("UI.OIDC.CLIENT_ID", file.client_id);
+        let client_secret = report.optional("UI.OIDC.CLIENT_SECRET", file.client_secret);
+        let redirect_url = report
+            .optional("UI.OIDC.REDIRECT_URL", file.redirect_url)
+            .unwrap_or_else(|| format!("https://{}/api/auth/oidc/callback", url));
+        let scopes = report
+            .optional("UI.OIDC.SCOPES", file.scopes)
+            .unwrap_or_else(|| vec!["email".to_string(), "profile".to_string()]);
+        let provision = report
+            .optional("UI.OIDC.PROVISION", file.provision)
+            .unwrap_or(false);
+        let trust_unverified_email = report
+            .optional(
+                "UI.OIDC.TRUST_UNVERIFIED_EMAIL",
+                file.trust_unverified_email,
+            )
+            .unwrap_or(false);
+        let groups_claim = report
+            .optional("UI.OIDC.GROUPS_CLAIM", file.groups_claim)
+            .unwrap_or_else(|| "groups".to_string());
+        let admin_group = report.optional("UI.OIDC.ADMIN_GROUP", file.admin_group);
+
+        Some(Self {
+            issuer: issuer?,
+            client_id: client_id?,
+            client_secret,
+            redirect_url,
+            scopes,
+            provision,
+            trust_unverified_email,
+            groups_claim,
+            admin_group,
+        })
     }
 }
 
-impl Default for Ui {
-    fn default() -> Self {
-        Self {
-            // this will be used, when no config file should be used.
-            url: env::var("UI_URL").expect("UI_URL not found in environment variables."),
+impl Ui {
+    /// Creates the UI config struct and checks for required and optional fields
+    pub(crate) fn from_file(file: Option<Checked<UiFile>>, report: &mut Report) -> Option<Self> {
+        let file = report.required("UI", file)?;
+        report.unknown("UI", &file.unknown);
+
+        let url = report.required("UI.URL", file.url);
+        if url.as_ref().filter(|v| v.contains("://")).is_some() {
+            report.error("UI.URL", "must not contain a protocol like http");
         }
+        let oidc = Oidc::from_file(file.oidc, url.as_deref().unwrap_or_default(), report);
+
+        Some(Self { url: url?, oidc })
     }
 }
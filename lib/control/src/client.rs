@@ -0,0 +1,17 @@
+use std::{io::BufReader, os::unix::net::UnixStream, path::Path, time::Duration};
+
+use crate::{read_message, write_message, ControlError, Request, Response};
+
+/// The server answers right away, only a reload of big storages may take a moment.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends a single request to the server listening on the socket and waits for its response.
+pub fn send(socket: &Path, request: &Request) -> Result<Response, ControlError> {
+    let stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut writer = &stream;
+    write_message(&mut writer, request)?;
+    read_message(&mut BufReader::new(&stream))?.ok_or(ControlError::Closed)
+}
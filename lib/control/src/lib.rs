@@ -0,0 +1,168 @@
+//! The protocol between the cli and the control socket of a running server.
+//! Requests and responses are json objects, one per line.
+
+mod client;
+mod protocol;
+
+pub use client::send;
+pub use protocol::{Collected, Error, ErrorCode, Reply, Request, Response, SessionInfo, Status};
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{self, BufRead, Read, Write};
+use thiserror::Error;
+
+/// Longer lines are rejected, so a client cannot make the server buffer endlessly.
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ControlError {
+    #[error("Io error occurred")]
+    IoError(#[from] io::Error),
+    #[error("Message is not valid json")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Message is longer than {} bytes", MAX_LINE_LENGTH)]
+    TooLong,
+    #[error("Connection closed before a response was received")]
+    Closed,
+}
+
+/// Serializes the message as a single line, including the newline.
+pub fn encode<T: Serialize>(message: &T) -> Result<String, ControlError> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    Ok(line)
+}
+
+pub fn decode<T: DeserializeOwned>(line: &str) -> Result<T, ControlError> {
+    if line.len() > MAX_LINE_LENGTH {
+        return Err(ControlError::TooLong);
+    }
+    Ok(serde_json::from_str(line.trim_end())?)
+}
+
+pub fn write_message<W: Write, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> Result<(), ControlError> {
+    writer.write_all(encode(message)?.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads the next message. Returns None, if the other side closed the connection.
+pub fn read_message<R: BufRead, T: DeserializeOwned>(
+    reader: &mut R,
+) -> Result<Option<T>, ControlError> {
+    let mut line = String::new();
+    let read = reader
+        .take(MAX_LINE_LENGTH as u64 + 1)
+        .read_line(&mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && read > MAX_LINE_LENGTH {
+        return Err(ControlError::TooLong);
+    }
+    decode(&line).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn read(input: &str) -> Result<Option<Request>, ControlError> {
+        read_message(&mut Cursor::new(input.as_bytes()))
+    }
+
+    /// A request padded with spaces to exactly `length` bytes, including the newline.
+    fn padded(length: usize) -> String {
+        let line = encode(&Request::Status).unwrap();
+        let line = line.trim_end();
+        format!("{}{}\n", line, " ".repeat(length - line.len() - 1))
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let request = Request::KickDevice {
+            email: "a@b.c".to_string(),
+            device: "d1".to_string(),
+        };
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, &request).unwrap();
+        write_message(&mut buffer, &Request::Gc).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(
+            read_message::<_, Request>(&mut reader).unwrap(),
+            Some(request)
+        );
+        assert_eq!(
+            read_message::<_, Request>(&mut reader).unwrap(),
+            Some(Request::Gc)
+        );
+        assert_eq!(read_message::<_, Request>(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn line_limit() {
+        assert_eq!(
+            read(&padded(MAX_LINE_LENGTH)).unwrap(),
+            Some(Request::Status)
+        );
+        assert!(matches!(
+            read(&padded(MAX_LINE_LENGTH + 1)),
+            Err(ControlError::TooLong)
+        ));
+        assert!(matches!(
+            decode::<Request>(&padded(MAX_LINE_LENGTH + 1)),
+            Err(ControlError::TooLong)
+        ));
+    }
+
+    #[test]
+    fn long_line_without_newline_is_not_buffered() {
+        let input = "x".repeat(MAX_LINE_LENGTH * 4);
+        assert!(matches!(read(&input), Err(ControlError::TooLong)));
+    }
+
+    #[test]
+    fn last_line_without_newline() {
+        assert_eq!(read("{\"command\":\"gc\"}").unwrap(), Some(Request::Gc));
+    }
+
+    #[test]
+    fn invalid_requests() {
+        assert!(matches!(
+            read("{\"command\":\"nope\"}\n"),
+            Err(ControlError::JsonError(_))
+        ));
+        assert!(matches!(
+            read("not json\n"),
+            Err(ControlError::JsonError(_))
+        ));
+    }
+
+    #[test]
+    fn error_codes() {
+        for code in [
+            ErrorCode::InvalidRequest,
+            ErrorCode::NotFound,
+            ErrorCode::StorageError,
+            ErrorCode::InvalidConfig,
+        ] {
+            let line = encode(&Response::error(code, "m")).unwrap();
+            assert_eq!(
+                line,
+                format!(
+                    "{{\"error\":{{\"code\":\"{}\",\"message\":\"m\"}}}}\n",
+                    code.name()
+                )
+            );
+            match decode::<Response>(&line).unwrap() {
+                Response::Error(v) => assert_eq!(v.code, code),
+                v => panic!("unexpected response {:?}", v),
+            }
+        }
+    }
+}
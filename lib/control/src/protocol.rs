@@ -0,0 +1,111 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A command for the running server. Every request gets exactly one response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    /// Reads the users again, after they were changed outside of the server.
+    ReloadUsers,
+    /// Reads the codes again, after they were changed outside of the server.
+    ReloadCodes,
+    /// Reads the config file again. Settings like the port are only reported, not applied.
+    ReloadConfig,
+    /// Lists the logged in sessions of the admin UI.
+    ListSessions,
+    /// Revokes the device, so all of its tokens are not valid anymore.
+    KickDevice {
+        email: String,
+        device: String,
+    },
+    /// Removes expired codes, UI sessions and forgotten login attempts.
+    Gc,
+    Status,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Ok(Reply),
+    Error(Error),
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error(Error {
+            code,
+            message: message.into(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum Reply {
+    /// The command was executed and has nothing to report.
+    Done,
+    Sessions {
+        sessions: Vec<SessionInfo>,
+    },
+    Collected(Collected),
+    Status(Status),
+    /// The config was swapped, the listed settings changed but need a restart.
+    Reloaded {
+        restart_required: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionInfo {
+    pub handle: String,
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// What the garbage collection removed.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Collected {
+    pub ui_sessions: usize,
+    pub attempts: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Status {
+    pub version: String,
+    pub started_at: DateTime<Utc>,
+    pub ui_sessions: usize,
+    pub screenshare_sessions: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The line was no valid request, e.g. an unknown command.
+    InvalidRequest,
+    /// The given user or device does not exist.
+    NotFound,
+    /// The storage failed, the command may be retried.
+    StorageError,
+    /// The config file cannot be read or is not valid, the old config is kept.
+    InvalidConfig,
+}
+
+impl ErrorCode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidRequest => "invalid_request",
+            ErrorCode::NotFound => "not_found",
+            ErrorCode::StorageError => "storage_error",
+            ErrorCode::InvalidConfig => "invalid_config",
+        }
+    }
+}
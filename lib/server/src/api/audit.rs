@@ -0,0 +1,31 @@
+use crate::{
+    helper::{Admin, RequireScope},
+    metrics, StateAuditStorage,
+};
+use axum::{
+    extract::Query, http::StatusCode, response::IntoResponse, routing::get, Extension, Json, Router,
+};
+use storage::AuditFilter;
+
+/// Answers at most this many events, if the query has no limit.
+const DEFAULT_LIMIT: usize = 1000;
+
+/// Searches the audit log, e.g. `?action=user.deleted&target=someone@example.com`.
+async fn query_handler(
+    _: RequireScope<Admin>,
+    Extension(audit_storage): Extension<StateAuditStorage>,
+    Query(mut filter): Query<AuditFilter>,
+) -> Result<impl IntoResponse, StatusCode> {
+    filter.limit = Some(filter.limit.unwrap_or(DEFAULT_LIMIT));
+    let events = audit_storage.read().unwrap().query(&filter).map_err(|v| {
+        tracing::debug! {?v, "cannot query audit log"};
+        metrics::storage_error("audit");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(events))
+}
+
+/// Routes for the admin api.
+pub fn get_admin_router() -> Router {
+    Router::new().route("/", get(query_handler))
+}
@@ -0,0 +1,117 @@
+use crate::{
+    helper::{audit, client_ip, code_request_keys, reserve_attempt},
+    mail::{StateMailer, CODE_FORGOTTEN_TEMPLATE},
+    metrics, StateAttemptStorage, StateAuditStorage, StateCodeStorage, StateUserStorage,
+};
+use axum::{
+    extract::ConnectInfo, http::StatusCode, response::IntoResponse, routing::post, Extension, Json,
+    Router,
+};
+use config::Config;
+use serde::Deserialize;
+use serde_json::json;
+use std::{net::SocketAddr, sync::Arc};
+use storage::{AuditAction, AuditEvent, AuditSource, EMail};
+
+#[derive(Deserialize, Debug)]
+struct CodeForgotten {
+    email: String,
+}
+
+/// Mails a new code, if the account exists.
+/// The answer is always the same and all work happens in the background,
+/// so neither the answer nor its timing tells, if the account exists.
+#[allow(clippy::too_many_arguments)]
+async fn request_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(user_storage): Extension<StateUserStorage>,
+    Extension(code_storage): Extension<StateCodeStorage>,
+    Extension(attempt_storage): Extension<StateAttemptStorage>,
+    Extension(audit_storage): Extension<StateAuditStorage>,
+    Extension(mailer): Extension<StateMailer>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Json(payload): Json<CodeForgotten>,
+) -> Result<impl IntoResponse, StatusCode> {
+    if !mailer.is_configured() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    let email = EMail::create(&payload.email).map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+
+    let ip = client_ip(&addr);
+    let keys = code_request_keys(&email, addr.map(|ConnectInfo(addr)| addr.ip()));
+    // every request counts, only the lockout of code requests is checked
+    reserve_attempt(&attempt_storage, keys)?.failed();
+
+    tokio::spawn(async move {
+        if user_storage.read().unwrap().get_user(&email).is_err() {
+            tracing::debug! {?email, "code requested for unknown account"};
+            return;
+        }
+
+        let code = match code_storage.write().unwrap().create_code(&email) {
+            Ok(v) => v,
+            Err(v) => {
+                tracing::debug! {?v, "cannot create code for code forgotten mail"};
+                metrics::storage_error("code");
+                return;
+            }
+        };
+        audit(
+            &audit_storage,
+            AuditEvent::new(AuditSource::Api, "anonymous", AuditAction::CodeCreated)
+                .target(&email.0)
+                .ip(ip),
+        );
+
+        let expires_in = format_duration(config.api.codes.ttl_seconds);
+        let values = [
+            ("email", email.0.as_str()),
+            ("code", code.as_str()),
+            ("expires_in", expires_in.as_str()),
+            ("ui_url", config.ui.url.as_str()),
+        ];
+        if let Err(v) = mailer
+            .send(&email.0, CODE_FORGOTTEN_TEMPLATE, &values)
+            .await
+        {
+            tracing::warn! {?v, "cannot send code forgotten mail"};
+        }
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(json!({
+            "message": "If an account exists for this email, a new code was sent to it."
+        })),
+    ))
+}
+
+fn format_duration(seconds: u64) -> String {
+    let (count, unit) = match seconds {
+        s if s % 3600 == 0 => (s / 3600, "hour"),
+        s if s % 60 == 0 => (s / 60, "minute"),
+        s => (s, "second"),
+    };
+    match count {
+        1 => format!("1 {}", unit),
+        _ => format!("{} {}s", count, unit),
+    }
+}
+
+pub fn get_router() -> Router {
+    Router::new().route("/", post(request_handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_duration;
+
+    #[test]
+    fn durations_in_their_largest_unit() {
+        assert_eq!(format_duration(3600), "1 hour");
+        assert_eq!(format_duration(7200), "2 hours");
+        assert_eq!(format_duration(60), "1 minute");
+        assert_eq!(format_duration(90), "90 seconds");
+        assert_eq!(format_duration(1), "1 second");
+    }
+}
@@ -0,0 +1,159 @@
+use crate::{
+    helper::{audit, client_ip, Intgr, RequireScope},
+    metrics, StateAuditStorage, StateUserStorage,
+};
+use axum::{
+    extract::{ConnectInfo, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, patch},
+    Extension, Json, Router,
+};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use storage::{AuditAction, AuditEvent, AuditSource, LocalStorageError};
+
+/// The longest description, which a device can be renamed to.
+const DESCRIPTION_MAX_LENGTH: usize = 100;
+
+#[derive(Deserialize, Debug)]
+struct Rename {
+    description: String,
+}
+
+async fn list_handler(
+    RequireScope(claims, _): RequireScope<Intgr>,
+    Extension(user_storage): Extension<StateUserStorage>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let devices = user_storage
+        .read()
+        .unwrap()
+        .get_devices(&claims.email)
+        .map_err(|v| {
+            tracing::debug! {?v, "cannot load devices"};
+            metrics::storage_error("user");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Json(devices))
+}
+
+async fn rename_handler(
+    RequireScope(claims, _): RequireScope<Intgr>,
+    Extension(user_storage): Extension<StateUserStorage>,
+    Extension(audit_storage): Extension<StateAuditStorage>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Path(id): Path<String>,
+    Json(payload): Json<Rename>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let description = payload.description.trim();
+    if description.is_empty() || description.chars().count() > DESCRIPTION_MAX_LENGTH {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    match user_storage
+        .read()
+        .unwrap()
+        .rename_device(&claims.email, &id, description)
+    {
+        Ok(_) => {
+            audit(
+                &audit_storage,
+                AuditEvent::new(
+                    AuditSource::Api,
+                    &claims.email.0,
+                    AuditAction::DeviceRenamed,
+                )
+                .target(&format!("{}/{}", claims.email.0, id))
+                .ip(client_ip(&addr)),
+            );
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(LocalStorageError::DeviceNotFound) => Err(StatusCode::NOT_FOUND),
+        Err(v) => {
+            tracing::debug! {?v, "cannot rename device"};
+            metrics::storage_error("user");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn revoke_handler(
+    RequireScope(claims, _): RequireScope<Intgr>,
+    Extension(user_storage): Extension<StateUserStorage>,
+    Extension(audit_storage): Extension<StateAuditStorage>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    match user_storage
+        .read()
+        .unwrap()
+        .revoke_device(&claims.email, &id)
+    {
+        Ok(_) => {
+            audit(
+                &audit_storage,
+                AuditEvent::new(
+                    AuditSource::Api,
+                    &claims.email.0,
+                    AuditAction::DeviceRevoked,
+                )
+                .target(&format!("{}/{}", claims.email.0, id))
+                .ip(client_ip(&addr)),
+            );
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(LocalStorageError::DeviceNotFound) => Err(StatusCode::NOT_FOUND),
+        Err(v) => {
+            tracing::debug! {?v, "cannot revoke device"};
+            metrics::storage_error("user");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Routes for the paired devices of the authenticated user.
+pub fn get_router() -> Router {
+    Router::new()
+        .route("/", get(list_handler))
+        .route("/:id", patch(rename_handler).delete(revoke_handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::Claims;
+    use axum::{body::Body, http::Request};
+    use chrono::{Duration, Utc};
+    use storage::EMail;
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn requires_the_intgr_scope() {
+        let claims = Claims {
+            email: EMail::create("user@example.com").unwrap(),
+            device_id: None,
+            scopes: vec!["screenshare".to_string()],
+            expires_at: Utc::now() + Duration::hours(1),
+        };
+        for (method, uri) in [("GET", "/"), ("PATCH", "/d1"), ("DELETE", "/d1")] {
+            let request = Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("content-type", "application/json")
+                .body(Body::from(r#"{"description":"tablet"}"#))
+                .unwrap();
+            let response = get_router()
+                .layer(Extension(claims.clone()))
+                .oneshot(request)
+                .await
+                .unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::FORBIDDEN,
+                "{} {}",
+                method,
+                uri
+            );
+        }
+    }
+}
@@ -1,31 +1,33 @@
 use crate::{
-    axum_server::State,
-    helper::{create_jwt_from_userprofile, verify_and_get_claims},
-    StateCodeStorage, StateUserStorage,
+    helper::{
+        account_key, audit, client_ip, create_jwt_from_userprofile, ip_key, is_device_active,
+        reserve_attempt, verify_and_get_claims,
+    },
+    metrics,
+    webhook::{StateWebhooks, WebhookEvent},
+    StateAttemptStorage, StateAuditStorage, StateCodeStorage, StateUserStorage,
 };
 use axum::{
+    extract::ConnectInfo,
     http::StatusCode,
     response::{Html, IntoResponse},
-    routing::{any, get, post},
+    routing::{get, post},
     Extension, Json, Router,
 };
 use chrono::{DateTime, NaiveDateTime, Utc};
 use config::Config;
-use std::sync::Arc;
-use std::{sync::atomic::Ordering, vec};
-use storage::EMail;
-
-pub async fn api_handler(
-    Extension(state): Extension<Arc<State>>,
-    //    Extension(config): Extension<Arc<Config>>,
-) -> Html<String> {
-    state.api_requests.fetch_add(1, Ordering::SeqCst);
-    tracing::debug! {"got api request"}
-    Html(format!(
-        "api: {}",
-        state.api_requests.load(Ordering::SeqCst)
-    ))
-}
+use std::vec;
+use std::{net::SocketAddr, sync::Arc};
+use storage::{AuditAction, AuditEvent, AuditSource, Device, EMail};
+use uuid::Uuid;
+
+mod audit;
+mod code_forgotten;
+mod device;
+mod screenshare;
+mod webhook;
+
+pub use screenshare::{ScreenshareSessions, StateScreenshareSessions};
 
 use serde::{Deserialize, Serialize};
 
@@ -41,6 +43,10 @@ struct About<'a> {
 struct Login {
     code: String,
     email: String,
+    #[serde(rename = "deviceDesc")]
+    device_desc: Option<String>,
+    #[serde(rename = "firmwareVersion")]
+    firmware_version: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -48,12 +54,15 @@ pub struct JWT {
     pub jwt: String,
 }
 
-// TODO add a "CODE FORGOTTEN" endpoint and link
-
+#[allow(clippy::too_many_arguments)]
 async fn login_handler(
     Extension(config): Extension<Arc<Config>>,
     user_storage: Extension<StateUserStorage>,
     code_storage: Extension<StateCodeStorage>,
+    Extension(webhooks): Extension<StateWebhooks>,
+    Extension(attempt_storage): Extension<StateAttemptStorage>,
+    Extension(audit_storage): Extension<StateAuditStorage>,
+    addr: Option<ConnectInfo<SocketAddr>>,
     Json(payload): Json<Login>,
 ) -> Result<impl IntoResponse, StatusCode> {
     tracing::debug! {?payload, "Got code for login exchange"};
@@ -65,42 +74,84 @@ async fn login_handler(
         }
     };
 
+    let mut attempt_keys = vec![account_key(&email)];
+    if let Some(ConnectInfo(addr)) = addr {
+        attempt_keys.push(ip_key(addr.ip()));
+    }
+    let attempt = reserve_attempt(&attempt_storage, attempt_keys)?;
+    let event =
+        AuditEvent::new(AuditSource::Api, &email.0, AuditAction::CodeUsed).ip(client_ip(&addr));
+
+    // validating and removing under one lock, so a code cannot be used twice
     let validation = {
         code_storage
-            .read()
+            .write()
             .unwrap()
-            .validate_code(&email, &payload.code)
+            .redeem_code(&email, &payload.code)
     };
 
     match validation {
         Ok(_) => {
-            tracing::debug! {"delete code from storage"};
-            code_storage
-                .write()
-                .unwrap()
-                .remove_code(&email, &payload.code)
-                .expect("cannot write to code storage");
+            attempt.succeeded(&account_key(&email));
+
+            let now = Utc::now();
+            let device = Device {
+                id: Uuid::new_v4().to_string(),
+                description: payload
+                    .device_desc
+                    .unwrap_or_else(|| "unknown device".to_string()),
+                paired_at: now,
+                last_seen: now,
+                ip: addr.map(|ConnectInfo(addr)| addr.ip().to_string()),
+                firmware_version: payload.firmware_version,
+            };
 
             tracing::debug! {"create jwt"};
-            let jwt = create_jwt_from_userprofile(
-                config.as_ref(),
+            let jwt = {
+                let user_storage = user_storage.read().unwrap();
                 user_storage
-                    .read()
-                    .unwrap()
-                    .get_user(&email)
-                    .unwrap()
-                    .as_ref(),
+                    .add_device(&email, device.clone())
+                    .map_err(|v| {
+                        tracing::debug! {?v, "cannot store paired device"};
+                        metrics::storage_error("user");
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    })?;
+                create_jwt_from_userprofile(
+                    config.as_ref(),
+                    user_storage.get_user(&email).unwrap().as_ref(),
+                    Some(&device.id),
+                )
+            };
+            audit(
+                &audit_storage,
+                event.target(&format!("{}/{}", email.0, device.id)),
+            );
+            webhooks.emit(
+                WebhookEvent::DevicePaired,
+                &email,
+                serde_json::json!({ "id": device.id, "description": device.description }),
             );
+            webhooks.emit(
+                WebhookEvent::UserLogin,
+                &email,
+                serde_json::json!({ "method": "code" }),
+            );
+            metrics::sync_operation("pair");
             return Ok(Json(JWT { jwt }));
         }
         Err(v) => tracing::debug! {?v, "got error"},
     };
+    audit(&audit_storage, event.target(&email.0).failed());
+    metrics::login_failure("code");
+    attempt.failed();
     Err(StatusCode::UNAUTHORIZED)
 }
 
 pub async fn jwt_handler(
     Extension(config): Extension<Arc<Config>>,
     user_storage: Extension<StateUserStorage>,
+    Extension(audit_storage): Extension<StateAuditStorage>,
+    addr: Option<ConnectInfo<SocketAddr>>,
     Json(payload): Json<JWT>,
 ) -> impl IntoResponse {
     let claims = verify_and_get_claims(&payload.jwt, &config).map_err(|v| {
@@ -114,6 +165,23 @@ pub async fn jwt_handler(
             return Err(StatusCode::UNAUTHORIZED);
         }
     };
+    // tokens of revoked devices cannot be refreshed
+    let device_id = claims.get("DeviceID").map(String::as_str);
+    let event = AuditEvent::new(AuditSource::Api, &email.0, AuditAction::TokenRefreshed)
+        .target(&match device_id {
+            Some(device_id) => format!("{}/{}", email.0, device_id),
+            None => email.0.clone(),
+        })
+        .ip(client_ip(&addr));
+    if let Some(device_id) = device_id {
+        if !is_device_active(&user_storage, &email, device_id) {
+            tracing::debug! {%device_id, "refresh for revoked device"};
+            audit(&audit_storage, event.failed());
+            metrics::login_failure("token");
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+    }
+
     // TODO: check, if expireAt is small. Only refresh jwt then
     let jwt;
     let date_stamp = NaiveDateTime::from_timestamp(
@@ -134,7 +202,10 @@ pub async fn jwt_handler(
                 .get_user(&email)
                 .unwrap()
                 .as_ref(),
+            device_id,
         );
+        audit(&audit_storage, event);
+        metrics::sync_operation("refresh");
         tracing::debug! {"JWT expired. Generated a new one."}
     } else {
         jwt = payload.jwt;
@@ -173,5 +244,17 @@ pub fn get_router() -> Router {
         .route("/login", post(login_handler))
         .route("/jwt", post(jwt_handler))
         .route("/health", get(health_handler))
-        .route("/", any(api_handler))
+        .nest("/code-forgotten", code_forgotten::get_router())
+        .nest("/devices", device::get_router())
+        .nest("/screenshare", screenshare::get_router())
+        .nest("/webhooks", webhook::get_router())
+        .nest("/admin/webhooks", webhook::get_admin_router())
+        .nest("/admin/audit", audit::get_admin_router())
+}
+
+/// The routes, which the admin UI calls on its own host.
+pub fn get_ui_router() -> Router {
+    Router::new()
+        .nest("/code-forgotten", code_forgotten::get_router())
+        .nest("/screenshare", screenshare::get_router())
 }
@@ -0,0 +1,349 @@
+use crate::{
+    gracefully_exit::{self, DetachedTasks, Shutdown},
+    helper::{RequireScope, Screenshare},
+    metrics::{self, WebsocketGuard},
+};
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Path, Query,
+    },
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+/// How many signalling messages can be buffered for a slow peer, before it misses some.
+const RELAY_CAPACITY: usize = 64;
+
+pub type StateScreenshareSessions = Arc<RwLock<ScreenshareSessions>>;
+
+/// A single screenshare session, which relays signalling messages between
+/// the tablet and all viewers of the owner.
+#[derive(Debug)]
+pub struct ScreenshareSession {
+    id: String,
+    owner: String,
+    created_at: DateTime<Utc>,
+    /// messages sent by the tablet, received by the viewers
+    to_viewers: broadcast::Sender<String>,
+    /// messages sent by the viewers, received by the tablet
+    to_tablet: broadcast::Sender<String>,
+}
+
+/// Holds all running screenshare sessions, indexed by their id.
+#[derive(Debug, Default)]
+pub struct ScreenshareSessions {
+    sessions: BTreeMap<String, ScreenshareSession>,
+}
+
+impl ScreenshareSessions {
+    pub fn count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    fn create(&mut self, owner: &str) -> SessionInfo {
+        let (to_viewers, _) = broadcast::channel(RELAY_CAPACITY);
+        let (to_tablet, _) = broadcast::channel(RELAY_CAPACITY);
+
+        let session = ScreenshareSession {
+            id: Uuid::new_v4().to_string(),
+            owner: owner.to_string(),
+            created_at: Utc::now(),
+            to_viewers,
+            to_tablet,
+        };
+        let info = SessionInfo::from(&session);
+        self.sessions.insert(session.id.clone(), session);
+        info
+    }
+
+    /// Returns the session only, if it belongs to the given owner.
+    /// Sessions of other users are handled like they do not exist.
+    fn get_owned(&self, owner: &str, id: &str) -> Option<&ScreenshareSession> {
+        self.sessions.get(id).filter(|s| s.owner == owner)
+    }
+
+    fn list_owned(&self, owner: &str) -> Vec<SessionInfo> {
+        self.sessions
+            .values()
+            .filter(|s| s.owner == owner)
+            .map(SessionInfo::from)
+            .collect()
+    }
+
+    fn remove_owned(&mut self, owner: &str, id: &str) -> Option<ScreenshareSession> {
+        self.get_owned(owner, id)?;
+        self.sessions.remove(id)
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct SessionInfo {
+    id: String,
+    created_at: DateTime<Utc>,
+    viewers: usize,
+}
+
+impl From<&ScreenshareSession> for SessionInfo {
+    fn from(session: &ScreenshareSession) -> Self {
+        Self {
+            id: session.id.clone(),
+            created_at: session.created_at,
+            viewers: session.to_viewers.receiver_count(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    Tablet,
+    Viewer,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConnectParams {
+    role: Role,
+}
+
+async fn create_session_handler(
+    RequireScope(claims, _): RequireScope<Screenshare>,
+    Extension(sessions): Extension<StateScreenshareSessions>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let owner = claims.email.0;
+    let info = sessions.write().unwrap().create(&owner);
+    tracing::debug! {?info, %owner, "screenshare session created"};
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+async fn list_sessions_handler(
+    RequireScope(claims, _): RequireScope<Screenshare>,
+    Extension(sessions): Extension<StateScreenshareSessions>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let owner = claims.email.0;
+    let list = sessions.read().unwrap().list_owned(&owner);
+    Ok(Json(list))
+}
+
+async fn delete_session_handler(
+    RequireScope(claims, _): RequireScope<Screenshare>,
+    Extension(sessions): Extension<StateScreenshareSessions>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let owner = claims.email.0;
+    match sessions.write().unwrap().remove_owned(&owner, &id) {
+        Some(_) => {
+            tracing::debug! {%id, "screenshare session closed"};
+            Ok(StatusCode::NO_CONTENT)
+        }
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn connect_handler(
+    RequireScope(claims, _): RequireScope<Screenshare>,
+    Extension(sessions): Extension<StateScreenshareSessions>,
+    Extension(shutdown): Extension<Shutdown>,
+    Extension(tasks): Extension<DetachedTasks>,
+    Path(id): Path<String>,
+    Query(params): Query<ConnectParams>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, StatusCode> {
+    let owner = claims.email.0;
+
+    let (tx, rx) = {
+        let sessions = sessions.read().unwrap();
+        let session = sessions
+            .get_owned(&owner, &id)
+            .ok_or(StatusCode::NOT_FOUND)?;
+        match params.role {
+            Role::Tablet => (session.to_viewers.clone(), session.to_tablet.subscribe()),
+            Role::Viewer => (session.to_tablet.clone(), session.to_viewers.subscribe()),
+        }
+    };
+
+    let role = params.role;
+    // the upgraded socket runs detached, the shutdown waits for it with this guard
+    let task = tasks.start();
+    Ok(ws.on_upgrade(move |socket| async move {
+        let guard = WebsocketGuard::open();
+        relay(socket, tx, rx, shutdown).await;
+        drop(guard);
+
+        // the session ends, when the tablet stops sharing
+        if role == Role::Tablet {
+            sessions.write().unwrap().remove_owned(&owner, &id);
+            tracing::debug! {%id, "tablet disconnected, screenshare session closed"};
+        }
+        drop(task);
+    }))
+}
+
+/// Forwards all text messages of the socket to `tx` and everything from `rx` to the socket,
+/// until one of both sides is closed or the server stops.
+async fn relay(
+    mut socket: WebSocket,
+    tx: broadcast::Sender<String>,
+    mut rx: broadcast::Receiver<String>,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        tokio::select! {
+            _ = gracefully_exit::wait(&mut shutdown) => {
+                // tells the tablet to reconnect later, instead of a broken connection
+                let close = CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "server shutdown".into(),
+                };
+                let _ = socket.send(Message::Close(Some(close))).await;
+                return;
+            }
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Text(text))) => {
+                    metrics::transferred(metrics::WEBSOCKET, metrics::UPLOAD, text.len() as u64);
+                    // nobody listening is not an error, the peer could connect later
+                    let _ = tx.send(text);
+                }
+                Some(Ok(Message::Close(_))) | None => return,
+                Some(Ok(_)) => (),
+                Some(Err(v)) => {
+                    tracing::debug! {?v, "screenshare socket error"};
+                    return;
+                }
+            },
+            outgoing = rx.recv() => match outgoing {
+                Ok(text) => {
+                    metrics::transferred(metrics::WEBSOCKET, metrics::DOWNLOAD, text.len() as u64);
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::debug! {skipped, "screenshare peer lagged behind"};
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    let _ = socket.send(Message::Close(None)).await;
+                    return;
+                }
+            },
+        }
+    }
+}
+
+pub fn get_router() -> Router {
+    Router::new()
+        .route("/session", post(create_session_handler))
+        .route("/sessions", get(list_sessions_handler))
+        .route("/session/:id", delete(delete_session_handler))
+        .route("/session/:id/ws", get(connect_handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::Claims;
+    use axum::{body::Body, http::Request};
+    use storage::EMail;
+    use tower::ServiceExt;
+
+    fn claims(email: &str, scopes: &[&str]) -> Claims {
+        Claims {
+            email: EMail::create(email).unwrap(),
+            device_id: None,
+            scopes: scopes.iter().map(|v| v.to_string()).collect(),
+            expires_at: Utc::now() + chrono::Duration::hours(1),
+        }
+    }
+
+    /// Sends the request as the user with the claims, like the jwt middleware would.
+    async fn send(
+        sessions: &StateScreenshareSessions,
+        claims: Claims,
+        request: Request<Body>,
+    ) -> (StatusCode, String) {
+        let response = get_router()
+            .layer(Extension(claims))
+            .layer(Extension(sessions.clone()))
+            .oneshot(request)
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn request(method: &str, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sessions_belong_to_their_owner() {
+        let sessions = StateScreenshareSessions::default();
+        let alice = claims("alice@example.com", &["screenshare"]);
+        let bob = claims("bob@example.com", &["screenshare"]);
+
+        let (status, body) = send(&sessions, alice.clone(), request("POST", "/session")).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let id = serde_json::from_str::<serde_json::Value>(&body).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let (_, body) = send(&sessions, bob.clone(), request("GET", "/sessions")).await;
+        assert_eq!(body, "[]");
+        assert!(sessions
+            .read()
+            .unwrap()
+            .get_owned("bob@example.com", &id)
+            .is_none());
+
+        let uri = format!("/session/{}", id);
+        let (status, _) = send(&sessions, bob, request("DELETE", &uri)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(sessions.read().unwrap().count(), 1);
+
+        let (_, body) = send(&sessions, alice.clone(), request("GET", "/sessions")).await;
+        assert!(body.contains(&id));
+        let (status, _) = send(&sessions, alice, request("DELETE", &uri)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(sessions.read().unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_sessions_are_not_found() {
+        let sessions = StateScreenshareSessions::default();
+        let alice = claims("alice@example.com", &["screenshare"]);
+
+        let (status, _) = send(&sessions, alice, request("DELETE", "/session/nope")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(sessions
+            .read()
+            .unwrap()
+            .get_owned("alice@example.com", "nope")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn requires_the_screenshare_scope() {
+        let sessions = StateScreenshareSessions::default();
+        let alice = claims("alice@example.com", &["sync15"]);
+
+        let (status, _) = send(&sessions, alice, request("POST", "/session")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(sessions.read().unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,145 @@
+use crate::{
+    helper::{Admin, Intgr, RequireScope},
+    metrics,
+    webhook::{self, StateWebhooks},
+    StateUserStorage,
+};
+use axum::{
+    extract::Path,
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{delete, get},
+    Extension, Json, Router,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use storage::{LocalStorageError, UserWebhook};
+use uuid::Uuid;
+
+/// Length of the generated secret, if the user does not give one.
+const SECRET_SIZE: usize = 32;
+
+#[derive(Deserialize, Debug)]
+struct NewWebhook {
+    url: String,
+    secret: Option<String>,
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+/// The webhook without its secret, which is only shown once on creation.
+#[derive(Serialize, Debug)]
+struct WebhookInfo {
+    id: String,
+    url: String,
+    events: Vec<String>,
+}
+
+impl From<UserWebhook> for WebhookInfo {
+    fn from(webhook: UserWebhook) -> Self {
+        Self {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events,
+        }
+    }
+}
+
+async fn list_handler(
+    RequireScope(claims, _): RequireScope<Intgr>,
+    Extension(user_storage): Extension<StateUserStorage>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let email = claims.email;
+    let webhooks = user_storage
+        .read()
+        .unwrap()
+        .get_webhooks(&email)
+        .map_err(|v| {
+            tracing::debug! {?v, "cannot load webhooks"};
+            metrics::storage_error("user");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(
+        webhooks
+            .into_iter()
+            .map(WebhookInfo::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+async fn create_handler(
+    RequireScope(claims, _): RequireScope<Intgr>,
+    Extension(user_storage): Extension<StateUserStorage>,
+    Json(payload): Json<NewWebhook>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let email = claims.email;
+    if let Err(v) = webhook::check_public_url(&payload.url).await {
+        tracing::debug! {%v, url = %payload.url, "webhook url rejected"};
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let webhook = UserWebhook {
+        id: Uuid::new_v4().to_string(),
+        url: payload.url,
+        secret: payload.secret.unwrap_or_else(|| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(SECRET_SIZE)
+                .map(char::from)
+                .collect()
+        }),
+        events: payload.events,
+    };
+
+    user_storage
+        .read()
+        .unwrap()
+        .add_webhook(&email, webhook.clone())
+        .map_err(|v| {
+            tracing::debug! {?v, "cannot store webhooks"};
+            metrics::storage_error("user");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+async fn delete_handler(
+    RequireScope(claims, _): RequireScope<Intgr>,
+    Extension(user_storage): Extension<StateUserStorage>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let email = claims.email;
+
+    match user_storage.read().unwrap().remove_webhook(&email, &id) {
+        Ok(()) => {}
+        Err(LocalStorageError::WebhookNotFound) => return Err(StatusCode::NOT_FOUND),
+        Err(v) => {
+            tracing::debug! {?v, "cannot store webhooks"};
+            metrics::storage_error("user");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn deliveries_handler(
+    _: RequireScope<Admin>,
+    Extension(webhooks): Extension<StateWebhooks>,
+) -> impl IntoResponse {
+    Json(webhooks.deliveries())
+}
+
+/// Routes for the webhooks of the authenticated user.
+pub fn get_router() -> Router {
+    Router::new()
+        .route("/", get(list_handler).post(create_handler))
+        .route("/:id", delete(delete_handler))
+}
+
+/// Routes for the admin api.
+pub fn get_admin_router() -> Router {
+    Router::new().route("/deliveries", get(deliveries_handler))
+}
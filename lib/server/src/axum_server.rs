@@ -1,78 +1,193 @@
 use std::{
     net::SocketAddr,
-    sync::{atomic::AtomicUsize, Arc, RwLock},
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 
-use crate::{api, ui};
+use crate::{
+    api,
+    gracefully_exit::{self, DetachedTasks, Shutdown},
+    helper::jwt_auth,
+    listener,
+    metrics,
+    reload::{current_config, Reloader},
+    tls, ui,
+    webhook::{StateWebhooks, Webhooks},
+    StateAttemptStorage, StateAuditStorage,
+};
 use axum::{
     body::Body,
-    extract::Host,
-    http::{Request, StatusCode},
+    extract::{Host, MatchedPath},
+    http::{header, Request, StatusCode},
+    middleware,
     response::IntoResponse,
     routing::any,
     Extension, Router,
 };
 use config::Config;
 use storage::{CodeStorage, UserStorage};
+use tokio::time::{timeout_at, Instant};
 use tower::{ServiceBuilder, ServiceExt};
 use tower_http::trace::TraceLayer;
 
-pub struct State {
-    pub api_requests: AtomicUsize,
-    pub website_requests: AtomicUsize,
-}
-
 async fn handler_404() -> impl IntoResponse {
     (StatusCode::NOT_FOUND, "nothing to see here")
 }
 
+/// Records the metrics of all matched routes.
+fn tracked(router: Router) -> Router {
+    router.route_layer(middleware::from_fn(metrics::track))
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn run_server(
-    config: Arc<Config>,
-    axum_rx: tokio::sync::oneshot::Receiver<()>,
+    reloader: Arc<Reloader>,
+    mut shutdown: Shutdown,
     user_storage: Arc<RwLock<Box<dyn UserStorage>>>,
     code_storage: Arc<RwLock<Box<dyn CodeStorage>>>,
+    attempt_storage: StateAttemptStorage,
+    audit_storage: StateAuditStorage,
+    ui_sessions: ui::StateUiSessions,
+    screenshare_sessions: api::StateScreenshareSessions,
 ) -> () {
     let notfound_router = Router::new().fallback(any(handler_404));
-    let state = Arc::new(State {
-        website_requests: AtomicUsize::new(0),
-        api_requests: AtomicUsize::new(0),
-    });
 
-    let config_req = config.clone();
+    let webhooks: StateWebhooks = Arc::new(Webhooks::new(
+        reloader.config.clone(),
+        user_storage.clone(),
+    ));
+    let detached_tasks = DetachedTasks::default();
+    // listeners and tls are set up once, a reload cannot change them
+    let config = reloader.current();
 
-    let app = Router::new()
-        .route(
-            "/*path",
-            any(|Host(hostname): Host, request: Request<Body>| async move {
-                if hostname.as_str() == config_req.api.url.as_str().to_string() {
-                    api::get_router().oneshot(request).await
-                } else if hostname.as_str() == config_req.ui.url.as_str().to_string() {
-                    ui::get_router().oneshot(request).await
+    let app = Router::new().route(
+        "/*path",
+        any(
+            |Host(hostname): Host,
+             Extension(config): Extension<Arc<Config>>,
+             mut request: Request<Body>| async move {
+                // the inner routers report their own routes to the metrics
+                request.extensions_mut().remove::<MatchedPath>();
+                // http/2 has no host header and `Host` falls back to the uri without the port
+                let hostname = match request.uri().authority() {
+                    Some(authority)
+                        if request.headers().get(header::HOST).is_none()
+                            && request.uri().host() == Some(hostname.as_str()) =>
+                    {
+                        authority.to_string()
+                    }
+                    _ => hostname,
+                };
+                if hostname == config.api.url {
+                    tracked(api::get_router()).oneshot(request).await
+                } else if hostname == config.ui.url {
+                    tracked(ui::get_router()).oneshot(request).await
                 } else {
                     notfound_router.oneshot(request).await
                 }
-            }),
-        )
-        .layer(
-            ServiceBuilder::new()
-                .layer(Extension(config.clone()))
-                .layer(Extension(state))
-                .layer(Extension(user_storage))
-                .layer(Extension(code_storage))
-                // See https://docs.rs/tower-http/0.1.1/tower_http/trace/index.html for more details.
-                // More customization see https://github.com/tokio-rs/axum/blob/ac7037d28208403d6030a47fdd9b0ff9cf2a9009/examples/tracing-aka-logging/src/main.rs#L37
-                .layer(TraceLayer::new_for_http()),
-            //TODO missing jwt auth
-        );
+            },
+        ),
+    );
+
+    let layers = ServiceBuilder::new()
+        .layer(Extension(reloader.config.clone()))
+        .layer(middleware::from_fn(current_config))
+        .layer(Extension(user_storage))
+        .layer(Extension(code_storage))
+        .layer(Extension(attempt_storage))
+        .layer(Extension(audit_storage))
+        .layer(Extension(screenshare_sessions))
+        .layer(Extension(webhooks))
+        .layer(Extension(ui_sessions))
+        .layer(Extension(reloader.mailer.clone()))
+        .layer(Extension(reloader.oidc_login.clone()))
+        .layer(Extension(shutdown.clone()))
+        .layer(Extension(detached_tasks.clone()))
+        // See https://docs.rs/tower-http/0.1.1/tower_http/trace/index.html for more details.
+        // More customization see https://github.com/tokio-rs/axum/blob/ac7037d28208403d6030a47fdd9b0ff9cf2a9009/examples/tracing-aka-logging/src/main.rs#L37
+        .layer(TraceLayer::new_for_http())
+        // needs the config extension above, handlers declare their scopes with `RequireScope`
+        .layer(middleware::from_fn(jwt_auth));
+
+    // the main port routes by host, the optional ports serve a single router
+    let bind = &config.common.bind;
+    let mut routers = vec![(bind, config.common.port, app.layer(layers.clone()))];
+    if let Some(port) = config.common.api_port {
+        routers.push((bind, port, tracked(api::get_router()).layer(layers.clone())));
+    }
+    if let Some(port) = config.common.ui_port {
+        routers.push((bind, port, tracked(ui::get_router()).layer(layers.clone())));
+    }
+    // metrics are only served on the admin listener, never with the public routers
+    let admin_bind = vec![config.common.admin_bind];
+    if let Some(port) = config.common.admin_port {
+        routers.push((
+            &admin_bind,
+            port,
+            metrics::get_router().layer(TraceLayer::new_for_http()),
+        ));
+    }
+
+    let (rustls, watcher) = match &config.common.tls {
+        Some(tls) => {
+            let rustls = tls::load(&config, tls)
+                .await
+                .expect("Cannot load tls certificate.");
+            let watcher = tls::watch(rustls.clone(), tls.clone());
+            (Some(rustls), Some(watcher))
+        }
+        None => (None, None),
+    };
+
+    let mut handles = vec![];
+    let mut servers = vec![];
+    for (bind, port, router) in routers {
+        for ip in bind {
+            let addr = SocketAddr::new(*ip, port);
+            let tcp =
+                listener::bind(addr).unwrap_or_else(|v| panic!("Cannot listen on {}: {}", addr, v));
+            let service = router
+                .clone()
+                .into_make_service_with_connect_info::<SocketAddr>();
+            let handle = axum_server::Handle::new();
+            handles.push(handle.clone());
+
+            servers.push(match &rustls {
+                Some(rustls) => {
+                    println!("listening on https://{}", addr);
+                    tokio::spawn(
+                        axum_server::from_tcp_rustls(tcp, rustls.clone())
+                            .handle(handle)
+                            .serve(service),
+                    )
+                }
+                None => {
+                    println!("listening on http://{}", addr);
+                    tokio::spawn(axum_server::from_tcp(tcp).handle(handle).serve(service))
+                }
+            });
+        }
+    }
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], config.common.port));
-    println!("listening on http://{}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(async {
-            axum_rx.await.ok();
-            tracing::debug! {"Close axum"};
-        })
-        .await
-        .unwrap();
+    gracefully_exit::wait(&mut shutdown).await;
+    // read now, so a reloaded timeout is used
+    let timeout = Duration::from_secs(reloader.current().common.shutdown_timeout_seconds);
+    let deadline = Instant::now() + timeout;
+    tracing::debug! {?timeout, "Close axum, wait for running requests"};
+    for handle in handles {
+        // stops accepting right away, the open connections are closed after the timeout
+        handle.graceful_shutdown(Some(timeout));
+    }
+    for server in servers {
+        if let Ok(Err(v)) = server.await {
+            tracing::warn! {?v, "listener stopped with error"};
+        }
+    }
+    // upgraded websockets got the shutdown too, they share the timeout with the requests
+    if timeout_at(deadline, detached_tasks.wait()).await.is_err() {
+        tracing::warn! {running = detached_tasks.running(), "websockets did not close in time"};
+    }
+    if let Some(watcher) = watcher {
+        watcher.abort();
+    }
 }
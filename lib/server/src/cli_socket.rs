@@ -1,66 +1,241 @@
-use std::{
-    io::{self, Read},
-    net::TcpListener,
-    sync::Arc,
+use std::{fs, io, os::unix::fs::PermissionsExt, path::Path, sync::Arc};
+
+use crate::{
+    api::StateScreenshareSessions,
+    gracefully_exit::{self, Shutdown},
+    helper::collect_attempts,
+    reload::Reloader,
+    ui::StateUiSessions,
+    StateAttemptStorage, StateCodeStorage, StateUserStorage,
 };
 
+use chrono::{DateTime, Utc};
 use config::Config;
+use control::{Collected, ControlError, ErrorCode, Reply, Request, Response, MAX_LINE_LENGTH};
+use storage::{EMail, LocalStorageError};
 use tokio::{
-    sync::oneshot::error::TryRecvError,
-    time::{sleep, Duration},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    time::{sleep, timeout, Duration},
 };
 
-pub async fn run_cli_socket(
-    config: Arc<Config>,
-    mut socket_rx: tokio::sync::oneshot::Receiver<()>,
-) -> tokio::task::JoinHandle<()> {
-    let socket_port = config.common.socket;
+/// A client, which neither sends nor reads for this time, is disconnected.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The pause after a failed accept, before the next try.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", socket_port))
-        .expect("Cannot create socket listener for cli.");
-    listener
-        .set_nonblocking(true)
-        .expect("Cannot set non-blocking");
+/// Everything the commands of the cli act on.
+pub struct Control {
+    pub user_storage: StateUserStorage,
+    pub code_storage: StateCodeStorage,
+    pub attempt_storage: StateAttemptStorage,
+    pub ui_sessions: StateUiSessions,
+    pub screenshare_sessions: StateScreenshareSessions,
+    pub reloader: Arc<Reloader>,
+    pub started_at: DateTime<Utc>,
+}
 
-    tokio::spawn(async move {
-        println!("start listener");
-        for stream in listener.incoming() {
-            match socket_rx.try_recv() {
-                Ok(_) => {
-                    tracing::debug! {"Close cli socket"};
-                    return ();
+impl Control {
+    async fn handle(&self, request: Request) -> Response {
+        tracing::debug! {?request, "got cli request"};
+        match request {
+            Request::ReloadUsers => reply(self.user_storage.write().unwrap().reload(), Reply::Done),
+            Request::ReloadCodes => reply(self.code_storage.write().unwrap().reload(), Reply::Done),
+            Request::ReloadConfig => match self.reloader.reload().await {
+                Ok(restart_required) => Response::Ok(Reply::Reloaded {
+                    restart_required: restart_required.iter().map(|v| v.to_string()).collect(),
+                }),
+                Err(v) => {
+                    tracing::warn! {error = %v.describe(), "cannot reload config, keep the old one"};
+                    Response::error(ErrorCode::InvalidConfig, v.describe())
                 }
-                Err(TryRecvError::Closed) => panic!("CLI socket closed unexpected"),
-                Err(TryRecvError::Empty) => (),
+            },
+            Request::ListSessions => Response::Ok(Reply::Sessions {
+                sessions: self.ui_sessions.read().unwrap().list(),
+            }),
+            Request::KickDevice { email, device } => {
+                let email = match EMail::create(&email) {
+                    Ok(v) => v,
+                    Err(_) => return Response::error(ErrorCode::InvalidRequest, "invalid email"),
+                };
+                let revoked = self
+                    .user_storage
+                    .read()
+                    .unwrap()
+                    .revoke_device(&email, &device);
+                reply(revoked, Reply::Done)
             }
-            sleep(Duration::from_millis(100)).await;
+            Request::Gc => {
+                let codes = self.code_storage.write().unwrap().clean_codes();
+                if let Err(v) = codes {
+                    return reply(Err(v), Reply::Done);
+                }
+                let ui_sessions = self.ui_sessions.write().unwrap().remove_expired();
+                match collect_attempts(&self.attempt_storage) {
+                    Ok(attempts) => Response::Ok(Reply::Collected(Collected {
+                        ui_sessions,
+                        attempts,
+                    })),
+                    Err(v) => reply(Err(v), Reply::Done),
+                }
+            }
+            Request::Status => Response::Ok(Reply::Status(control::Status {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                started_at: self.started_at,
+                ui_sessions: self.ui_sessions.read().unwrap().count(),
+                screenshare_sessions: self.screenshare_sessions.read().unwrap().count(),
+            })),
+        }
+    }
 
-            match stream {
-                Ok(mut s) => {
-                    // TODO: process tcp socket incoming messages from cli
-                    tracing::debug! {?s, "got cli message"};
-                    /*
-                    let buf_reader = BufReader::new(&mut s);
-                    let http_request: Vec<_> = buf_reader
-                    .lines()
-                    .map(|result| result.unwrap())
-                    .take_while(|line| !line.is_empty())
-                    .collect();
-                    */
-                    let mut http_request = [0; 512];
-                    let bytes_read = s.read(&mut http_request).expect("Cannot read tcp stream");
-                    if bytes_read == 0 {
-                        return;
-                    }
+    /// Answers all requests of the client, until it closes the connection or stays idle too long.
+    async fn serve(&self, stream: UnixStream) -> Result<(), ControlError> {
+        let (reader, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let read = timeout(
+                CLIENT_TIMEOUT,
+                (&mut reader)
+                    .take(MAX_LINE_LENGTH as u64 + 1)
+                    .read_line(&mut line),
+            )
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+            if read == 0 {
+                return Ok(());
+            }
 
-                    let http_request = String::from_utf8(http_request.to_vec());
-                    tracing::debug! {?http_request, "got cli request"};
+            let response = match control::decode::<Request>(&line) {
+                Ok(request) => self.handle(request).await,
+                Err(ControlError::JsonError(v)) => {
+                    Response::error(ErrorCode::InvalidRequest, v.to_string())
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
-                    continue;
+                Err(v) => {
+                    // the rest of the line cannot be told apart from the next request
+                    let response = Response::error(ErrorCode::InvalidRequest, v.to_string());
+                    write_response(&mut writer, &response).await?;
+                    return Err(v);
                 }
-                Err(e) => panic!("encountered IO error: {}", e),
+            };
+            write_response(&mut writer, &response).await?;
+        }
+    }
+}
+
+async fn write_response(
+    writer: &mut OwnedWriteHalf,
+    response: &Response,
+) -> Result<(), ControlError> {
+    timeout(
+        CLIENT_TIMEOUT,
+        writer.write_all(control::encode(response)?.as_bytes()),
+    )
+    .await
+    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    Ok(())
+}
+
+fn reply(result: Result<(), LocalStorageError>, reply: Reply) -> Response {
+    match result {
+        Ok(_) => Response::Ok(reply),
+        Err(LocalStorageError::UserNotFound) => {
+            Response::error(ErrorCode::NotFound, "user not found")
+        }
+        Err(LocalStorageError::DeviceNotFound) => {
+            Response::error(ErrorCode::NotFound, "device not found")
+        }
+        Err(v) => {
+            tracing::warn! {?v, "cli request failed in storage"};
+            Response::error(ErrorCode::StorageError, v.to_string())
+        }
+    }
+}
+
+/// Binds the socket, only the user running the server can connect to it.
+fn bind(path: &Path) -> io::Result<UnixListener> {
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another server is listening on the cli socket",
+            ));
+        }
+        // left over by a server, which did not stop gracefully
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+pub async fn run_cli_socket(
+    config: Arc<Config>,
+    control: Control,
+    mut shutdown: Shutdown,
+) -> tokio::task::JoinHandle<()> {
+    let path = config.common.socket.clone();
+    let listener = bind(&path).expect("Cannot create socket listener for cli.");
+    let control = Arc::new(control);
+
+    tokio::spawn(async move {
+        println!("cli socket listening on {}", path.display());
+        loop {
+            tokio::select! {
+                _ = gracefully_exit::wait(&mut shutdown) => {
+                    tracing::debug! {"Close cli socket"};
+                    break;
+                }
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        // every client gets its own task, so a slow one does not block the others
+                        let control = control.clone();
+                        tokio::spawn(async move {
+                            if let Err(v) = control.serve(stream).await {
+                                tracing::debug! {?v, "cli connection failed"};
+                            }
+                        });
+                    }
+                    Err(v) => {
+                        // e.g. too many open files, accepting again right away would spin
+                        tracing::warn! {?v, "cannot accept cli connection"};
+                        sleep(ACCEPT_BACKOFF).await;
+                    }
+                },
             }
         }
+        if let Err(v) = fs::remove_file(&path) {
+            tracing::warn! {?v, "cannot remove cli socket"};
+        }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(result: Result<(), LocalStorageError>) -> Option<ErrorCode> {
+        match reply(result, Reply::Done) {
+            Response::Ok(_) => None,
+            Response::Error(v) => Some(v.code),
+        }
+    }
+
+    #[test]
+    fn storage_errors_map_to_codes() {
+        assert_eq!(code(Ok(())), None);
+        assert_eq!(
+            code(Err(LocalStorageError::UserNotFound)),
+            Some(ErrorCode::NotFound)
+        );
+        assert_eq!(
+            code(Err(LocalStorageError::DeviceNotFound)),
+            Some(ErrorCode::NotFound)
+        );
+        assert_eq!(
+            code(Err(io::Error::from(io::ErrorKind::PermissionDenied).into())),
+            Some(ErrorCode::StorageError)
+        );
+    }
+}
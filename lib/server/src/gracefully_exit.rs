@@ -1,26 +1,115 @@
-use tokio;
-use tokio::sync::oneshot;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{watch, Notify},
+};
 
-pub fn create_receivers() -> (
-    tokio::sync::oneshot::Receiver<()>,
-    tokio::sync::oneshot::Receiver<()>,
-) {
-    let (socket_tx, socket_rx) = oneshot::channel();
-    let (axum_tx, axum_rx) = oneshot::channel();
+/// Changes once, when the server shall stop. Every part waits on its own clone.
+pub type Shutdown = watch::Receiver<bool>;
+
+/// Waits for SIGTERM of systemd or docker, SIGINT of ctrl-c and SIGQUIT.
+pub fn create_receiver() -> Shutdown {
+    let (tx, rx) = watch::channel(false);
 
     tokio::spawn(async move {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("expect tokio signal ctrl-c");
+        let mut terminate = signal(SignalKind::terminate()).expect("Cannot listen for SIGTERM.");
+        let mut interrupt = signal(SignalKind::interrupt()).expect("Cannot listen for SIGINT.");
+        let mut quit = signal(SignalKind::quit()).expect("Cannot listen for SIGQUIT.");
 
-        println!("Signal exit...");
-        socket_tx
-            .send(())
-            .expect("Cannot send close command to cli socket.");
+        let name = tokio::select! {
+            _ = terminate.recv() => "SIGTERM",
+            _ = interrupt.recv() => "SIGINT",
+            _ = quit.recv() => "SIGQUIT",
+        };
 
-        axum_tx
-            .send(())
-            .expect("Cannot send close command to axum server.");
+        println!("Signal {}, exit...", name);
+        // fails only, if everything stopped already
+        if tx.send(true).is_err() {
+            tracing::debug! {"nothing left to shut down"};
+        }
     });
-    (socket_rx, axum_rx)
+    rx
+}
+
+/// Returns, when the server shall stop. Also returns, if the sender is gone.
+pub async fn wait(shutdown: &mut Shutdown) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Counts the tasks, which run detached from their connection, like upgraded websockets.
+/// The listeners do not wait for them on shutdown, so the server waits here.
+#[derive(Clone, Default)]
+pub struct DetachedTasks(Arc<Counter>);
+
+#[derive(Default)]
+struct Counter {
+    running: AtomicUsize,
+    finished: Notify,
+}
+
+/// Counts a running task, until it is dropped.
+pub struct TaskGuard(Arc<Counter>);
+
+impl DetachedTasks {
+    pub fn start(&self) -> TaskGuard {
+        self.0.running.fetch_add(1, Ordering::SeqCst);
+        TaskGuard(self.0.clone())
+    }
+
+    pub fn running(&self) -> usize {
+        self.0.running.load(Ordering::SeqCst)
+    }
+
+    /// Returns, when no task runs anymore.
+    pub async fn wait(&self) {
+        loop {
+            // created before the check, so a task finishing in between is not missed
+            let finished = self.0.finished.notified();
+            if self.running() == 0 {
+                return;
+            }
+            finished.await;
+        }
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if self.0.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.finished.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn waits_for_detached_tasks() {
+        let tasks = DetachedTasks::default();
+        tasks.wait().await;
+
+        let guards: Vec<_> = (0..3).map(|_| tasks.start()).collect();
+        let task = tokio::spawn(async move {
+            for guard in guards {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                drop(guard);
+            }
+        });
+        assert_eq!(tasks.running(), 3);
+        tokio::time::timeout(Duration::from_secs(5), tasks.wait())
+            .await
+            .unwrap();
+        assert_eq!(tasks.running(), 0);
+        task.await.unwrap();
+    }
 }
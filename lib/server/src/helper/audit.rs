@@ -0,0 +1,18 @@
+use axum::extract::ConnectInfo;
+use std::net::SocketAddr;
+use storage::AuditEvent;
+
+use crate::{metrics, StateAuditStorage};
+
+/// Writes the event to the audit log. The action is already done,
+/// so a failing log does not fail the request and is only reported.
+pub fn audit(storage: &StateAuditStorage, event: AuditEvent) {
+    if let Err(v) = storage.read().unwrap().append(&event) {
+        tracing::error! {?v, ?event, "cannot write audit event"};
+        metrics::storage_error("audit");
+    }
+}
+
+pub fn client_ip(addr: &Option<ConnectInfo<SocketAddr>>) -> Option<String> {
+    addr.as_ref().map(|ConnectInfo(addr)| addr.ip().to_string())
+}
@@ -0,0 +1,228 @@
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequest, RequestParts},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use config::Config;
+use std::{collections::BTreeMap, marker::PhantomData, net::SocketAddr, sync::Arc};
+use storage::EMail;
+
+use super::verify_and_get_claims;
+use crate::{metrics, StateUserStorage};
+
+/// Last-seen of a device is only written again after this time, to save writes.
+const DEVICE_TOUCH_SECONDS: i64 = 60;
+
+/// The typed claims of a verified jwt. The middleware puts it into the request extensions.
+#[derive(Debug, Clone)]
+pub struct Claims {
+    pub email: EMail,
+    pub device_id: Option<String>,
+    pub scopes: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl Claims {
+    fn from_map(claims: &BTreeMap<String, String>) -> Option<Self> {
+        let email = EMail::create(claims.get("UserID")?).ok()?;
+        let expires_at = claims.get("ExpiresAt")?.parse::<i64>().ok()?;
+
+        Some(Self {
+            email,
+            device_id: claims.get("DeviceID").cloned(),
+            scopes: claims
+                .get("Scopes")
+                .map(|s| s.split(' ').map(str::to_string).collect())
+                .unwrap_or_default(),
+            expires_at: DateTime::<Utc>::from_utc(
+                NaiveDateTime::from_timestamp_opt(expires_at, 0)?,
+                Utc,
+            ),
+        })
+    }
+
+    /// Scopes can carry a parameter like `mail:-1`, which is ignored here.
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes
+            .iter()
+            .any(|s| s.split(':').next() == Some(scope))
+    }
+}
+
+/// Checks, if the device is still registered for the user. Revoked devices are removed.
+pub fn is_device_active(user_storage: &StateUserStorage, email: &EMail, device_id: &str) -> bool {
+    match user_storage.read().unwrap().get_devices(email) {
+        Ok(devices) => devices.iter().any(|d| d.id == device_id),
+        Err(v) => {
+            tracing::debug! {?v, "cannot load devices"};
+            metrics::storage_error("user");
+            false
+        }
+    }
+}
+
+/// Rejects tokens of revoked devices and updates last-seen of the others.
+fn check_device<B>(req: &Request<B>, claims: &Claims) -> bool {
+    let device_id = match &claims.device_id {
+        Some(v) => v,
+        None => return true,
+    };
+    let user_storage = match req.extensions().get::<StateUserStorage>() {
+        Some(v) => v,
+        None => return false,
+    };
+
+    let user_storage = user_storage.read().unwrap();
+    let device = match user_storage.get_devices(&claims.email) {
+        Ok(devices) => devices.into_iter().find(|d| &d.id == device_id),
+        Err(v) => {
+            tracing::debug! {?v, "cannot load devices"};
+            metrics::storage_error("user");
+            None
+        }
+    };
+    let device = match device {
+        Some(v) => v,
+        None => return false,
+    };
+
+    if device.last_seen < Utc::now() - Duration::seconds(DEVICE_TOUCH_SECONDS) {
+        let ip = req
+            .extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+        if let Err(v) = user_storage.touch_device(&claims.email, device_id, ip) {
+            tracing::debug! {?v, "cannot update last seen of device"};
+            metrics::storage_error("user");
+        }
+    }
+    true
+}
+
+/// Verifies the bearer token of the request and puts its [`Claims`] into the extensions.
+/// Invalid or expired tokens and tokens of revoked devices leave the claims unset, so
+/// [`Claims`] and [`RequireScope`] reject them with UNAUTHORIZED. Public routes like
+/// /login and /jwt keep working with a stale Authorization header.
+pub async fn jwt_auth<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let token = match get_token(&req) {
+        Some(v) => v,
+        None => return next.run(req).await,
+    };
+
+    let config = match req.extensions().get::<Arc<Config>>() {
+        Some(v) => v.clone(),
+        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    if let Some(claims) = verify(&req, &token, &config) {
+        req.extensions_mut().insert(claims);
+    }
+    next.run(req).await
+}
+
+/// The claims of a valid token of an active device.
+fn verify<B>(req: &Request<B>, token: &str, config: &Config) -> Option<Claims> {
+    let claims = match verify_and_get_claims(token, config) {
+        Ok(v) => v,
+        Err(v) => {
+            tracing::debug! {?v, "Error in jwt verification"};
+            return None;
+        }
+    };
+    let claims = match Claims::from_map(&claims) {
+        Some(v) => v,
+        None => {
+            tracing::debug! {"jwt misses required claims"};
+            return None;
+        }
+    };
+    if claims.expires_at < Utc::now() {
+        tracing::debug! {email = %claims.email.0, "jwt expired"};
+        return None;
+    }
+    if !check_device(req, &claims) {
+        tracing::debug! {email = %claims.email.0, "jwt of revoked device"};
+        return None;
+    }
+    Some(claims)
+}
+
+/// Takes the token from the `Authorization: Bearer` header.
+/// Browsers cannot set headers for websockets, so only a websocket upgrade may pass it as
+/// `token` query parameter. Elsewhere it would end up in access logs and the browser history.
+fn get_token<B>(req: &Request<B>) -> Option<String> {
+    if let Some(value) = req.headers().get(header::AUTHORIZATION) {
+        return value
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")
+            .map(str::to_string);
+    }
+
+    let upgrade = req.headers().get(header::UPGRADE)?.to_str().ok()?;
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return None;
+    }
+    req.uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .map(str::to_string)
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for Claims {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        req.extensions()
+            .get::<Claims>()
+            .cloned()
+            .ok_or(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// A scope, which a handler can require through [`RequireScope`].
+pub trait Scope: Send + Sync {
+    const NAME: &'static str;
+}
+
+macro_rules! scope {
+    ($name:ident, $value:literal) => {
+        #[derive(Debug, Clone, Copy)]
+        pub struct $name;
+
+        impl Scope for $name {
+            const NAME: &'static str = $value;
+        }
+    };
+}
+
+scope!(Intgr, "intgr");
+scope!(Screenshare, "screenshare");
+scope!(Sync15, "sync15");
+scope!(Admin, "admin");
+scope!(Mail, "mail");
+scope!(HwcMail, "hwcmail");
+
+/// Extracts the claims, if the token grants the scope `S`.
+/// Fails with UNAUTHORIZED without token and with FORBIDDEN without the scope.
+#[derive(Debug, Clone)]
+pub struct RequireScope<S: Scope>(pub Claims, pub PhantomData<S>);
+
+#[async_trait]
+impl<B: Send, S: Scope> FromRequest<B> for RequireScope<S> {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let claims = Claims::from_request(req).await?;
+        if !claims.has_scope(S::NAME) {
+            tracing::debug! {scope = S::NAME, email = %claims.email.0, "jwt misses required scope"};
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(RequireScope(claims, PhantomData))
+    }
+}
@@ -8,8 +8,12 @@ use storage::UserFile;
 use uuid::Uuid;
 
 /// Create an jwt from userprofile and claims.
-/// It uses HMAC256 for signing.
-pub fn create_jwt_from_userprofile(config: &Config, user: &dyn UserFile) -> String {
+/// It uses HMAC256 for signing. Tokens of paired devices carry the id of the device.
+pub fn create_jwt_from_userprofile(
+    config: &Config,
+    user: &dyn UserFile,
+    device_id: Option<&str>,
+) -> String {
     let mut scopes = vec!["intgr", "screenshare", "hwcmail:-1", "mail:-1"];
 
     if user.using_sync15() {
@@ -33,6 +37,9 @@ pub fn create_jwt_from_userprofile(config: &Config, user: &dyn UserFile) -> Stri
     claims.insert("ExpiresAt", expiration.timestamp().to_string());
     claims.insert("Issuer", "rmCloud WEB".to_string());
     claims.insert("Audience", "web".to_string());
+    if let Some(device_id) = device_id {
+        claims.insert("DeviceID", device_id.to_string());
+    }
 
     //user.to_json().as_bytes()
 
@@ -1,3 +1,14 @@
+mod audit;
+mod auth;
 mod jwt;
+mod rate_limit;
 
+pub use self::audit::{audit, client_ip};
+pub use self::auth::{
+    is_device_active, jwt_auth, Admin, Claims, HwcMail, Intgr, Mail, RequireScope, Scope,
+    Screenshare, Sync15,
+};
 pub use self::jwt::{create_jwt_from_userprofile, verify_and_get_claims};
+pub use self::rate_limit::{
+    account_key, code_request_keys, collect_attempts, ip_key, reserve_attempt, second_factor_key,
+};
@@ -0,0 +1,379 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use std::net::IpAddr;
+use storage::{AttemptStorage, Attempts, EMail, LocalStorageError};
+
+use crate::{metrics, StateAttemptStorage};
+
+/// Failures per account before the first lockout.
+const ACCOUNT_FREE_ATTEMPTS: u32 = 5;
+/// Failures per ip before the first lockout. An ip can try several accounts.
+const IP_FREE_ATTEMPTS: u32 = 20;
+/// The first lockout duration, it doubles with every further failure.
+const LOCKOUT_BASE_SECONDS: i64 = 30;
+const LOCKOUT_MAX_SECONDS: i64 = 24 * 60 * 60;
+/// Failures are forgotten after this time without further failures.
+const RESET_AFTER_SECONDS: i64 = 60 * 60;
+/// Requests for new codes are counted apart from failed logins.
+const CODE_REQUEST_PREFIX: &str = "code-request:";
+/// Wrong second factors are counted apart, so a correct password does not reset them.
+const SECOND_FACTOR_PREFIX: &str = "second-factor:";
+
+pub fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+pub fn account_key(email: &EMail) -> String {
+    format!("account:{}", email.0)
+}
+
+/// Every request of a new code counts against these keys, so nobody can flood a mailbox.
+pub fn code_request_keys(email: &EMail, ip: Option<IpAddr>) -> Vec<String> {
+    let mut keys = vec![format!("{}{}", CODE_REQUEST_PREFIX, account_key(email))];
+    if let Some(ip) = ip {
+        keys.push(format!("{}{}", CODE_REQUEST_PREFIX, ip_key(ip)));
+    }
+    keys
+}
+
+pub fn second_factor_key(email: &EMail) -> String {
+    format!("{}{}", SECOND_FACTOR_PREFIX, account_key(email))
+}
+
+fn free_attempts(key: &str) -> u32 {
+    let key = key
+        .strip_prefix(CODE_REQUEST_PREFIX)
+        .or_else(|| key.strip_prefix(SECOND_FACTOR_PREFIX))
+        .unwrap_or(key);
+    if key.starts_with("ip:") {
+        IP_FREE_ATTEMPTS
+    } else {
+        ACCOUNT_FREE_ATTEMPTS
+    }
+}
+
+fn lockout_duration(failures: u32, free: u32) -> Option<Duration> {
+    let exceeded = failures.checked_sub(free)?;
+    let seconds = LOCKOUT_BASE_SECONDS
+        .checked_mul(1i64.checked_shl(exceeded).unwrap_or(i64::MAX))
+        .unwrap_or(LOCKOUT_MAX_SECONDS)
+        .min(LOCKOUT_MAX_SECONDS);
+    Some(Duration::seconds(seconds))
+}
+
+/// An attempt, which is counted as failed before it is verified. So parallel guesses cannot
+/// all pass the lockout, while the slow verification runs. A success takes it back.
+#[must_use = "the attempt stays counted as failed"]
+pub struct Attempt {
+    storage: StateAttemptStorage,
+    keys: Vec<String>,
+}
+
+/// Checks the lockout of the keys and counts the attempt as failed under one lock.
+/// Returns TOO_MANY_REQUESTS, if one of the keys is locked out at the moment.
+/// Fails closed with SERVICE_UNAVAILABLE, if the attempts cannot be read or stored,
+/// as a broken storage must not lift the lockout.
+pub fn reserve_attempt(
+    storage: &StateAttemptStorage,
+    keys: Vec<String>,
+) -> Result<Attempt, StatusCode> {
+    let locked = storage.write().unwrap();
+    let now = Utc::now();
+    let unavailable = |v: LocalStorageError| {
+        tracing::warn! {?v, "cannot count login attempt"};
+        metrics::storage_error("attempt");
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let mut previous = Vec::with_capacity(keys.len());
+    for key in &keys {
+        let attempts = locked.get_attempts(key).map_err(unavailable)?;
+        if let Some(until) = attempts.as_ref().and_then(|a| a.locked_until) {
+            if until > now {
+                tracing::debug! {%key, %until, "attempt while locked out"};
+                return Err(StatusCode::TOO_MANY_REQUESTS);
+            }
+        }
+        previous.push(attempts);
+    }
+
+    for (key, previous) in keys.iter().zip(previous) {
+        let failures = match previous {
+            Some(a) if !is_forgotten(&a, now) => a.failures.saturating_add(1),
+            _ => 1,
+        };
+
+        let locked_until = lockout_duration(failures, free_attempts(key)).map(|d| now + d);
+        if let Some(until) = locked_until {
+            tracing::warn! {%key, failures, %until, "locked out after too many attempts"};
+        }
+
+        let attempts = Attempts {
+            failures,
+            last_failure: now,
+            locked_until,
+        };
+        locked.store_attempts(key, &attempts).map_err(unavailable)?;
+    }
+
+    Ok(Attempt {
+        storage: storage.clone(),
+        keys,
+    })
+}
+
+impl Attempt {
+    /// Keeps the attempt counted, like dropping it does.
+    pub fn failed(self) {}
+
+    /// Resets the counter of `key` and takes the attempt back from the other keys,
+    /// e.g. the ip, which can still have failures for other accounts.
+    pub fn succeeded(self, key: &str) {
+        let storage = self.storage.write().unwrap();
+        for other in &self.keys {
+            let result = match other == key {
+                true => storage.clear_attempts(key).map(|_| ()),
+                false => take_back(storage.as_ref(), other),
+            };
+            if let Err(v) = result {
+                tracing::warn! {?v, "cannot clear login attempts"};
+                metrics::storage_error("attempt");
+            }
+        }
+    }
+}
+
+fn take_back(storage: &dyn AttemptStorage, key: &str) -> Result<(), LocalStorageError> {
+    let mut attempts = match storage.get_attempts(key)? {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    attempts.failures = attempts.failures.saturating_sub(1);
+    if attempts.failures == 0 {
+        return storage.clear_attempts(key).map(|_| ());
+    }
+    attempts.locked_until =
+        lockout_duration(attempts.failures, free_attempts(key)).map(|d| attempts.last_failure + d);
+    storage.store_attempts(key, &attempts)
+}
+
+/// Removes the attempts, which are forgotten anyway. Returns how many were removed.
+pub fn collect_attempts(storage: &StateAttemptStorage) -> Result<usize, LocalStorageError> {
+    let storage = storage.write().unwrap();
+    let now = Utc::now();
+
+    let mut removed = 0;
+    for (key, attempts) in storage.list_attempts()? {
+        if is_forgotten(&attempts, now) && storage.clear_attempts(&key)? {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn is_forgotten(attempts: &Attempts, now: DateTime<Utc>) -> bool {
+    let locked = attempts.locked_until.map(|u| u > now).unwrap_or(false);
+    !locked && attempts.last_failure < now - Duration::seconds(RESET_AFTER_SECONDS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use std::{
+        sync::{Arc, RwLock},
+        thread,
+        time::Duration as StdDuration,
+    };
+    use storage::AttemptLocalStorage;
+
+    fn storage(dir: &tempfile::TempDir) -> StateAttemptStorage {
+        let config = Config::create(&format!(
+            r#"
+[COMMON]
+LOGLEVEL = "info"
+PORT = 8080
+SOCKET = "{dir}/rmcloud.sock"
+
+[UI]
+URL = "ui.local"
+
+[API]
+SECRET_KEY = "rate-limit-test-secret"
+URL = "api.local"
+DATADIR = "{dir}"
+"#,
+            dir = dir.path().display()
+        ))
+        .unwrap();
+        Arc::new(RwLock::new(AttemptLocalStorage::create(&config).unwrap()))
+    }
+
+    fn failures(storage: &StateAttemptStorage, key: &str) -> Option<u32> {
+        let attempts = storage.read().unwrap().get_attempts(key).unwrap();
+        attempts.map(|a| a.failures)
+    }
+
+    #[test]
+    fn parallel_guesses_are_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir);
+        let email = EMail::create("user@example.com").unwrap();
+
+        let passed = thread::scope(|scope| {
+            let threads: Vec<_> = (0..20)
+                .map(|_| {
+                    scope.spawn(
+                        || match reserve_attempt(&storage, vec![account_key(&email)]) {
+                            Ok(attempt) => {
+                                // the slow verification of a wrong password
+                                thread::sleep(StdDuration::from_millis(50));
+                                attempt.failed();
+                                true
+                            }
+                            Err(v) => {
+                                assert_eq!(v, StatusCode::TOO_MANY_REQUESTS);
+                                false
+                            }
+                        },
+                    )
+                })
+                .collect();
+            threads
+                .into_iter()
+                .map(|v| v.join().unwrap())
+                .filter(|v| *v)
+                .count()
+        });
+        assert_eq!(passed, ACCOUNT_FREE_ATTEMPTS as usize);
+        assert_eq!(failures(&storage, &account_key(&email)), Some(5));
+    }
+
+    #[test]
+    fn success_takes_the_attempt_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir);
+        let email = EMail::create("user@example.com").unwrap();
+        let other = EMail::create("other@example.com").unwrap();
+        let ip = ip_key("127.0.0.1".parse().unwrap());
+
+        reserve_attempt(&storage, vec![account_key(&other), ip.clone()])
+            .unwrap()
+            .failed();
+        let attempt = reserve_attempt(&storage, vec![account_key(&email), ip.clone()]).unwrap();
+        assert_eq!(failures(&storage, &account_key(&email)), Some(1));
+        assert_eq!(failures(&storage, &ip), Some(2));
+
+        attempt.succeeded(&account_key(&email));
+        assert_eq!(failures(&storage, &account_key(&email)), None);
+        assert_eq!(failures(&storage, &ip), Some(1));
+        assert_eq!(failures(&storage, &account_key(&other)), Some(1));
+    }
+
+    #[test]
+    fn taking_back_lifts_the_lockout() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir);
+        let ip = ip_key("127.0.0.1".parse().unwrap());
+
+        let attempts: Vec<Attempt> = (0..IP_FREE_ATTEMPTS)
+            .map(|_| reserve_attempt(&storage, vec![ip.clone()]).unwrap())
+            .collect();
+        assert_eq!(
+            reserve_attempt(&storage, vec![ip.clone()]).err(),
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+
+        for attempt in attempts {
+            attempt.succeeded("account:nobody");
+        }
+        assert_eq!(failures(&storage, &ip), None);
+        assert!(reserve_attempt(&storage, vec![ip]).is_ok());
+    }
+
+    #[test]
+    fn code_requests_do_not_lock_out_logins() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = storage(&dir);
+        let email = EMail::create("user@example.com").unwrap();
+
+        for _ in 0..ACCOUNT_FREE_ATTEMPTS {
+            reserve_attempt(&storage, code_request_keys(&email, None))
+                .unwrap()
+                .failed();
+        }
+        assert!(reserve_attempt(&storage, code_request_keys(&email, None)).is_err());
+        assert!(reserve_attempt(&storage, vec![account_key(&email)]).is_ok());
+    }
+
+    #[test]
+    fn free_attempts_do_not_lock() {
+        assert_eq!(lockout_duration(0, ACCOUNT_FREE_ATTEMPTS), None);
+        assert_eq!(lockout_duration(4, ACCOUNT_FREE_ATTEMPTS), None);
+        assert_eq!(lockout_duration(19, IP_FREE_ATTEMPTS), None);
+    }
+
+    #[test]
+    fn lockout_doubles_with_every_failure() {
+        let free = ACCOUNT_FREE_ATTEMPTS;
+        assert_eq!(lockout_duration(free, free), Some(Duration::seconds(30)));
+        assert_eq!(
+            lockout_duration(free + 1, free),
+            Some(Duration::seconds(60))
+        );
+        assert_eq!(
+            lockout_duration(free + 2, free),
+            Some(Duration::seconds(120))
+        );
+        assert_eq!(
+            lockout_duration(free + 10, free),
+            Some(Duration::seconds(30 * 1024))
+        );
+    }
+
+    #[test]
+    fn lockout_is_capped_without_overflow() {
+        let max = Some(Duration::seconds(LOCKOUT_MAX_SECONDS));
+        let free = ACCOUNT_FREE_ATTEMPTS;
+        assert_eq!(lockout_duration(free + 12, free), max);
+        assert_eq!(lockout_duration(free + 62, free), max);
+        assert_eq!(lockout_duration(free + 63, free), max);
+        assert_eq!(lockout_duration(u32::MAX, free), max);
+    }
+
+    #[test]
+    fn prefixed_keys_count_like_their_key() {
+        let email = EMail::create("user@example.com").unwrap();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        assert_eq!(free_attempts(&account_key(&email)), ACCOUNT_FREE_ATTEMPTS);
+        assert_eq!(free_attempts(&ip_key(ip)), IP_FREE_ATTEMPTS);
+        assert_eq!(
+            free_attempts(&second_factor_key(&email)),
+            ACCOUNT_FREE_ATTEMPTS
+        );
+
+        let keys = code_request_keys(&email, Some(ip));
+        assert_eq!(free_attempts(&keys[0]), ACCOUNT_FREE_ATTEMPTS);
+        assert_eq!(free_attempts(&keys[1]), IP_FREE_ATTEMPTS);
+    }
+
+    #[test]
+    fn failures_are_forgotten_after_the_lockout() {
+        let now = Utc::now();
+        let attempts = |last_failure: i64, locked_until: Option<i64>| Attempts {
+            failures: 10,
+            last_failure: now - Duration::seconds(last_failure),
+            locked_until: locked_until.map(|v| now + Duration::seconds(v)),
+        };
+        assert!(!is_forgotten(&attempts(60, None), now));
+        assert!(is_forgotten(&attempts(RESET_AFTER_SECONDS + 1, None), now));
+        assert!(!is_forgotten(
+            &attempts(RESET_AFTER_SECONDS + 1, Some(60)),
+            now
+        ));
+        assert!(is_forgotten(
+            &attempts(RESET_AFTER_SECONDS + 1, Some(-60)),
+            now
+        ));
+    }
+}
@@ -1,35 +1,99 @@
 use config::Config;
-use std::sync::{Arc, RwLock};
-use storage::{CodeStorage, UserStorage};
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+use storage::{AttemptStorage, AuditStorage, CodeStorage, LocalStorageError, UserStorage};
+use tracing_subscriber::{reload::Handle, EnvFilter, Registry};
 
 mod api;
 mod axum_server;
 mod cli_socket;
 mod gracefully_exit;
 mod helper;
+mod listener;
+mod mail;
+mod metrics;
+mod reload;
+mod tls;
 mod ui;
+mod webhook;
+
+pub use helper::{Admin, Claims, HwcMail, Intgr, Mail, RequireScope, Scope, Screenshare, Sync15};
+pub use reload::StateConfig;
+pub use webhook::{sign, StateWebhooks, WebhookEvent, Webhooks, SIGNATURE_HEADER};
 
 // taken from https://github.com/tokio-rs/axum/blob/main/examples/error-handling-and-dependency-injection/src/main.rs
 pub type StateUserStorage = Arc<RwLock<Box<dyn UserStorage>>>;
 pub type StateCodeStorage = Arc<RwLock<Box<dyn CodeStorage>>>;
+pub type StateAttemptStorage = Arc<RwLock<Box<dyn AttemptStorage>>>;
+pub type StateAuditStorage = Arc<RwLock<Box<dyn AuditStorage>>>;
+
+/// Changes the log level of the running server, when LOGLEVEL is reloaded.
+pub type LogFilter = Handle<EnvFilter, Registry>;
 
 #[tokio::main]
 pub async fn run(
+    config_path: PathBuf,
     config: Config,
+    log_filter: Option<LogFilter>,
     user_storage: Box<dyn UserStorage>,
     code_storage: Box<dyn CodeStorage>,
+    attempt_storage: Box<dyn AttemptStorage>,
+    audit_storage: Box<dyn AuditStorage>,
 ) -> std::io::Result<()> {
-    let config = Arc::new(config);
+    let reloader = reload::Reloader::new(config_path, config, log_filter)
+        .map_err(|v| std::io::Error::new(std::io::ErrorKind::InvalidInput, v.describe()))?;
+    let reloader = Arc::new(reloader);
+    let hangup = reload::watch_hangup(reloader.clone());
 
     let user_storage = Arc::new(RwLock::new(user_storage)) as StateUserStorage;
     let code_storage = Arc::new(RwLock::new(code_storage)) as StateCodeStorage;
+    let attempt_storage = Arc::new(RwLock::new(attempt_storage)) as StateAttemptStorage;
+    let audit_storage = Arc::new(RwLock::new(audit_storage)) as StateAuditStorage;
+
+    // shared with the cli socket, so it can list and collect them
+    let ui_sessions: ui::StateUiSessions = Arc::new(RwLock::new(ui::UiSessions::default()));
+    let screenshare_sessions: api::StateScreenshareSessions =
+        Arc::new(RwLock::new(api::ScreenshareSessions::default()));
 
-    let (socket_rx, axum_rx) = gracefully_exit::create_receivers();
+    let shutdown = gracefully_exit::create_receiver();
 
-    let handle = cli_socket::run_cli_socket(config.clone(), socket_rx).await;
-    axum_server::run_server(config, axum_rx, user_storage, code_storage).await;
+    let control = cli_socket::Control {
+        user_storage: user_storage.clone(),
+        code_storage: code_storage.clone(),
+        attempt_storage: attempt_storage.clone(),
+        ui_sessions: ui_sessions.clone(),
+        screenshare_sessions: screenshare_sessions.clone(),
+        reloader: reloader.clone(),
+        started_at: chrono::Utc::now(),
+    };
+    let handle = cli_socket::run_cli_socket(reloader.current(), control, shutdown.clone()).await;
+    axum_server::run_server(
+        reloader,
+        shutdown,
+        user_storage.clone(),
+        code_storage.clone(),
+        attempt_storage.clone(),
+        audit_storage.clone(),
+        ui_sessions,
+        screenshare_sessions,
+    )
+    .await;
     handle.await.expect("Cannot join cli socket");
+    hangup.abort();
+
+    flush("user", user_storage.read().unwrap().flush());
+    flush("code", code_storage.read().unwrap().flush());
+    flush("attempt", attempt_storage.read().unwrap().flush());
+    flush("audit", audit_storage.read().unwrap().flush());
 
     println!("Everything is closed gracefully. Bye.");
     Ok(())
 }
+
+fn flush(storage: &str, result: Result<(), LocalStorageError>) {
+    if let Err(v) = result {
+        tracing::error! {?v, storage, "cannot flush storage"};
+    }
+}
@@ -0,0 +1,122 @@
+use config::{Config, SmtpTls};
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
+
+pub type StateMailer = Arc<Mailer>;
+
+/// Mail templates start with a `Subject:` line, followed by an empty line and the body.
+/// `{{name}}` is replaced with the value of the same name.
+pub const CODE_FORGOTTEN_TEMPLATE: &str = include_str!("../templates/code_forgotten.txt");
+
+#[derive(Error, Debug)]
+pub enum MailError {
+    #[error("No SMTP server is configured")]
+    NotConfigured,
+    #[error("Mail template has no subject line")]
+    TemplateWithoutSubject,
+    #[error("Mail address is not valid")]
+    AddressError(#[from] lettre::address::AddressError),
+    #[error("Mail cannot be built")]
+    MessageError(#[from] lettre::error::Error),
+    #[error("SMTP server had an error")]
+    SmtpError(#[from] lettre::transport::smtp::Error),
+}
+
+type Transport = (AsyncSmtpTransport<Tokio1Executor>, Mailbox);
+
+/// Sends mails through the SMTP server of the config, if there is one.
+pub struct Mailer {
+    transport: RwLock<Option<Transport>>,
+}
+
+impl Mailer {
+    pub fn new(config: &Config) -> Result<Self, MailError> {
+        Ok(Self {
+            transport: RwLock::new(transport(config)?),
+        })
+    }
+
+    /// Sends with the SMTP server of the other mailer from now on, e.g. after a config reload.
+    pub fn replace(&self, other: Mailer) {
+        *self.transport.write().unwrap() = other.transport.into_inner().unwrap();
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.transport.read().unwrap().is_some()
+    }
+
+    /// Renders the template with the values and sends it to the given address.
+    pub async fn send(
+        &self,
+        to: &str,
+        template: &str,
+        values: &[(&str, &str)],
+    ) -> Result<(), MailError> {
+        let (transport, from) = self
+            .transport
+            .read()
+            .unwrap()
+            .clone()
+            .ok_or(MailError::NotConfigured)?;
+        let (subject, body) = render(template, values)?;
+
+        let message = Message::builder()
+            .from(from)
+            .to(to.parse()?)
+            .subject(subject)
+            .body(body)?;
+
+        transport.send(message).await?;
+        tracing::debug! {%to, "mail sent"};
+        Ok(())
+    }
+}
+
+fn transport(config: &Config) -> Result<Option<Transport>, MailError> {
+    let smtp = match &config.api.smtp {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let (host, port) = match smtp.server.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().ok()),
+        None => (smtp.server.as_str(), None),
+    };
+    let mut builder = match smtp.tls {
+        SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+        SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+    };
+    if let Some(port) = port {
+        builder = builder.port(port);
+    }
+    if !smtp.username.is_empty() {
+        builder = builder.credentials(Credentials::new(
+            smtp.username.clone(),
+            smtp.password.clone(),
+        ));
+    }
+
+    Ok(Some((builder.build(), smtp.from.parse()?)))
+}
+
+fn render(template: &str, values: &[(&str, &str)]) -> Result<(String, String), MailError> {
+    let rendered = values
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{{{}}}}}", name), value)
+        });
+
+    let (subject, body) = rendered
+        .split_once("\n\n")
+        .ok_or(MailError::TemplateWithoutSubject)?;
+    let subject = subject
+        .strip_prefix("Subject:")
+        .ok_or(MailError::TemplateWithoutSubject)?;
+
+    Ok((subject.trim().to_string(), body.to_string()))
+}
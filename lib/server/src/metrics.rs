@@ -0,0 +1,225 @@
+use axum::{
+    body::HttpBody,
+    extract::MatchedPath,
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use std::time::Instant;
+
+pub const HTTP: &str = "http";
+pub const WEBSOCKET: &str = "websocket";
+pub const UPLOAD: &str = "up";
+pub const DOWNLOAD: &str = "down";
+
+static METRICS: Lazy<Metrics> = Lazy::new(Metrics::new);
+
+/// All metrics of the server, exported in the prometheus text format on the admin listener.
+struct Metrics {
+    registry: Registry,
+    request_duration: HistogramVec,
+    active_websockets: IntGauge,
+    sync_operations: IntCounterVec,
+    transferred_bytes: IntCounterVec,
+    storage_errors: IntCounterVec,
+    login_failures: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("rmcloud".to_string()), None)
+            .expect("Cannot create metrics registry.");
+
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Latency of http requests by route and status",
+            ),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let active_websockets =
+            IntGauge::new("active_websockets", "Open websocket connections").unwrap();
+        let sync_operations = IntCounterVec::new(
+            Opts::new("sync_operations_total", "Sync operations of devices"),
+            &["operation"],
+        )
+        .unwrap();
+        let transferred_bytes = IntCounterVec::new(
+            Opts::new(
+                "transferred_bytes_total",
+                "Bytes uploaded and downloaded by protocol",
+            ),
+            &["protocol", "direction"],
+        )
+        .unwrap();
+        let storage_errors = IntCounterVec::new(
+            Opts::new("storage_errors_total", "Failed storage operations"),
+            &["storage"],
+        )
+        .unwrap();
+        let login_failures = IntCounterVec::new(
+            Opts::new("login_failures_total", "Failed logins by method"),
+            &["method"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(request_duration.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(active_websockets.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(sync_operations.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(transferred_bytes.clone()))
+            .unwrap();
+        registry.register(Box::new(storage_errors.clone())).unwrap();
+        registry.register(Box::new(login_failures.clone())).unwrap();
+
+        Self {
+            registry,
+            request_duration,
+            active_websockets,
+            sync_operations,
+            transferred_bytes,
+            storage_errors,
+            login_failures,
+        }
+    }
+}
+
+/// Counts a sync operation of a device, like "pair" or "refresh".
+pub fn sync_operation(operation: &str) {
+    METRICS
+        .sync_operations
+        .with_label_values(&[operation])
+        .inc();
+}
+
+/// Counts transferred bytes, the protocol is `HTTP` or `WEBSOCKET` and the direction `UPLOAD` or `DOWNLOAD`.
+pub fn transferred(protocol: &str, direction: &str, bytes: u64) {
+    METRICS
+        .transferred_bytes
+        .with_label_values(&[protocol, direction])
+        .inc_by(bytes);
+}
+
+/// Counts a failed operation of the "user", "code", "attempt" or "audit" storage.
+pub fn storage_error(storage: &str) {
+    METRICS.storage_errors.with_label_values(&[storage]).inc();
+}
+
+/// Counts a failed login, the method is "code", "password", "second_factor", "token" or "oidc".
+pub fn login_failure(method: &str) {
+    METRICS.login_failures.with_label_values(&[method]).inc();
+}
+
+/// Counts an open websocket, until it is dropped.
+pub struct WebsocketGuard(());
+
+impl WebsocketGuard {
+    pub fn open() -> Self {
+        METRICS.active_websockets.inc();
+        Self(())
+    }
+}
+
+impl Drop for WebsocketGuard {
+    fn drop(&mut self) {
+        METRICS.active_websockets.dec();
+    }
+}
+
+/// Measures latency and size of requests. It is a route layer, so the route is known
+/// and unmatched paths do not create a label each.
+pub async fn track<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let start = Instant::now();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|v| v.as_str().to_string())
+        .unwrap_or_default();
+    let method = method_label(req.method());
+    let uploaded = content_length(req.headers()).unwrap_or(0);
+
+    let response = next.run(req).await;
+
+    let downloaded = content_length(response.headers())
+        .or_else(|| response.body().size_hint().exact())
+        .unwrap_or(0);
+    METRICS
+        .request_duration
+        .with_label_values(&[&route, method, response.status().as_str()])
+        .observe(start.elapsed().as_secs_f64());
+    transferred(HTTP, UPLOAD, uploaded);
+    transferred(HTTP, DOWNLOAD, downloaded);
+    response
+}
+
+/// Extension methods are any token, so they share one label instead of creating one each.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        _ => "other",
+    }
+}
+
+fn content_length(headers: &axum::http::HeaderMap) -> Option<u64> {
+    headers
+        .get(header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+async fn metrics_handler() -> Result<impl IntoResponse, StatusCode> {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&METRICS.registry.gather(), &mut buffer)
+        .map_err(|v| {
+            tracing::warn! {?v, "cannot encode metrics"};
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok((
+        [(header::CONTENT_TYPE, encoder.format_type().to_string())],
+        buffer,
+    ))
+}
+
+/// The router of the admin listener
+pub fn get_router() -> Router {
+    Router::new().route("/metrics", get(metrics_handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_methods_share_a_label() {
+        assert_eq!(method_label(&Method::PATCH), "PATCH");
+        assert_eq!(method_label(&Method::TRACE), "other");
+        assert_eq!(
+            method_label(&Method::from_bytes(b"PROPFIND").unwrap()),
+            "other"
+        );
+        assert_eq!(method_label(&Method::from_bytes(b"X1").unwrap()), "other");
+    }
+}
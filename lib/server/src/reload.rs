@@ -0,0 +1,169 @@
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    mail::{MailError, Mailer, StateMailer},
+    ui::StateOidcLogin,
+    LogFilter,
+};
+use axum::{http::Request, middleware::Next, response::Response};
+use config::{Config, ConfigError, Layered, Tls};
+use thiserror::Error;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+};
+use tracing_subscriber::EnvFilter;
+
+/// The config, which is replaced as a whole on a reload.
+pub type StateConfig = Arc<RwLock<Arc<Config>>>;
+
+#[derive(Error, Debug)]
+pub enum ReloadError {
+    #[error("config cannot be read")]
+    ConfigError(#[from] ConfigError),
+    #[error("mailer cannot be created")]
+    MailError(#[from] MailError),
+    #[error("LOGLEVEL is not valid")]
+    LogLevelNotValid(#[from] tracing_subscriber::filter::ParseError),
+}
+
+impl ReloadError {
+    /// The error with all of its causes, as the first line alone rarely tells what is wrong.
+    pub fn describe(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = self.source();
+        while let Some(v) = source {
+            message.push_str(&format!(": {}", v));
+            source = v.source();
+        }
+        message
+    }
+}
+
+/// Reads the config file again and swaps everything, which is built from it.
+pub struct Reloader {
+    path: PathBuf,
+    /// the listeners, the cli socket and the storages keep using this one
+    started: Arc<Config>,
+    pub config: StateConfig,
+    pub mailer: StateMailer,
+    pub oidc_login: StateOidcLogin,
+    log_filter: Option<LogFilter>,
+}
+
+impl Reloader {
+    pub fn new(
+        path: PathBuf,
+        config: Config,
+        log_filter: Option<LogFilter>,
+    ) -> Result<Self, ReloadError> {
+        let mailer = Mailer::new(&config)?;
+        let oidc_login = crate::ui::OidcLogin::new(&config);
+        if let Some(filter) = &log_filter {
+            match EnvFilter::try_new(&config.common.loglevel) {
+                Ok(v) => set_log_filter(filter, v),
+                Err(v) => tracing::warn! {?v, "LOGLEVEL is not valid, keep the default"},
+            }
+        }
+
+        let config = Arc::new(config);
+        Ok(Self {
+            path,
+            started: config.clone(),
+            config: Arc::new(RwLock::new(config)),
+            mailer: Arc::new(mailer),
+            oidc_login: Arc::new(oidc_login),
+            log_filter,
+        })
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Validates the new config before anything is swapped, so a broken file changes nothing.
+    /// Returns the settings, which differ from the running ones and need a restart.
+    pub async fn reload(&self) -> Result<Vec<&'static str>, ReloadError> {
+        let layered = Layered::load(&self.path)?;
+        for v in &layered.warnings {
+            tracing::warn! {%v, "config warning"};
+        }
+        let config = layered.config;
+        let mailer = Mailer::new(&config)?;
+        let filter = EnvFilter::try_new(&config.common.loglevel)?;
+
+        let restart_required = restart_required(&self.started, &config);
+        let config = Arc::new(config);
+        *self.config.write().unwrap() = config.clone();
+        self.mailer.replace(mailer);
+        self.oidc_login.reload(&config).await;
+        if let Some(v) = &self.log_filter {
+            set_log_filter(v, filter);
+        }
+
+        tracing::info! {?restart_required, "config reloaded"};
+        Ok(restart_required)
+    }
+}
+
+fn set_log_filter(log_filter: &LogFilter, filter: EnvFilter) {
+    if let Err(v) = log_filter.reload(filter) {
+        tracing::warn! {?v, "cannot change the log level"};
+    }
+}
+
+/// The settings, which the listeners, the cli socket and the storages only read on startup.
+fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    fn tls(config: &Config) -> Option<&Tls> {
+        config.common.tls.as_ref()
+    }
+    let changes = [
+        ("COMMON.PORT", old.common.port != new.common.port),
+        ("COMMON.BIND", old.common.bind != new.common.bind),
+        ("COMMON.API_PORT", old.common.api_port != new.common.api_port),
+        ("COMMON.UI_PORT", old.common.ui_port != new.common.ui_port),
+        ("COMMON.ADMIN_PORT", old.common.admin_port != new.common.admin_port),
+        ("COMMON.ADMIN_BIND", old.common.admin_bind != new.common.admin_bind),
+        ("COMMON.SOCKET", old.common.socket != new.common.socket),
+        ("COMMON.TLS_CERT", tls(old).map(|v| &v.cert) != tls(new).map(|v| &v.cert)),
+        ("COMMON.TLS_KEY", tls(old).map(|v| &v.key) != tls(new).map(|v| &v.key)),
+        ("COMMON.TLS_GENERATE", tls(old).map(|v| v.generate) != tls(new).map(|v| v.generate)),
+        ("API.DATADIR", old.api.data_dir != new.api.data_dir),
+        ("API.CODES", old.api.codes != new.api.codes),
+        ("STORAGE", old.storage != new.storage),
+    ];
+    changes
+        .into_iter()
+        .filter(|(_, changed)| *changed)
+        .map(|(key, _)| key)
+        .collect()
+}
+
+/// Puts the current config into the request, so a reload does not change it halfway through.
+pub async fn current_config<B>(mut req: Request<B>, next: Next<B>) -> Response {
+    let config = req
+        .extensions()
+        .get::<StateConfig>()
+        .map(|v| v.read().unwrap().clone());
+    if let Some(config) = config {
+        req.extensions_mut().insert(config);
+    }
+    next.run(req).await
+}
+
+/// Reloads the config on every SIGHUP.
+pub fn watch_hangup(reloader: Arc<Reloader>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut hangup = signal(SignalKind::hangup()).expect("Cannot listen for SIGHUP.");
+        while hangup.recv().await.is_some() {
+            tracing::info! {"got SIGHUP, reload config"};
+            if let Err(v) = reloader.reload().await {
+                tracing::error! {error = %v.describe(), "cannot reload config, keep the old one"};
+            }
+        }
+    })
+}
@@ -0,0 +1,368 @@
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{Datelike, Duration, Utc};
+use config::{Config, Tls};
+use rcgen::{
+    date_time_ymd, BasicConstraints, Certificate, CertificateParams, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SanType,
+};
+use std::{
+    fs::{create_dir_all, read_to_string, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+/// The local CA is stored next to the certificate, it has to be installed on the tablet.
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca.key";
+const CA_VALID_DAYS: i64 = 10 * 365;
+const HOST_VALID_DAYS: i64 = 825;
+/// A generated host certificate is renewed on startup, when it expires within these days.
+const HOST_RENEW_DAYS: i64 = 30;
+/// How often the files are checked for changes.
+const WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("cannot read or write tls files")]
+    IoError(#[from] io::Error),
+    #[error("cannot generate certificate")]
+    CertificateError(#[from] rcgen::RcgenError),
+}
+
+/// Loads the certificate and key. Generates them first, if wanted and missing or outdated.
+pub async fn load(config: &Config, tls: &Tls) -> Result<RustlsConfig, TlsError> {
+    if tls.generate && outdated(config, tls) {
+        generate(config, tls)?;
+    }
+    let (cert, key) = read_pem(tls)?;
+    Ok(RustlsConfig::from_pem(cert, key).await?)
+}
+
+/// Reads the certificate and key. Fails for files without a certificate,
+/// which rustls would accept and fail on every handshake.
+fn read_pem(tls: &Tls) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let cert = std::fs::read(&tls.cert)?;
+    let key = std::fs::read(&tls.key)?;
+    if rustls_pemfile::certs(&mut cert.as_slice())?.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificate found in {}", tls.cert.display()),
+        ));
+    }
+    Ok((cert, key))
+}
+
+/// Reloads the certificate and key, when one of the files changes.
+/// A failed reload keeps the old certificate, so a half written file does not stop the server.
+pub fn watch(rustls: RustlsConfig, tls: Tls) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut last = modified(&tls);
+        loop {
+            tokio::time::sleep(WATCH_INTERVAL).await;
+            let current = modified(&tls);
+            if current == last {
+                continue;
+            }
+            last = current;
+
+            let reload = match read_pem(&tls) {
+                Ok((cert, key)) => rustls.reload_from_pem(cert, key).await,
+                Err(v) => Err(v),
+            };
+            match reload {
+                Ok(_) => tracing::info! {cert = ?tls.cert, "tls certificate reloaded"},
+                Err(v) => tracing::warn! {?v, "cannot reload tls certificate, keep the old one"},
+            }
+        }
+    })
+}
+
+fn modified(tls: &Tls) -> (Option<SystemTime>, Option<SystemTime>) {
+    let time = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    (time(&tls.cert), time(&tls.key))
+}
+
+/// The url can carry a port, like `localhost:8080` or `[::1]:8080`.
+fn hostname(url: &str) -> &str {
+    match url.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or(rest),
+        None => url.split(':').next().unwrap_or(url),
+    }
+}
+
+/// Whether the host certificate is missing, expires soon or is not for API.URL and UI.URL.
+fn outdated(config: &Config, tls: &Tls) -> bool {
+    if !(tls.cert.exists() && tls.key.exists()) {
+        return true;
+    }
+    let params = match read_params(tls) {
+        Ok(v) => v,
+        Err(v) => {
+            tracing::warn! {?v, cert = ?tls.cert, "cannot read tls certificate, generate a new one"};
+            return true;
+        }
+    };
+
+    let renew_at = Utc::now() + Duration::days(HOST_RENEW_DAYS);
+    if params.not_after.unix_timestamp() <= renew_at.timestamp() {
+        tracing::info! {cert = ?tls.cert, "tls certificate expires, generate a new one"};
+        return true;
+    }
+    if params.subject_alt_names != subject_alt_names(&host_names(config)) {
+        tracing::info! {cert = ?tls.cert, "tls certificate is for other urls, generate a new one"};
+        return true;
+    }
+    false
+}
+
+fn read_params(tls: &Tls) -> Result<CertificateParams, TlsError> {
+    let key = KeyPair::from_pem(&read_to_string(&tls.key)?)?;
+    Ok(CertificateParams::from_ca_cert_pem(
+        &read_to_string(&tls.cert)?,
+        key,
+    )?)
+}
+
+fn host_names(config: &Config) -> Vec<&str> {
+    let mut names = vec![hostname(&config.api.url), hostname(&config.ui.url)];
+    names.dedup();
+    names
+}
+
+fn subject_alt_names(names: &[&str]) -> Vec<SanType> {
+    names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.to_string()),
+        })
+        .collect()
+}
+
+fn validity(params: &mut CertificateParams, days: i64) {
+    let from = Utc::now() - Duration::days(1);
+    let until = from + Duration::days(days);
+    params.not_before = date_time_ymd(from.year(), from.month() as u8, from.day() as u8);
+    params.not_after = date_time_ymd(until.year(), until.month() as u8, until.day() as u8);
+}
+
+/// Creates a host certificate for API.URL and UI.URL, signed by the local CA.
+fn generate(config: &Config, tls: &Tls) -> Result<(), TlsError> {
+    let dir = tls
+        .cert
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."));
+    create_dir_all(&dir)?;
+    if let Some(parent) = tls.key.parent() {
+        create_dir_all(parent)?;
+    }
+    let ca = load_or_create_ca(&dir)?;
+
+    let names = host_names(config);
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, names[0]);
+    params.subject_alt_names = subject_alt_names(&names);
+    params.key_usages = vec![
+        KeyUsagePurpose::DigitalSignature,
+        KeyUsagePurpose::KeyEncipherment,
+    ];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+    params.use_authority_key_identifier_extension = true;
+    validity(&mut params, HOST_VALID_DAYS);
+
+    let cert = Certificate::from_params(params)?;
+    write_file(&tls.cert, &cert.serialize_pem_with_signer(&ca)?, false)?;
+    write_file(&tls.key, &cert.serialize_private_key_pem(), true)?;
+
+    tracing::info! {
+        ?names,
+        ca = ?dir.join(CA_CERT_FILE),
+        "generated tls certificate, install the ca on the tablet"
+    };
+    Ok(())
+}
+
+/// Keeps an existing CA, so the tablet does not need a new one for every host certificate.
+fn load_or_create_ca(dir: &Path) -> Result<Certificate, TlsError> {
+    let cert_file = dir.join(CA_CERT_FILE);
+    let key_file = dir.join(CA_KEY_FILE);
+
+    if cert_file.exists() && key_file.exists() {
+        let key = KeyPair::from_pem(&read_to_string(&key_file)?)?;
+        let params = CertificateParams::from_ca_cert_pem(&read_to_string(&cert_file)?, key)?;
+        return Ok(Certificate::from_params(params)?);
+    }
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "rmcloud local CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    validity(&mut params, CA_VALID_DAYS);
+
+    let ca = Certificate::from_params(params)?;
+    write_file(&cert_file, &ca.serialize_pem()?, false)?;
+    write_file(&key_file, &ca.serialize_private_key_pem(), true)?;
+    tracing::info! {file = ?cert_file, "generated local ca"};
+    Ok(ca)
+}
+
+/// Private keys are only readable by the owner, also when the file existed before.
+fn write_file(path: &Path, contents: &str, private: bool) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn config(dir: &tempfile::TempDir, ui_url: &str) -> Config {
+        Config::create(&format!(
+            r#"
+[COMMON]
+LOGLEVEL = "info"
+PORT = 8080
+SOCKET = "{dir}/rmcloud.sock"
+
+[UI]
+URL = "{ui_url}"
+
+[API]
+SECRET_KEY = "tls-test-secret"
+URL = "api.local:8080"
+DATADIR = "{dir}"
+"#,
+            dir = dir.path().display(),
+            ui_url = ui_url,
+        ))
+        .unwrap()
+    }
+
+    fn tls(dir: &tempfile::TempDir) -> Tls {
+        Tls {
+            cert: dir.path().join("tls/cert.pem"),
+            key: dir.path().join("tls/key.pem"),
+            generate: true,
+        }
+    }
+
+    fn mode(path: &Path) -> u32 {
+        path.metadata().unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn hostname_without_port() {
+        assert_eq!(hostname("localhost:8080"), "localhost");
+        assert_eq!(hostname("rmcloud.example.com"), "rmcloud.example.com");
+        assert_eq!(hostname("[::1]:8080"), "::1");
+        assert_eq!(hostname("[::1]"), "::1");
+        assert_eq!(hostname("127.0.0.1:8443"), "127.0.0.1");
+    }
+
+    #[test]
+    fn generated_for_both_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir, "127.0.0.1");
+        let tls = tls(&dir);
+        assert!(outdated(&config, &tls));
+
+        generate(&config, &tls).unwrap();
+        let params = read_params(&tls).unwrap();
+        assert_eq!(
+            params.subject_alt_names,
+            vec![
+                SanType::DnsName("api.local".to_string()),
+                SanType::IpAddress("127.0.0.1".parse().unwrap()),
+            ]
+        );
+        assert!(!outdated(&config, &tls));
+        assert_eq!(mode(&tls.key), 0o600);
+        assert_eq!(mode(&dir.path().join("tls").join(CA_KEY_FILE)), 0o600);
+    }
+
+    #[test]
+    fn outdated_for_other_urls() {
+        let dir = tempfile::tempdir().unwrap();
+        let tls = tls(&dir);
+        generate(&config(&dir, "ui.local"), &tls).unwrap();
+
+        assert!(!outdated(&config(&dir, "ui.local"), &tls));
+        assert!(outdated(&config(&dir, "admin.local"), &tls));
+    }
+
+    #[test]
+    fn outdated_when_expiring() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = config(&dir, "ui.local");
+        let tls = tls(&dir);
+        create_dir_all(dir.path().join("tls")).unwrap();
+
+        let mut params =
+            CertificateParams::new(vec!["api.local".to_string(), "ui.local".to_string()]);
+        validity(&mut params, HOST_RENEW_DAYS - 1);
+        let cert = Certificate::from_params(params).unwrap();
+        write_file(&tls.cert, &cert.serialize_pem().unwrap(), false).unwrap();
+        write_file(&tls.key, &cert.serialize_private_key_pem(), true).unwrap();
+
+        assert!(outdated(&config, &tls));
+    }
+
+    #[test]
+    fn private_files_get_restricted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key.pem");
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_file(&path, "new", true).unwrap();
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+    }
+
+    #[tokio::test]
+    async fn load_renews_the_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let tls = tls(&dir);
+        load(&config(&dir, "ui.local"), &tls).await.unwrap();
+        let first = std::fs::read(&tls.cert).unwrap();
+        let ca = std::fs::read(dir.path().join("tls").join(CA_CERT_FILE)).unwrap();
+
+        load(&config(&dir, "ui.local"), &tls).await.unwrap();
+        assert_eq!(std::fs::read(&tls.cert).unwrap(), first);
+
+        load(&config(&dir, "admin.local"), &tls).await.unwrap();
+        assert_ne!(std::fs::read(&tls.cert).unwrap(), first);
+        // the tablet keeps trusting the same ca
+        assert_eq!(
+            std::fs::read(dir.path().join("tls").join(CA_CERT_FILE)).unwrap(),
+            ca
+        );
+    }
+}
@@ -0,0 +1,590 @@
+use crate::{
+    helper::{account_key, audit, client_ip, create_jwt_from_userprofile, ip_key, reserve_attempt},
+    metrics, StateAttemptStorage, StateAuditStorage, StateUserStorage,
+};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequest, Path, RequestParts, TypedHeader},
+    headers::{Cookie, UserAgent},
+    http::{header, Method, StatusCode},
+    response::{AppendHeaders, IntoResponse},
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Duration, Utc};
+use config::Config;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+};
+use storage::{AuditAction, AuditEvent, AuditSource, EMail};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+pub const SESSION_COOKIE: &str = "rmcloud_session";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+const TOKEN_SIZE: usize = 48;
+/// Sessions without any request in this time are removed.
+pub const SESSION_IDLE_HOURS: i64 = 12;
+
+pub type StateUiSessions = Arc<RwLock<UiSessions>>;
+
+/// A logged in browser session of the admin UI.
+#[derive(Debug, Clone)]
+pub struct UiSession {
+    /// the secret, which is stored in the http-only cookie
+    pub(super) id: String,
+    /// the public id to list and revoke the session, without knowing the secret
+    handle: String,
+    pub email: EMail,
+    pub is_admin: bool,
+    /// admins have to pass the second factor, before the session can be used
+    pub(super) second_factor_pending: bool,
+    csrf_token: String,
+    created_at: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+/// A session of an account with `is_admin`. Extracting it fails with FORBIDDEN for other users.
+#[derive(Debug, Clone)]
+pub struct AdminUiSession(pub UiSession);
+
+/// A session, which may still wait for the second factor.
+/// Only the login steps use it, `UiSession` fails with FORBIDDEN for such sessions.
+#[derive(Debug, Clone)]
+pub struct PendingUiSession(pub UiSession);
+
+/// Holds all logged in sessions of the admin UI, indexed by their cookie value.
+#[derive(Debug, Default)]
+pub struct UiSessions {
+    sessions: BTreeMap<String, UiSession>,
+}
+
+impl UiSessions {
+    pub fn create(
+        &mut self,
+        email: EMail,
+        is_admin: bool,
+        ip: Option<String>,
+        user_agent: Option<String>,
+    ) -> UiSession {
+        let now = Utc::now();
+        let session = UiSession {
+            id: generate_token(),
+            handle: Uuid::new_v4().to_string(),
+            email,
+            is_admin,
+            second_factor_pending: is_admin,
+            csrf_token: generate_token(),
+            created_at: now,
+            last_seen: now,
+            ip,
+            user_agent,
+        };
+        self.sessions.insert(session.id.clone(), session.clone());
+        session
+    }
+
+    /// Returns the session for the cookie value and marks it as seen.
+    fn touch(&mut self, id: &str) -> Option<UiSession> {
+        self.remove_expired();
+        let session = self.sessions.get_mut(id)?;
+        session.last_seen = Utc::now();
+        Some(session.clone())
+    }
+
+    /// Marks the second factor of the session as passed.
+    pub(super) fn complete_second_factor(&mut self, id: &str) {
+        if let Some(session) = self.sessions.get_mut(id) {
+            session.second_factor_pending = false;
+        }
+    }
+
+    /// Removes the sessions, which were idle for too long. Returns how many were removed.
+    pub fn remove_expired(&mut self) -> usize {
+        let deadline = Utc::now() - Duration::hours(SESSION_IDLE_HOURS);
+        let before = self.sessions.len();
+        self.sessions.retain(|_, s| s.last_seen > deadline);
+        before - self.sessions.len()
+    }
+
+    pub fn count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// All sessions for the cli, without their secrets.
+    pub fn list(&self) -> Vec<control::SessionInfo> {
+        self.sessions
+            .values()
+            .map(|s| control::SessionInfo {
+                handle: s.handle.clone(),
+                email: s.email.0.clone(),
+                created_at: s.created_at,
+                last_seen: s.last_seen,
+                ip: s.ip.clone(),
+                user_agent: s.user_agent.clone(),
+            })
+            .collect()
+    }
+
+    fn remove_by_handle(&mut self, handle: &str) -> Option<UiSession> {
+        let id = self
+            .sessions
+            .values()
+            .find(|s| s.handle == handle)?
+            .id
+            .clone();
+        self.sessions.remove(&id)
+    }
+}
+
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_SIZE)
+        .map(char::from)
+        .collect()
+}
+
+/// With TLS, the browser must never send the cookie over plain http.
+pub fn session_cookie(value: &str, max_age: i64, config: &Config) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+        SESSION_COOKIE,
+        value,
+        max_age,
+        secure(config)
+    )
+}
+
+pub fn secure(config: &Config) -> &'static str {
+    match config.common.tls {
+        Some(_) => "; Secure",
+        None => "",
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for PendingUiSession {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let Extension(sessions) = Extension::<StateUiSessions>::from_request(req)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let TypedHeader(cookie) = TypedHeader::<Cookie>::from_request(req)
+            .await
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let id = cookie.get(SESSION_COOKIE).ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let session = sessions
+            .write()
+            .unwrap()
+            .touch(id)
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        // the csrf token is not readable for other sites, so they cannot forge changing requests
+        let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+        if !safe {
+            let given = req
+                .headers()
+                .get(CSRF_HEADER)
+                .map(|v| v.as_bytes())
+                .unwrap_or_default();
+            if !bool::from(given.ct_eq(session.csrf_token.as_bytes())) {
+                tracing::debug! {email = %session.email.0, "csrf token mismatch"};
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+
+        Ok(PendingUiSession(session))
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for UiSession {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let PendingUiSession(session) = PendingUiSession::from_request(req).await?;
+        if session.second_factor_pending {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(session)
+    }
+}
+
+#[async_trait]
+impl<B: Send> FromRequest<B> for AdminUiSession {
+    type Rejection = StatusCode;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let session = UiSession::from_request(req).await?;
+        if !session.is_admin {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(AdminUiSession(session))
+    }
+}
+
+#[derive(Deserialize)]
+struct Login {
+    email: String,
+    password: String,
+}
+
+#[derive(Serialize)]
+pub(super) struct LoginResponse {
+    email: String,
+    is_admin: bool,
+    csrf_token: String,
+    /// `verify` or `enroll`, if the second factor is still missing
+    second_factor: Option<&'static str>,
+}
+
+impl LoginResponse {
+    pub(super) fn from(session: UiSession, user_storage: &StateUserStorage) -> Self {
+        let second_factor = session.second_factor_pending.then(|| {
+            let confirmed = user_storage
+                .read()
+                .unwrap()
+                .get_second_factor(&session.email)
+                .ok()
+                .flatten()
+                .is_some_and(|f| f.confirmed);
+            if confirmed {
+                "verify"
+            } else {
+                "enroll"
+            }
+        });
+        Self {
+            email: session.email.0,
+            is_admin: session.is_admin,
+            csrf_token: session.csrf_token,
+            second_factor,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SessionInfo {
+    handle: String,
+    email: String,
+    created_at: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    current: bool,
+}
+
+impl SessionInfo {
+    fn from(session: &UiSession, current: &UiSession) -> Self {
+        Self {
+            handle: session.handle.clone(),
+            email: session.email.0.clone(),
+            created_at: session.created_at,
+            last_seen: session.last_seen,
+            ip: session.ip.clone(),
+            user_agent: session.user_agent.clone(),
+            current: session.id == current.id,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Token {
+    jwt: String,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn login_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(sessions): Extension<StateUiSessions>,
+    Extension(user_storage): Extension<StateUserStorage>,
+    Extension(attempt_storage): Extension<StateAttemptStorage>,
+    Extension(audit_storage): Extension<StateAuditStorage>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    Json(payload): Json<Login>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let email = EMail::create(&payload.email).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+    let mut attempt_keys = vec![account_key(&email)];
+    if let Some(ConnectInfo(addr)) = &addr {
+        attempt_keys.push(ip_key(addr.ip()));
+    }
+    let attempt = reserve_attempt(&attempt_storage, attempt_keys)?;
+    let event = AuditEvent::new(AuditSource::Ui, &email.0, AuditAction::Login).ip(client_ip(&addr));
+
+    // argon2 takes its time, so it does not block the other requests on this worker
+    let verified = {
+        let (user_storage, email) = (user_storage.clone(), email.clone());
+        tokio::task::spawn_blocking(move || {
+            user_storage
+                .read()
+                .unwrap()
+                .verify_password(&email, &payload.password)
+        })
+        .await
+        .map_err(|v| {
+            tracing::error! {?v, "password verification panicked"};
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    };
+
+    let is_admin = {
+        let user_storage = user_storage.read().unwrap();
+        match verified {
+            Ok(true) => attempt.succeeded(&account_key(&email)),
+            Ok(false) => {
+                tracing::debug! {?email, "wrong password for ui login"};
+                audit(&audit_storage, event.failed());
+                attempt.failed();
+                metrics::login_failure("password");
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            Err(v) => {
+                tracing::debug! {?v, "cannot verify password for ui login"};
+                audit(&audit_storage, event.failed());
+                attempt.failed();
+                metrics::login_failure("password");
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+        }
+        user_storage
+            .get_user(&email)
+            .map_err(|_| StatusCode::UNAUTHORIZED)?
+            .is_admin()
+    };
+
+    let session = sessions.write().unwrap().create(
+        email,
+        is_admin,
+        addr.map(|ConnectInfo(addr)| addr.ip().to_string()),
+        user_agent.map(|TypedHeader(agent)| agent.to_string()),
+    );
+    tracing::debug! {email = %session.email.0, "ui login"};
+    audit(&audit_storage, event);
+
+    Ok((
+        AppendHeaders([(
+            header::SET_COOKIE,
+            session_cookie(&session.id, SESSION_IDLE_HOURS * 60 * 60, &config),
+        )]),
+        Json(LoginResponse::from(session, &user_storage)),
+    ))
+}
+
+async fn logout_handler(
+    Extension(config): Extension<Arc<Config>>,
+    PendingUiSession(session): PendingUiSession,
+    Extension(sessions): Extension<StateUiSessions>,
+) -> impl IntoResponse {
+    sessions.write().unwrap().sessions.remove(&session.id);
+    tracing::debug! {email = %session.email.0, "ui logout"};
+    (
+        StatusCode::NO_CONTENT,
+        AppendHeaders([(header::SET_COOKIE, session_cookie("", 0, &config))]),
+    )
+}
+
+async fn me_handler(
+    PendingUiSession(session): PendingUiSession,
+    Extension(user_storage): Extension<StateUserStorage>,
+) -> impl IntoResponse {
+    Json(LoginResponse::from(session, &user_storage))
+}
+
+/// Issues a jwt for the user of the session, so the UI can use the api.
+async fn token_handler(
+    session: UiSession,
+    Extension(config): Extension<Arc<Config>>,
+    Extension(user_storage): Extension<StateUserStorage>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let user = user_storage
+        .read()
+        .unwrap()
+        .get_user(&session.email)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    Ok(Json(Token {
+        jwt: create_jwt_from_userprofile(&config, user.as_ref(), None),
+    }))
+}
+
+async fn list_sessions_handler(
+    session: UiSession,
+    Extension(sessions): Extension<StateUiSessions>,
+) -> impl IntoResponse {
+    let list: Vec<SessionInfo> = sessions
+        .read()
+        .unwrap()
+        .sessions
+        .values()
+        .filter(|s| s.email.0 == session.email.0)
+        .map(|s| SessionInfo::from(s, &session))
+        .collect();
+    Json(list)
+}
+
+/// Lists the sessions of all users, only for admins.
+async fn list_all_sessions_handler(
+    AdminUiSession(session): AdminUiSession,
+    Extension(sessions): Extension<StateUiSessions>,
+) -> impl IntoResponse {
+    let list: Vec<SessionInfo> = sessions
+        .read()
+        .unwrap()
+        .sessions
+        .values()
+        .map(|s| SessionInfo::from(s, &session))
+        .collect();
+    Json(list)
+}
+
+async fn revoke_session_handler(
+    session: UiSession,
+    Extension(sessions): Extension<StateUiSessions>,
+    Path(handle): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let mut sessions = sessions.write().unwrap();
+    let owner = sessions
+        .sessions
+        .values()
+        .find(|s| s.handle == handle)
+        .map(|s| s.email.0.clone())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    // do not reveal sessions of other users to non admins
+    if owner != session.email.0 && !session.is_admin {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    sessions.remove_by_handle(&handle);
+    tracing::debug! {%handle, %owner, "ui session revoked"};
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn get_router() -> Router {
+    Router::new()
+        .route("/login", post(login_handler))
+        .route("/logout", post(logout_handler))
+        .route("/me", get(me_handler))
+        .route("/token", post(token_handler))
+        .route("/sessions", get(list_sessions_handler))
+        .route("/sessions/all", get(list_all_sessions_handler))
+        .route("/sessions/:handle", delete(revoke_session_handler))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use storage::{
+        AttemptLocalStorage, AttemptStorage, AuditLocalStorage, AuditStorage, UserLocalStorage,
+        UserStorage,
+    };
+    use tower::ServiceExt;
+
+    const PASSWORD: &str = "correct horse";
+
+    fn app(dir: &tempfile::TempDir) -> Router {
+        let config = Config::create(&format!(
+            r#"
+[COMMON]
+LOGLEVEL = "info"
+PORT = 8080
+SOCKET = "{dir}/rmcloud.sock"
+
+[UI]
+URL = "ui.local"
+
+[API]
+SECRET_KEY = "auth-test-secret"
+URL = "api.local"
+DATADIR = "{dir}"
+"#,
+            dir = dir.path().display()
+        ))
+        .unwrap();
+        let users = UserLocalStorage::create(&config).unwrap();
+        users
+            .create_user(
+                &EMail::create("admin@example.com").unwrap(),
+                PASSWORD,
+                &true,
+                &false,
+            )
+            .unwrap();
+        let user_storage: StateUserStorage = Arc::new(RwLock::new(users as Box<dyn UserStorage>));
+        let attempt_storage: StateAttemptStorage = Arc::new(RwLock::new(
+            AttemptLocalStorage::create(&config).unwrap() as Box<dyn AttemptStorage>,
+        ));
+        let audit_storage: StateAuditStorage = Arc::new(RwLock::new(
+            AuditLocalStorage::create(&config).unwrap() as Box<dyn AuditStorage>,
+        ));
+
+        get_router()
+            .layer(Extension(Arc::new(config)))
+            .layer(Extension(StateUiSessions::default()))
+            .layer(Extension(user_storage))
+            .layer(Extension(attempt_storage))
+            .layer(Extension(audit_storage))
+    }
+
+    async fn login(app: Router, password: &str) -> StatusCode {
+        let request = Request::post("/login")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(
+                r#"{{"email":"admin@example.com","password":"{}"}}"#,
+                password
+            )))
+            .unwrap();
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn parallel_password_guesses_are_bounded() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = app(&dir);
+
+        let guesses: Vec<_> = (0..12)
+            .map(|i| {
+                let app = app.clone();
+                tokio::spawn(async move { login(app.clone(), &format!("guess {}", i)).await })
+            })
+            .collect();
+        let mut statuses = vec![];
+        for guess in guesses {
+            statuses.push(guess.await.unwrap());
+        }
+
+        let count = |status| statuses.iter().filter(|v| **v == status).count();
+        assert_eq!(count(StatusCode::UNAUTHORIZED), 5);
+        assert_eq!(count(StatusCode::TOO_MANY_REQUESTS), 7);
+        // the lockout holds for the right password as well
+        assert_eq!(
+            login(app.clone(), PASSWORD).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn right_password_resets_the_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = app(&dir);
+
+        for _ in 0..4 {
+            assert_eq!(login(app.clone(), "wrong").await, StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(login(app.clone(), PASSWORD).await, StatusCode::OK);
+        for _ in 0..4 {
+            assert_eq!(login(app.clone(), "wrong").await, StatusCode::UNAUTHORIZED);
+        }
+    }
+}
@@ -1,31 +1,32 @@
-use crate::axum_server::State;
-use axum::Extension;
 use axum::{
     body::{boxed, Full},
     handler::Handler,
     http::{header, StatusCode, Uri},
-    response::{Html, IntoResponse, Response},
+    response::{IntoResponse, Response},
     routing::{get, Router},
 };
 use mime_guess;
 use rust_embed::RustEmbed;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
 
-// REMOVEME: This is an example for state exchange
-#[allow(dead_code)]
-async fn website_handler(Extension(state): Extension<Arc<State>>) -> Html<String> {
-    state.website_requests.fetch_add(1, Ordering::SeqCst);
-    Html(format!(
-        "website: {}",
-        state.website_requests.load(Ordering::SeqCst)
-    ))
-}
+mod auth;
+mod oidc;
+mod second_factor;
+
+pub use auth::{StateUiSessions, UiSessions};
+pub use oidc::{OidcLogin, StateOidcLogin};
 
 pub fn get_router() -> Router {
     Router::new()
         .route("/assets/*file", static_handler.into_service())
-        .route("/api/*path", crate::api::api_handler.into_service())
+        .nest(
+            "/api",
+            crate::api::get_ui_router().nest(
+                "/auth",
+                auth::get_router()
+                    .merge(oidc::get_router())
+                    .merge(second_factor::get_router()),
+            ),
+        )
         .fallback(get(index_handler))
 }
 
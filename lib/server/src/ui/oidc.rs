@@ -0,0 +1,808 @@
+use super::auth::{secure, session_cookie, StateUiSessions, SESSION_IDLE_HOURS};
+use crate::{
+    helper::{audit, client_ip},
+    metrics, StateAuditStorage, StateUserStorage,
+};
+use axum::{
+    extract::{ConnectInfo, Query, TypedHeader},
+    headers::{Cookie, UserAgent},
+    http::{header, StatusCode},
+    response::{AppendHeaders, IntoResponse, Redirect, Response},
+    routing::get,
+    Extension, Json, Router,
+};
+use config::{Config, Oidc};
+use openidconnect::{
+    core::{
+        CoreAuthDisplay, CoreAuthPrompt, CoreAuthenticationFlow, CoreErrorResponseType,
+        CoreGenderClaim, CoreJsonWebKey, CoreJsonWebKeyType, CoreJsonWebKeyUse,
+        CoreJweContentEncryptionAlgorithm, CoreJwsSigningAlgorithm, CoreProviderMetadata,
+        CoreRevocableToken, CoreRevocationErrorResponse, CoreTokenIntrospectionResponse,
+        CoreTokenType,
+    },
+    reqwest::async_http_client,
+    AdditionalClaims, AuthorizationCode, ClaimsVerificationError, Client, ClientId, ClientSecret,
+    CsrfToken, EmptyExtraTokenFields, IdTokenFields, IssuerUrl, Nonce, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, Scope, StandardErrorResponse, StandardTokenResponse,
+    TokenResponse,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use storage::{AuditAction, AuditEvent, AuditSource, EMail};
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+
+/// Binds the login to the browser, which started it.
+const STATE_COOKIE: &str = "rmcloud_oidc";
+/// Time for the user to login at the provider.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Logins waiting for their callback. Further ones are rejected, so nobody can fill the memory.
+const PENDING_MAX: usize = 1000;
+/// The provider metadata and its keys are discovered again after this time.
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+const PASSWORD_SIZE: usize = 48;
+
+pub type StateOidcLogin = Arc<OidcLogin>;
+
+/// All claims of the id token, which are not standard claims. The group claim is taken from it.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ExtraClaims {
+    #[serde(flatten)]
+    claims: BTreeMap<String, Value>,
+}
+
+impl AdditionalClaims for ExtraClaims {}
+
+type OidcTokenResponse = StandardTokenResponse<
+    IdTokenFields<
+        ExtraClaims,
+        EmptyExtraTokenFields,
+        CoreGenderClaim,
+        CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm,
+        CoreJsonWebKeyType,
+    >,
+    CoreTokenType,
+>;
+
+type OidcClient = Client<
+    ExtraClaims,
+    CoreAuthDisplay,
+    CoreGenderClaim,
+    CoreJweContentEncryptionAlgorithm,
+    CoreJwsSigningAlgorithm,
+    CoreJsonWebKeyType,
+    CoreJsonWebKeyUse,
+    CoreJsonWebKey,
+    CoreAuthPrompt,
+    StandardErrorResponse<CoreErrorResponseType>,
+    OidcTokenResponse,
+    CoreTokenType,
+    CoreTokenIntrospectionResponse,
+    CoreRevocableToken,
+    CoreRevocationErrorResponse,
+>;
+
+/// A login, which was sent to the provider and waits for its callback.
+struct PendingLogin {
+    verifier: PkceCodeVerifier,
+    nonce: Nonce,
+    started_at: Instant,
+}
+
+/// The authorization code flow with PKCE against the provider of `[UI.OIDC]`.
+pub struct OidcLogin {
+    config: RwLock<Option<Oidc>>,
+    client: tokio::sync::Mutex<Option<(OidcClient, Instant)>>,
+    pending: Mutex<BTreeMap<String, PendingLogin>>,
+}
+
+impl OidcLogin {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            config: RwLock::new(config.ui.oidc.clone()),
+            client: tokio::sync::Mutex::new(None),
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn config(&self) -> Option<Oidc> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Takes the provider of the reloaded config, logins in progress can still finish.
+    pub async fn reload(&self, config: &Config) {
+        *self.config.write().unwrap() = config.ui.oidc.clone();
+        self.forget_client().await;
+    }
+
+    /// Discovers the provider lazily, so the server starts even if the provider is down.
+    async fn client(&self, config: &Oidc) -> Result<OidcClient, String> {
+        let mut cached = self.client.lock().await;
+        if let Some((client, fetched_at)) = cached.as_ref() {
+            if fetched_at.elapsed() < DISCOVERY_TTL {
+                return Ok(client.clone());
+            }
+        }
+
+        let issuer = IssuerUrl::new(config.issuer.clone()).map_err(|v| v.to_string())?;
+        let metadata = CoreProviderMetadata::discover_async(issuer, async_http_client)
+            .await
+            .map_err(|v| format!("{:?}", v))?;
+        let client = OidcClient::from_provider_metadata(
+            metadata,
+            ClientId::new(config.client_id.clone()),
+            config.client_secret.clone().map(ClientSecret::new),
+        )
+        .set_redirect_uri(
+            RedirectUrl::new(config.redirect_url.clone()).map_err(|v| v.to_string())?,
+        );
+
+        *cached = Some((client.clone(), Instant::now()));
+        Ok(client)
+    }
+
+    /// The provider may have rotated its keys, so the next login discovers them again.
+    async fn forget_client(&self) {
+        self.client.lock().await.take();
+    }
+}
+
+/// Why a callback did not log the user in.
+#[derive(Error, Debug)]
+enum LoginError {
+    #[error("{0}")]
+    Rejected(String),
+    /// The provider may have rotated its keys.
+    #[error("id token signature not valid: {0:?}")]
+    Signature(ClaimsVerificationError),
+}
+
+impl From<String> for LoginError {
+    fn from(v: String) -> Self {
+        LoginError::Rejected(v)
+    }
+}
+
+impl From<&str> for LoginError {
+    fn from(v: &str) -> Self {
+        LoginError::Rejected(v.to_string())
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+fn state_cookie(value: &str, max_age: u64, config: &Config) -> String {
+    // Lax, because the callback is a top-level navigation coming from the provider
+    format!(
+        "{}={}; Path=/api/auth/oidc; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        STATE_COOKIE,
+        value,
+        max_age,
+        secure(config)
+    )
+}
+
+/// The login page asks for it, to show the button only if a provider is configured.
+async fn info_handler(Extension(oidc): Extension<StateOidcLogin>) -> impl IntoResponse {
+    Json(json!({ "enabled": oidc.config().is_some() }))
+}
+
+async fn login_handler(
+    Extension(server_config): Extension<Arc<Config>>,
+    Extension(oidc): Extension<StateOidcLogin>,
+) -> Result<Response, StatusCode> {
+    let config = oidc.config().ok_or(StatusCode::NOT_FOUND)?;
+    let client = oidc.client(&config).await.map_err(|v| {
+        tracing::warn! {%v, "cannot discover oidc provider"};
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+    let mut request = client.authorize_url(
+        CoreAuthenticationFlow::AuthorizationCode,
+        CsrfToken::new_random,
+        Nonce::new_random,
+    );
+    for scope in &config.scopes {
+        request = request.add_scope(Scope::new(scope.clone()));
+    }
+    let (url, state, nonce) = request.set_pkce_challenge(challenge).url();
+
+    {
+        let mut pending = oidc.pending.lock().unwrap();
+        pending.retain(|_, p| p.started_at.elapsed() < PENDING_TIMEOUT);
+        if pending.len() >= PENDING_MAX {
+            tracing::warn! {"too many pending oidc logins"};
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        }
+        pending.insert(
+            state.secret().clone(),
+            PendingLogin {
+                verifier,
+                nonce,
+                started_at: Instant::now(),
+            },
+        );
+    }
+
+    Ok((
+        AppendHeaders([(
+            header::SET_COOKIE,
+            state_cookie(state.secret(), PENDING_TIMEOUT.as_secs(), &server_config),
+        )]),
+        Redirect::to(url.as_str()),
+    )
+        .into_response())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn callback_handler(
+    Extension(config): Extension<Arc<Config>>,
+    Extension(oidc): Extension<StateOidcLogin>,
+    Extension(sessions): Extension<StateUiSessions>,
+    Extension(user_storage): Extension<StateUserStorage>,
+    Extension(audit_storage): Extension<StateAuditStorage>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(callback): Query<Callback>,
+) -> Response {
+    let clear_state = (header::SET_COOKIE, state_cookie("", 0, &config));
+    let (email, is_admin) =
+        match finish_login(&oidc, &user_storage, &audit_storage, cookie, callback).await {
+            Ok(v) => v,
+            Err(v) => {
+                tracing::debug! {%v, "oidc login failed"};
+                metrics::login_failure("oidc");
+                if let LoginError::Signature(_) = v {
+                    oidc.forget_client().await;
+                }
+                return (
+                    AppendHeaders([clear_state]),
+                    Redirect::to("/login?oidc=failed"),
+                )
+                    .into_response();
+            }
+        };
+
+    audit(
+        &audit_storage,
+        AuditEvent::new(AuditSource::Ui, &email.0, AuditAction::Login).ip(client_ip(&addr)),
+    );
+    let session = sessions.write().unwrap().create(
+        email,
+        is_admin,
+        client_ip(&addr),
+        user_agent.map(|TypedHeader(agent)| agent.to_string()),
+    );
+    tracing::debug! {email = %session.email.0, "ui login through oidc"};
+
+    (
+        AppendHeaders([
+            clear_state,
+            (
+                header::SET_COOKIE,
+                session_cookie(&session.id, SESSION_IDLE_HOURS * 60 * 60, &config),
+            ),
+        ]),
+        Redirect::to("/login?oidc=success"),
+    )
+        .into_response()
+}
+
+/// Exchanges the code and maps the verified claims onto a user. Returns the email and is_admin.
+async fn finish_login(
+    oidc: &OidcLogin,
+    user_storage: &StateUserStorage,
+    audit_storage: &StateAuditStorage,
+    cookie: Option<TypedHeader<Cookie>>,
+    callback: Callback,
+) -> Result<(EMail, bool), LoginError> {
+    let config = oidc.config().ok_or("oidc is not configured")?;
+    if let Some(error) = callback.error {
+        return Err(format!("provider returned error {}", error).into());
+    }
+    let state = callback.state.ok_or("callback without state")?;
+    let code = callback.code.ok_or("callback without code")?;
+
+    let cookie_state = cookie
+        .as_ref()
+        .and_then(|TypedHeader(cookie)| cookie.get(STATE_COOKIE))
+        .unwrap_or_default();
+    if !bool::from(cookie_state.as_bytes().ct_eq(state.as_bytes())) {
+        return Err("state does not belong to this browser".into());
+    }
+
+    let pending = oidc
+        .pending
+        .lock()
+        .unwrap()
+        .remove(&state)
+        .filter(|p| p.started_at.elapsed() < PENDING_TIMEOUT)
+        .ok_or("unknown or expired state")?;
+
+    let client = oidc.client(&config).await?;
+    let response = client
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(pending.verifier)
+        .request_async(async_http_client)
+        .await
+        .map_err(|v| format!("cannot exchange code: {:?}", v))?;
+
+    let id_token = response.id_token().ok_or("provider sent no id token")?;
+    let claims = id_token
+        .claims(&client.id_token_verifier(), &pending.nonce)
+        .map_err(|v| match v {
+            ClaimsVerificationError::SignatureVerification(_) => LoginError::Signature(v),
+            v => format!("id token not valid: {}", v).into(),
+        })?;
+
+    // the account of the email is taken over, so the provider has to vouch for it
+    if claims.email_verified() != Some(true) && !config.trust_unverified_email {
+        return Err("email of the provider is not verified".into());
+    }
+    let email = claims.email().ok_or("id token has no email claim")?;
+    let email = EMail::create(email.as_str()).map_err(|v| format!("{:?}", v))?;
+
+    let groups = groups(&claims.additional_claims().claims, &config.groups_claim);
+    let admin = config
+        .admin_group
+        .as_ref()
+        .map(|group| groups.iter().any(|g| g == group));
+
+    let user_storage = user_storage.read().unwrap();
+    let user = match user_storage.get_user(&email) {
+        Ok(v) => v,
+        Err(_) if config.provision => {
+            // the user logs in through the provider, so nobody needs to know this password
+            let password: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(PASSWORD_SIZE)
+                .map(char::from)
+                .collect();
+            tracing::info! {?email, "provision user on first oidc login"};
+            let user = user_storage
+                .create_user(&email, &password, &admin.unwrap_or(false), &false)
+                .map_err(|v| format!("cannot provision user: {:?}", v))?;
+            audit(
+                audit_storage,
+                AuditEvent::new(AuditSource::Ui, &email.0, AuditAction::UserCreated)
+                    .target(&email.0),
+            );
+            user
+        }
+        Err(_) => return Err(format!("no user for {} and provisioning is off", email.0).into()),
+    };
+
+    let is_admin = match admin {
+        Some(is_admin) => {
+            user_storage
+                .set_admin(&email, is_admin)
+                .map_err(|v| format!("cannot change admin flag: {:?}", v))?;
+            is_admin
+        }
+        None => user.is_admin(),
+    };
+
+    Ok((email, is_admin))
+}
+
+/// The claim can hold a list of groups or a single one.
+fn groups(claims: &BTreeMap<String, Value>, name: &str) -> Vec<String> {
+    match claims.get(name) {
+        Some(Value::Array(v)) => v
+            .iter()
+            .filter_map(|g| g.as_str().map(str::to_string))
+            .collect(),
+        Some(Value::String(v)) => vec![v.clone()],
+        _ => vec![],
+    }
+}
+
+pub fn get_router() -> Router {
+    Router::new()
+        .route("/oidc", get(info_handler))
+        .route("/oidc/login", get(login_handler))
+        .route("/oidc/callback", get(callback_handler))
+}
+
+/// Runs the login against a provider in the same process, the issuer of the config points to it.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request, routing::post};
+    use openidconnect::{
+        core::{CoreJsonWebKeyType, CoreRsaPrivateSigningKey},
+        url::Url,
+        Audience, EndUserEmail, IdToken, IdTokenClaims, JsonWebKeyId, PrivateSigningKey,
+        StandardClaims, SubjectIdentifier,
+    };
+    use std::path::PathBuf;
+    use storage::{AuditLocalStorage, AuditStorage, UserLocalStorage, UserStorage};
+    use tower::ServiceExt;
+
+    /// What the next id token of the provider says.
+    #[derive(Clone, Default)]
+    struct Token {
+        email: String,
+        email_verified: Option<bool>,
+        groups: Vec<String>,
+        /// the nonce of the login, if not set
+        nonce: Option<String>,
+        /// breaks the signature
+        tamper: bool,
+    }
+
+    #[derive(Clone)]
+    struct Provider {
+        issuer: String,
+        key: Arc<CoreRsaPrivateSigningKey>,
+        token: Arc<Mutex<Token>>,
+        /// the nonce of the last authorization request
+        nonce: Arc<Mutex<String>>,
+    }
+
+    type ProviderIdToken = IdToken<
+        ExtraClaims,
+        CoreGenderClaim,
+        CoreJweContentEncryptionAlgorithm,
+        CoreJwsSigningAlgorithm,
+        CoreJsonWebKeyType,
+    >;
+
+    impl Provider {
+        async fn start() -> Self {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let provider = Provider {
+                issuer: format!("http://{}", listener.local_addr().unwrap()),
+                key: Arc::new(
+                    CoreRsaPrivateSigningKey::from_pem(
+                        include_str!("testdata/oidc_key.pem"),
+                        Some(JsonWebKeyId::new("test".to_string())),
+                    )
+                    .unwrap(),
+                ),
+                token: Arc::default(),
+                nonce: Arc::default(),
+            };
+
+            let app = Router::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    get(|Extension(p): Extension<Provider>| async move {
+                        Json(json!({
+                            "issuer": p.issuer,
+                            "authorization_endpoint": format!("{}/authorize", p.issuer),
+                            "token_endpoint": format!("{}/token", p.issuer),
+                            "jwks_uri": format!("{}/jwks", p.issuer),
+                            "response_types_supported": ["code"],
+                            "subject_types_supported": ["public"],
+                            "id_token_signing_alg_values_supported": ["RS256"],
+                        }))
+                    }),
+                )
+                .route(
+                    "/jwks",
+                    get(|Extension(p): Extension<Provider>| async move {
+                        Json(json!({ "keys": [p.key.as_verification_key()] }))
+                    }),
+                )
+                .route(
+                    "/token",
+                    post(|Extension(p): Extension<Provider>| async move {
+                        Json(json!({
+                            "access_token": "access",
+                            "token_type": "bearer",
+                            "id_token": p.id_token(),
+                        }))
+                    }),
+                )
+                .layer(Extension(provider.clone()));
+            let server = axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service());
+            tokio::spawn(server);
+            provider
+        }
+
+        fn id_token(&self) -> String {
+            let token = self.token.lock().unwrap().clone();
+            let nonce = token
+                .nonce
+                .unwrap_or_else(|| self.nonce.lock().unwrap().clone());
+            let now = chrono::Utc::now();
+            let claims = IdTokenClaims::new(
+                IssuerUrl::new(self.issuer.clone()).unwrap(),
+                vec![Audience::new("rmcloud".to_string())],
+                now + chrono::Duration::minutes(5),
+                now,
+                StandardClaims::new(SubjectIdentifier::new(token.email.clone()))
+                    .set_email(Some(EndUserEmail::new(token.email)))
+                    .set_email_verified(token.email_verified),
+                ExtraClaims {
+                    claims: BTreeMap::from([("groups".to_string(), json!(token.groups))]),
+                },
+            )
+            .set_nonce(Some(Nonce::new(nonce)));
+            let id_token = ProviderIdToken::new(
+                claims,
+                self.key.as_ref(),
+                CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+                None,
+                None,
+            )
+            .unwrap()
+            .to_string();
+            if !token.tamper {
+                return id_token;
+            }
+            // another first character keeps the signature decodable, but not valid
+            let (content, signature) = id_token.rsplit_once('.').unwrap();
+            let flipped = if signature.starts_with('A') { 'B' } else { 'A' };
+            format!("{}.{}{}", content, flipped, &signature[1..])
+        }
+    }
+
+    struct Setup {
+        provider: Provider,
+        oidc: StateOidcLogin,
+        user_storage: StateUserStorage,
+        app: Router,
+        _dir: tempfile::TempDir,
+    }
+
+    /// `oidc` is added to the `[UI.OIDC]` table.
+    async fn setup(oidc: &str) -> Setup {
+        let provider = Provider::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let path: PathBuf = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+[COMMON]
+LOGLEVEL = "info"
+PORT = 8080
+SOCKET = "{dir}/rmcloud.sock"
+
+[UI]
+URL = "ui.local"
+
+[UI.OIDC]
+ISSUER = "{issuer}"
+CLIENT_ID = "rmcloud"
+REDIRECT_URL = "http://ui.local/api/auth/oidc/callback"
+{oidc}
+
+[API]
+SECRET_KEY = "oidc-test-secret"
+URL = "api.local"
+DATADIR = "{dir}"
+"#,
+                dir = dir.path().display(),
+                issuer = provider.issuer,
+                oidc = oidc,
+            ),
+        )
+        .unwrap();
+
+        let config = config::Layered::load(&path).unwrap().config;
+        let oidc = Arc::new(OidcLogin::new(&config));
+        let user_storage: StateUserStorage = Arc::new(RwLock::new(
+            UserLocalStorage::create(&config).unwrap() as Box<dyn UserStorage>,
+        ));
+        let audit_storage: StateAuditStorage = Arc::new(RwLock::new(
+            AuditLocalStorage::create(&config).unwrap() as Box<dyn AuditStorage>,
+        ));
+        let app = get_router()
+            .layer(Extension(Arc::new(config)))
+            .layer(Extension(oidc.clone()))
+            .layer(Extension(StateUiSessions::default()))
+            .layer(Extension(user_storage.clone()))
+            .layer(Extension(audit_storage));
+
+        Setup {
+            provider,
+            oidc,
+            user_storage,
+            app,
+            _dir: dir,
+        }
+    }
+
+    fn location(response: &Response) -> String {
+        response.headers()[header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    impl Setup {
+        /// Starts a login and returns the state of its cookie.
+        async fn start(&self) -> String {
+            let response = self
+                .app
+                .clone()
+                .oneshot(Request::get("/oidc/login").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::SEE_OTHER);
+
+            let url = Url::parse(&location(&response)).unwrap();
+            let param = |name: &str| {
+                url.query_pairs()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.to_string())
+                    .unwrap()
+            };
+            *self.provider.nonce.lock().unwrap() = param("nonce");
+            param("state")
+        }
+
+        /// Returns the page, which the browser is sent to after the callback.
+        async fn callback(&self, state: &str, cookie_state: &str) -> String {
+            let request = Request::get(format!("/oidc/callback?code=code&state={}", state))
+                .header(header::COOKIE, format!("{}={}", STATE_COOKIE, cookie_state))
+                .body(Body::empty())
+                .unwrap();
+            location(&self.app.clone().oneshot(request).await.unwrap())
+        }
+
+        async fn login(&self, token: Token) -> String {
+            *self.provider.token.lock().unwrap() = token;
+            let state = self.start().await;
+            self.callback(&state, &state).await
+        }
+
+        fn is_admin(&self, email: &str) -> Option<bool> {
+            let email = EMail::create(email).unwrap();
+            let user = self.user_storage.read().unwrap().get_user(&email).ok()?;
+            Some(user.is_admin())
+        }
+
+        async fn has_client(&self) -> bool {
+            self.oidc.client.lock().await.is_some()
+        }
+    }
+
+    const SUCCESS: &str = "/login?oidc=success";
+    const FAILED: &str = "/login?oidc=failed";
+
+    fn token(email: &str, groups: &[&str]) -> Token {
+        Token {
+            email: email.to_string(),
+            email_verified: Some(true),
+            groups: groups.iter().map(|v| v.to_string()).collect(),
+            ..Token::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn provisions_user_and_maps_admin_group() {
+        let setup = setup("PROVISION = true\nADMIN_GROUP = \"admins\"").await;
+
+        let login = setup.login(token("admin@example.com", &["admins"])).await;
+        assert_eq!(login, SUCCESS);
+        assert_eq!(setup.is_admin("admin@example.com"), Some(true));
+
+        // leaving the group takes is_admin away on the next login
+        let login = setup.login(token("admin@example.com", &["users"])).await;
+        assert_eq!(login, SUCCESS);
+        assert_eq!(setup.is_admin("admin@example.com"), Some(false));
+
+        let login = setup.login(token("user@example.com", &[])).await;
+        assert_eq!(login, SUCCESS);
+        assert_eq!(setup.is_admin("user@example.com"), Some(false));
+    }
+
+    #[tokio::test]
+    async fn unknown_user_needs_provisioning() {
+        let setup = setup("").await;
+        let login = setup.login(token("user@example.com", &[])).await;
+        assert_eq!(login, FAILED);
+        assert_eq!(setup.is_admin("user@example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn rejects_state_of_another_browser() {
+        let setup = setup("PROVISION = true").await;
+        *setup.provider.token.lock().unwrap() = token("user@example.com", &[]);
+        let state = setup.start().await;
+        let other = setup.start().await;
+
+        assert_eq!(setup.callback(&state, &other).await, FAILED);
+        assert_eq!(setup.callback("unknown", "unknown").await, FAILED);
+        assert_eq!(setup.is_admin("user@example.com"), None);
+        // the state is still pending for its own browser
+        assert_eq!(setup.callback(&other, &other).await, SUCCESS);
+    }
+
+    #[tokio::test]
+    async fn rejects_nonce_of_another_login() {
+        let setup = setup("PROVISION = true").await;
+        let login = setup
+            .login(Token {
+                nonce: Some("another".to_string()),
+                ..token("user@example.com", &[])
+            })
+            .await;
+        assert_eq!(login, FAILED);
+        assert_eq!(setup.is_admin("user@example.com"), None);
+        // only a broken signature discovers the keys again
+        assert!(setup.has_client().await);
+    }
+
+    #[tokio::test]
+    async fn forgets_provider_on_broken_signature() {
+        let setup = setup("PROVISION = true").await;
+        let login = setup
+            .login(Token {
+                tamper: true,
+                ..token("user@example.com", &[])
+            })
+            .await;
+        assert_eq!(login, FAILED);
+        assert!(!setup.has_client().await);
+    }
+
+    #[tokio::test]
+    async fn requires_verified_email() {
+        let strict = setup("PROVISION = true").await;
+        for email_verified in [None, Some(false)] {
+            let login = strict
+                .login(Token {
+                    email_verified,
+                    ..token("user@example.com", &[])
+                })
+                .await;
+            assert_eq!(login, FAILED);
+        }
+
+        let trusting = setup("PROVISION = true\nTRUST_UNVERIFIED_EMAIL = true").await;
+        let login = trusting
+            .login(Token {
+                email_verified: None,
+                ..token("user@example.com", &[])
+            })
+            .await;
+        assert_eq!(login, SUCCESS);
+    }
+
+    #[tokio::test]
+    async fn pending_logins_are_capped() {
+        let setup = setup("").await;
+        {
+            let mut pending = setup.oidc.pending.lock().unwrap();
+            for i in 0..PENDING_MAX {
+                pending.insert(
+                    i.to_string(),
+                    PendingLogin {
+                        verifier: PkceCodeVerifier::new("verifier".repeat(8)),
+                        nonce: Nonce::new("nonce".to_string()),
+                        started_at: Instant::now(),
+                    },
+                );
+            }
+        }
+        let response = setup
+            .app
+            .clone()
+            .oneshot(Request::get("/oidc/login").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,247 @@
+use super::auth::{LoginResponse, PendingUiSession, StateUiSessions};
+use crate::{
+    helper::{ip_key, reserve_attempt, second_factor_key},
+    metrics, StateAttemptStorage, StateUserStorage,
+};
+use axum::{
+    extract::ConnectInfo,
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use qrcodegen::{QrCode, QrCodeEcc};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use storage::{EMail, SecondFactor, SecondFactorCheck};
+
+/// Shown as the name of the account in authenticator apps.
+const ISSUER: &str = "rmcloud";
+/// White modules around the QR code, which scanners need.
+const QR_BORDER: i32 = 4;
+
+#[derive(Serialize)]
+struct Status {
+    enrolled: bool,
+    confirmed: bool,
+    recovery_codes_left: usize,
+}
+
+#[derive(Serialize)]
+struct Enrolment {
+    secret_uri: String,
+    qr_svg: String,
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct Verify {
+    code: String,
+}
+
+/// Renders the QR code as svg, so the UI can show it without further libraries.
+fn qr_svg(text: &str) -> Option<String> {
+    let qr = QrCode::encode_text(text, QrCodeEcc::Medium).ok()?;
+    let size = qr.size() + QR_BORDER * 2;
+    let mut path = String::new();
+    for y in 0..qr.size() {
+        for x in 0..qr.size() {
+            if qr.get_module(x, y) {
+                path += &format!("M{},{}h1v1h-1z", x + QR_BORDER, y + QR_BORDER);
+            }
+        }
+    }
+    Some(format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"0 0 {0} {0}\" stroke=\"none\">\
+         <rect width=\"100%\" height=\"100%\" fill=\"#FFFFFF\"/>\
+         <path d=\"{1}\" fill=\"#000000\"/></svg>",
+        size, path
+    ))
+}
+
+async fn status_handler(
+    PendingUiSession(session): PendingUiSession,
+    Extension(user_storage): Extension<StateUserStorage>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let second_factor = user_storage
+        .read()
+        .unwrap()
+        .get_second_factor(&session.email)
+        .map_err(|v| {
+            tracing::debug! {?v, "cannot load second factor"};
+            metrics::storage_error("user");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Json(Status {
+        enrolled: second_factor.is_some(),
+        confirmed: second_factor.as_ref().is_some_and(|f| f.confirmed),
+        recovery_codes_left: second_factor.map_or(0, |f| f.recovery_codes_left()),
+    }))
+}
+
+/// Starts a new enrolment. A confirmed second factor can only be reset through the cli.
+async fn enroll_handler(
+    PendingUiSession(session): PendingUiSession,
+    Extension(user_storage): Extension<StateUserStorage>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let user_storage = user_storage.read().unwrap();
+    let existing = user_storage
+        .get_second_factor(&session.email)
+        .map_err(|v| {
+            tracing::debug! {?v, "cannot load second factor"};
+            metrics::storage_error("user");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    if existing.is_some_and(|f| f.confirmed) {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let (second_factor, recovery_codes) = SecondFactor::generate();
+    let secret_uri = second_factor
+        .provisioning_uri(ISSUER, &session.email.0)
+        .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let qr_svg = qr_svg(&secret_uri).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    user_storage
+        .store_second_factor(&session.email, &second_factor)
+        .map_err(|v| {
+            tracing::warn! {?v, "cannot store second factor"};
+            metrics::storage_error("user");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    tracing::debug! {email = %session.email.0, "second factor enrolment started"};
+
+    Ok(Json(Enrolment {
+        secret_uri,
+        qr_svg,
+        recovery_codes,
+    }))
+}
+
+/// Checks the code on a blocking thread, as recovery codes are argon2 hashes.
+/// Failures count against the same lockout for enrolment and login.
+async fn check_code(
+    email: &EMail,
+    code: String,
+    confirm: bool,
+    user_storage: &StateUserStorage,
+    attempt_storage: &StateAttemptStorage,
+    addr: &Option<ConnectInfo<SocketAddr>>,
+) -> Result<SecondFactorCheck, StatusCode> {
+    let mut attempt_keys = vec![second_factor_key(email)];
+    if let Some(ConnectInfo(addr)) = addr {
+        attempt_keys.push(ip_key(addr.ip()));
+    }
+    let attempt = reserve_attempt(attempt_storage, attempt_keys)?;
+
+    let check = {
+        let (user_storage, email) = (user_storage.clone(), email.clone());
+        tokio::task::spawn_blocking(move || {
+            user_storage
+                .read()
+                .unwrap()
+                .verify_second_factor(&email, &code, confirm)
+        })
+        .await
+        .map_err(|v| {
+            tracing::error! {?v, "second factor verification panicked"};
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map_err(|v| {
+            tracing::warn! {?v, "cannot verify second factor"};
+            metrics::storage_error("user");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+    };
+
+    match check {
+        SecondFactorCheck::Missing => {
+            // nothing to guess yet
+            attempt.succeeded(&second_factor_key(email));
+            Err(StatusCode::CONFLICT)
+        }
+        SecondFactorCheck::Wrong => {
+            tracing::debug! {email = %email.0, "wrong second factor"};
+            attempt.failed();
+            metrics::login_failure("second_factor");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+        check => {
+            attempt.succeeded(&second_factor_key(email));
+            Ok(check)
+        }
+    }
+}
+
+/// Finishes the enrolment with the first code of the authenticator app.
+/// Verifies the session as well, so admins do not have to enter a second code.
+async fn confirm_handler(
+    PendingUiSession(session): PendingUiSession,
+    Extension(sessions): Extension<StateUiSessions>,
+    Extension(user_storage): Extension<StateUserStorage>,
+    Extension(attempt_storage): Extension<StateAttemptStorage>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Json(payload): Json<Verify>,
+) -> Result<impl IntoResponse, StatusCode> {
+    check_code(
+        &session.email,
+        payload.code,
+        true,
+        &user_storage,
+        &attempt_storage,
+        &addr,
+    )
+    .await?;
+    tracing::info! {email = %session.email.0, "second factor enrolled"};
+
+    let mut session = session;
+    sessions
+        .write()
+        .unwrap()
+        .complete_second_factor(&session.id);
+    session.second_factor_pending = false;
+    Ok(Json(LoginResponse::from(session, &user_storage)))
+}
+
+/// Accepts a code of the authenticator app or a recovery code.
+async fn verify_handler(
+    PendingUiSession(session): PendingUiSession,
+    Extension(sessions): Extension<StateUiSessions>,
+    Extension(user_storage): Extension<StateUserStorage>,
+    Extension(attempt_storage): Extension<StateAttemptStorage>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Json(payload): Json<Verify>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let check = check_code(
+        &session.email,
+        payload.code,
+        false,
+        &user_storage,
+        &attempt_storage,
+        &addr,
+    )
+    .await?;
+    match check {
+        SecondFactorCheck::RecoveryCode { left } => {
+            tracing::info! {email = %session.email.0, left, "recovery code used"}
+        }
+        _ => tracing::debug! {email = %session.email.0, "second factor verified"},
+    }
+
+    let mut session = session;
+    sessions
+        .write()
+        .unwrap()
+        .complete_second_factor(&session.id);
+    session.second_factor_pending = false;
+    Ok(Json(LoginResponse::from(session, &user_storage)))
+}
+
+pub fn get_router() -> Router {
+    Router::new()
+        .route("/totp", get(status_handler))
+        .route("/totp/enroll", post(enroll_handler))
+        .route("/totp/confirm", post(confirm_handler))
+        .route("/totp/verify", post(verify_handler))
+}
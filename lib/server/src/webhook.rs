@@ -0,0 +1,484 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Url,
+};
+use serde::Serialize;
+use sha2::Sha256;
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+};
+use storage::EMail;
+use tokio::{
+    net::lookup_host,
+    time::{sleep, Duration},
+};
+use uuid::Uuid;
+
+use crate::{metrics, StateConfig, StateUserStorage};
+
+/// How often a delivery is tried, before it is given up.
+const MAX_ATTEMPTS: u32 = 5;
+/// The waiting time before the first retry. It doubles with every further attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many delivery attempts are kept for the admin api.
+const DELIVERY_LOG_SIZE: usize = 1000;
+
+/// The header, which holds the hex encoded HMAC-SHA256 of the body.
+pub const SIGNATURE_HEADER: &str = "X-Rmcloud-Signature";
+
+pub type StateWebhooks = Arc<Webhooks>;
+
+/// All events, which can be sent to webhooks. Document events follow, once documents are stored.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEvent {
+    #[serde(rename = "user.login")]
+    UserLogin,
+    #[serde(rename = "device.paired")]
+    DevicePaired,
+}
+
+impl WebhookEvent {
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::UserLogin => "user.login",
+            WebhookEvent::DevicePaired => "device.paired",
+        }
+    }
+
+    /// An empty filter subscribes to all events.
+    fn matches(&self, filter: &[String]) -> bool {
+        filter.is_empty() || filter.iter().any(|e| e == self.name())
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Payload<'a> {
+    id: String,
+    event: WebhookEvent,
+    timestamp: DateTime<Utc>,
+    user: &'a str,
+    data: &'a serde_json::Value,
+}
+
+/// A single delivery attempt of a payload to a webhook.
+#[derive(Serialize, Debug, Clone)]
+pub struct Delivery {
+    pub payload_id: String,
+    pub event: WebhookEvent,
+    pub user: String,
+    pub url: String,
+    pub attempt: u32,
+    pub timestamp: DateTime<Utc>,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub delivered: bool,
+}
+
+struct Target {
+    url: String,
+    secret: String,
+    /// the webhook of a user may only reach public addresses, unlike the ones of the config
+    public_only: bool,
+}
+
+/// Sends the events to the global webhooks of the config and to the webhooks of the user.
+pub struct Webhooks {
+    config: StateConfig,
+    user_storage: StateUserStorage,
+    client: reqwest::Client,
+    /// for the webhooks of users, which must not reach the server's own network
+    public_client: reqwest::Client,
+    deliveries: Arc<Mutex<VecDeque<Delivery>>>,
+}
+
+impl Webhooks {
+    pub fn new(config: StateConfig, user_storage: StateUserStorage) -> Self {
+        Self {
+            config,
+            user_storage,
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Cannot create http client for webhooks."),
+            public_client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .dns_resolver(Arc::new(PublicResolver))
+                // a redirect could point to any address
+                .redirect(redirect::Policy::none())
+                .build()
+                .expect("Cannot create http client for webhooks."),
+            deliveries: Arc::new(Mutex::new(VecDeque::with_capacity(DELIVERY_LOG_SIZE))),
+        }
+    }
+
+    /// Sends the event in the background to all webhooks, which subscribed to it.
+    pub fn emit(&self, event: WebhookEvent, user: &EMail, data: serde_json::Value) {
+        let targets = self.targets(event, user);
+        if targets.is_empty() {
+            return;
+        }
+
+        let payload = Payload {
+            id: Uuid::new_v4().to_string(),
+            event,
+            timestamp: Utc::now(),
+            user: &user.0,
+            data: &data,
+        };
+        let body = match serde_json::to_string(&payload) {
+            Ok(v) => v,
+            Err(v) => {
+                tracing::debug! {?v, "cannot serialize webhook payload"};
+                return;
+            }
+        };
+
+        tracing::debug! {event = event.name(), targets = targets.len(), "emit webhook event"};
+        for target in targets {
+            let delivery = Delivery {
+                payload_id: payload.id.clone(),
+                event,
+                user: user.0.clone(),
+                url: target.url.clone(),
+                attempt: 0,
+                timestamp: payload.timestamp,
+                status: None,
+                error: None,
+                delivered: false,
+            };
+            let client = match target.public_only {
+                true => self.public_client.clone(),
+                false => self.client.clone(),
+            };
+            tokio::spawn(deliver(
+                client,
+                self.deliveries.clone(),
+                target,
+                body.clone(),
+                delivery,
+            ));
+        }
+    }
+
+    /// Returns the logged delivery attempts, newest first.
+    pub fn deliveries(&self) -> Vec<Delivery> {
+        self.deliveries
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    fn targets(&self, event: WebhookEvent, user: &EMail) -> Vec<Target> {
+        let mut targets: Vec<Target> = self
+            .config
+            .read()
+            .unwrap()
+            .api
+            .webhooks
+            .iter()
+            .filter(|w| event.matches(&w.events))
+            .map(|w| Target {
+                url: w.url.clone(),
+                secret: w.secret.clone(),
+                public_only: false,
+            })
+            .collect();
+
+        match self.user_storage.read().unwrap().get_webhooks(user) {
+            Ok(webhooks) => targets.extend(
+                webhooks
+                    .into_iter()
+                    .filter(|w| event.matches(&w.events))
+                    .map(|w| Target {
+                        url: w.url,
+                        secret: w.secret,
+                        public_only: true,
+                    }),
+            ),
+            Err(v) => {
+                tracing::debug! {?v, "cannot load webhooks of user"};
+                metrics::storage_error("user");
+            }
+        }
+
+        targets
+    }
+}
+
+/// Returns the hex encoded HMAC-SHA256 of the body with the given secret.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac: Hmac<Sha256> = Hmac::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Whether the address is reachable from the internet. Loopback, private, link-local,
+/// shared and reserved addresses are not, so a user webhook cannot reach the local network.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v) => is_public_v4(v),
+        IpAddr::V6(v) => match v.to_ipv4_mapped() {
+            Some(v) => is_public_v4(v),
+            None => is_public_v6(v),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // shared address space of carrier-grade NAT
+        || (a == 100 && (b & 0xc0) == 64)
+        || (a == 192 && b == 0 && c == 0)
+        // benchmarking
+        || (a == 198 && (b & 0xfe) == 18)
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local
+        || (segments[0] & 0xfe00) == 0xfc00
+        // link-local
+        || (segments[0] & 0xffc0) == 0xfe80
+        // documentation
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8)
+        // NAT64, which could reach any IPv4 address
+        || (segments[0] == 0x64 && segments[1] == 0xff9b))
+}
+
+/// Parses the url of a user webhook. Only http(s) urls with a host are taken,
+/// an IP address as host must be public.
+fn parse_public_url(url: &str) -> Result<Url, String> {
+    let url = Url::parse(url).map_err(|v| v.to_string())?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err("only http and https are allowed".to_string());
+    }
+    match url.host() {
+        None => Err("the url has no host".to_string()),
+        Some(url::Host::Ipv4(v)) if !is_public_v4(v) => Err(format!("{} is not public", v)),
+        Some(url::Host::Ipv6(v)) if !is_public(IpAddr::V6(v)) => {
+            Err(format!("{} is not public", v))
+        }
+        Some(_) => Ok(url),
+    }
+}
+
+/// Checks, that the url of a user webhook resolves only to public addresses.
+/// The delivery checks again, as the name can resolve to other addresses later.
+pub async fn check_public_url(url: &str) -> Result<(), String> {
+    let url = parse_public_url(url)?;
+    let host = match url.host() {
+        Some(url::Host::Domain(v)) => v,
+        _ => return Ok(()),
+    };
+    let port = url.port_or_known_default().unwrap_or_default();
+    let addrs: Vec<SocketAddr> = lookup_host((host, port))
+        .await
+        .map_err(|v| format!("cannot resolve {}: {}", host, v))?
+        .collect();
+    match addrs.iter().find(|v| !is_public(v.ip())) {
+        Some(v) => Err(format!(
+            "{} resolves to {}, which is not public",
+            host,
+            v.ip()
+        )),
+        None if addrs.is_empty() => Err(format!("{} has no address", host)),
+        None => Ok(()),
+    }
+}
+
+/// Resolves only to the public addresses of a name, for the client of the user webhooks.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|v| is_public(v.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    deliveries: Arc<Mutex<VecDeque<Delivery>>>,
+    target: Target,
+    body: String,
+    mut delivery: Delivery,
+) {
+    let signature = format!("sha256={}", sign(&target.secret, &body));
+    let mut backoff = INITIAL_BACKOFF;
+
+    if target.public_only {
+        if let Err(v) = parse_public_url(&target.url) {
+            // stored before the address was checked, retrying does not help
+            delivery.attempt = 1;
+            delivery.timestamp = Utc::now();
+            delivery.error = Some(v);
+            log_delivery(&deliveries, delivery);
+            tracing::warn! {url = %target.url, "webhook of user points to no public address"};
+            return;
+        }
+    }
+
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = client
+            .post(&target.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .body(body.clone())
+            .send()
+            .await;
+
+        delivery.attempt = attempt;
+        delivery.timestamp = Utc::now();
+        match result {
+            Ok(res) => {
+                delivery.status = Some(res.status().as_u16());
+                delivery.error = None;
+                delivery.delivered = res.status().is_success();
+            }
+            Err(v) => {
+                delivery.status = None;
+                delivery.error = Some(v.to_string());
+                delivery.delivered = false;
+            }
+        }
+        log_delivery(&deliveries, delivery.clone());
+
+        if delivery.delivered {
+            return;
+        }
+        tracing::debug! {url = %target.url, attempt, "webhook delivery failed"};
+        if attempt < MAX_ATTEMPTS {
+            sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    tracing::warn! {url = %target.url, payload = %delivery.payload_id, "giving up webhook delivery"};
+}
+
+fn log_delivery(deliveries: &Mutex<VecDeque<Delivery>>, delivery: Delivery) {
+    let mut deliveries = deliveries.lock().unwrap();
+    if deliveries.len() >= DELIVERY_LOG_SIZE {
+        deliveries.pop_front();
+    }
+    deliveries.push_back(delivery);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses() {
+        for ip in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{} is not public", ip);
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "172.32.0.1", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{} is public", ip);
+        }
+    }
+
+    #[tokio::test]
+    async fn user_webhooks_reach_no_local_address() {
+        for url in [
+            "ftp://example.com/",
+            "http://127.0.0.1:8080/hook",
+            "http://[::1]/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://localhost/hook",
+            "http:///hook",
+        ] {
+            assert!(check_public_url(url).await.is_err(), "{} is rejected", url);
+        }
+        assert!(check_public_url("https://93.184.216.34/hook").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn local_addresses_are_not_delivered_to() {
+        let deliveries = Arc::new(Mutex::new(VecDeque::new()));
+        let target = Target {
+            url: "http://127.0.0.1:9/hook".to_string(),
+            secret: "secret".to_string(),
+            public_only: true,
+        };
+        let delivery = Delivery {
+            payload_id: "p1".to_string(),
+            event: WebhookEvent::UserLogin,
+            user: "a@b.cd".to_string(),
+            url: target.url.clone(),
+            attempt: 0,
+            timestamp: Utc::now(),
+            status: None,
+            error: None,
+            delivered: false,
+        };
+        deliver(
+            reqwest::Client::new(),
+            deliveries.clone(),
+            target,
+            "{}".to_string(),
+            delivery,
+        )
+        .await;
+
+        let deliveries = deliveries.lock().unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert!(!deliveries[0].delivered);
+        assert!(deliveries[0].error.as_ref().unwrap().contains("not public"));
+    }
+
+    #[tokio::test]
+    async fn resolver_drops_local_addresses() {
+        let name = "localhost".parse().unwrap();
+        assert!(PublicResolver.resolve(name).await.is_err());
+    }
+}
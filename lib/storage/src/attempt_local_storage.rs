@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use config::Config;
+use std::{collections::BTreeMap, path::PathBuf};
+
+use crate::{
+    helper::{read_yaml, update_yaml},
+    AttemptStorage, LocalStorageError, Storage,
+};
+
+/// The failed login attempts for a single key, like an ip or an account.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Attempts {
+    pub failures: u32,
+    pub last_failure: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// Stores the failed login attempts in a yaml file in the data dir,
+/// so the cli can see and clear the lockouts of the running server.
+#[derive(Debug)]
+pub struct AttemptLocalStorage {
+    file: PathBuf,
+}
+
+type AllAttempts = BTreeMap<String, Attempts>;
+
+impl AttemptLocalStorage {
+    fn load(&self) -> Result<AllAttempts, LocalStorageError> {
+        read_yaml(&self.file)
+    }
+}
+
+impl Storage for AttemptLocalStorage {}
+impl AttemptStorage for AttemptLocalStorage {
+    fn create(config: &Config) -> Result<Box<Self>, LocalStorageError> {
+        let mut file = PathBuf::from(&config.api.data_dir);
+        file.push(".attempts.yaml");
+
+        Ok(Box::new(AttemptLocalStorage { file }))
+    }
+
+    fn get_attempts(&self, key: &str) -> Result<Option<Attempts>, LocalStorageError> {
+        Ok(self.load()?.remove(key))
+    }
+
+    fn store_attempts(&self, key: &str, attempts: &Attempts) -> Result<(), LocalStorageError> {
+        tracing::debug! {?self.file, "store login attempts in file"};
+        update_yaml(&self.file, |all: &mut AllAttempts| {
+            all.insert(key.to_string(), attempts.clone());
+            Ok(())
+        })
+    }
+
+    fn clear_attempts(&self, key: &str) -> Result<bool, LocalStorageError> {
+        update_yaml(&self.file, |all: &mut AllAttempts| {
+            Ok(all.remove(key).is_some())
+        })
+    }
+
+    fn list_attempts(&self) -> Result<Vec<(String, Attempts)>, LocalStorageError> {
+        Ok(self.load()?.into_iter().collect())
+    }
+
+    fn flush(&self) -> Result<(), LocalStorageError> {
+        // every change is written and synced under the lock right away
+        Ok(())
+    }
+}
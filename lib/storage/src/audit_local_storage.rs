@@ -0,0 +1,226 @@
+use chrono::{DateTime, Utc};
+use config::Config;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use crate::{AuditStorage, LocalStorageError, Storage};
+
+/// Where the action was triggered.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditSource {
+    Cli,
+    Api,
+    Ui,
+}
+
+impl AuditSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditSource::Cli => "cli",
+            AuditSource::Api => "api",
+            AuditSource::Ui => "ui",
+        }
+    }
+}
+
+impl FromStr for AuditSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "cli" => Ok(AuditSource::Cli),
+            "api" => Ok(AuditSource::Api),
+            "ui" => Ok(AuditSource::Ui),
+            _ => Err(format!("{} is not one of cli, api or ui", s)),
+        }
+    }
+}
+
+/// All actions, which are recorded in the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    #[serde(rename = "user.created")]
+    UserCreated,
+    #[serde(rename = "user.edited")]
+    UserEdited,
+    #[serde(rename = "user.deleted")]
+    UserDeleted,
+    #[serde(rename = "user.second_factor_reset")]
+    SecondFactorReset,
+    #[serde(rename = "code.created")]
+    CodeCreated,
+    #[serde(rename = "code.used")]
+    CodeUsed,
+    #[serde(rename = "login")]
+    Login,
+    #[serde(rename = "token.refreshed")]
+    TokenRefreshed,
+    #[serde(rename = "device.renamed")]
+    DeviceRenamed,
+    #[serde(rename = "device.revoked")]
+    DeviceRevoked,
+}
+
+impl AuditAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditAction::UserCreated => "user.created",
+            AuditAction::UserEdited => "user.edited",
+            AuditAction::UserDeleted => "user.deleted",
+            AuditAction::SecondFactorReset => "user.second_factor_reset",
+            AuditAction::CodeCreated => "code.created",
+            AuditAction::CodeUsed => "code.used",
+            AuditAction::Login => "login",
+            AuditAction::TokenRefreshed => "token.refreshed",
+            AuditAction::DeviceRenamed => "device.renamed",
+            AuditAction::DeviceRevoked => "device.revoked",
+        }
+    }
+}
+
+/// A single entry of the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEvent {
+    pub time: DateTime<Utc>,
+    pub source: AuditSource,
+    /// the email of the acting user, `cli:<os user>` for the cli or `anonymous`
+    pub actor: String,
+    pub action: AuditAction,
+    /// the user or device, which was acted on
+    pub target: Option<String>,
+    /// false for rejected attempts, like a wrong password or code
+    pub success: bool,
+    pub ip: Option<String>,
+}
+
+impl AuditEvent {
+    pub fn new(source: AuditSource, actor: &str, action: AuditAction) -> Self {
+        Self {
+            time: Utc::now(),
+            source,
+            actor: actor.to_string(),
+            action,
+            target: None,
+            success: true,
+            ip: None,
+        }
+    }
+
+    pub fn target(mut self, target: &str) -> Self {
+        self.target = Some(target.to_string());
+        self
+    }
+
+    pub fn ip(mut self, ip: Option<String>) -> Self {
+        self.ip = ip;
+        self
+    }
+
+    pub fn failed(mut self) -> Self {
+        self.success = false;
+        self
+    }
+}
+
+/// Selects events of the audit log. Empty fields match everything.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    /// matches the action name or its prefix, like `user.` or `user`
+    pub action: Option<String>,
+    pub source: Option<AuditSource>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// only the newest events are returned
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    pub fn matches(&self, event: &AuditEvent) -> bool {
+        let action = event.action.name();
+        self.actor.as_ref().is_none_or(|v| *v == event.actor)
+            && self
+                .target
+                .as_ref()
+                .is_none_or(|v| event.target.as_ref() == Some(v))
+            && self.action.as_ref().is_none_or(|v| {
+                action == v
+                    || action
+                        .strip_prefix(v.trim_end_matches('.'))
+                        .is_some_and(|rest| rest.starts_with('.'))
+            })
+            && self.source.is_none_or(|v| v == event.source)
+            && self.since.is_none_or(|v| event.time >= v)
+            && self.until.is_none_or(|v| event.time <= v)
+    }
+}
+
+/// Appends the events as json lines to a file in the data dir.
+/// Every event is written with a single append, so the cli and the server can write at the same time.
+#[derive(Debug)]
+pub struct AuditLocalStorage {
+    file: PathBuf,
+}
+
+impl Storage for AuditLocalStorage {}
+impl AuditStorage for AuditLocalStorage {
+    fn create(config: &Config) -> Result<Box<Self>, LocalStorageError> {
+        let mut file = PathBuf::from(&config.api.data_dir);
+        file.push(".audit.log");
+
+        Ok(Box::new(AuditLocalStorage { file }))
+    }
+
+    fn append(&self, event: &AuditEvent) -> Result<(), LocalStorageError> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.file)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, LocalStorageError> {
+        if !self.file.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut events = vec![];
+        for (number, line) in BufReader::new(File::open(&self.file)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<AuditEvent>(&line) {
+                Ok(event) if filter.matches(&event) => events.push(event),
+                Ok(_) => {}
+                Err(v) => tracing::warn! {?v, line = number + 1, "skip broken audit event"},
+            }
+        }
+
+        if let Some(limit) = filter.limit {
+            let skip = events.len().saturating_sub(limit);
+            events.drain(..skip);
+        }
+        Ok(events)
+    }
+
+    fn flush(&self) -> Result<(), LocalStorageError> {
+        // appends are not synced one by one, the os may still hold the last events
+        match File::open(&self.file) {
+            Ok(file) => Ok(file.sync_all()?),
+            Err(v) if v.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(v) => Err(v.into()),
+        }
+    }
+}
@@ -1,21 +1,27 @@
 use chrono::DateTime;
 use chrono::{Duration, Utc};
 
-use config::read_config;
-use rand::Rng;
-use std::{
-    collections::BTreeMap,
-    fs::{remove_file, File},
-    io::{Read, Write},
-    path::PathBuf,
+use config::{CodePolicy, Config};
+use rand::{rngs::OsRng, Rng};
+use std::{collections::BTreeMap, path::PathBuf};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    helper::{read_yaml, update_yaml, write_yaml, FileLock},
+    CodeStorage, EMail, LocalStorageError, Storage,
 };
 
-use crate::{CodeStorage, EMail, LocalStorageError, Storage};
-
-#[derive(Debug, serde::Serialize, serde::Deserialize, PartialEq)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 struct Code(String);
 
-#[derive(Debug, PartialEq, PartialOrd)]
+impl Code {
+    /// Compares in constant time, so the time of a failed login does not tell how much was right.
+    fn matches(&self, other: &str) -> bool {
+        self.0.as_bytes().ct_eq(other.as_bytes()).into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, PartialOrd)]
 struct ExpiresAt(DateTime<Utc>);
 
 use serde::de::{self, Visitor};
@@ -55,136 +61,298 @@ impl<'de> serde::Deserialize<'de> for ExpiresAt {
         deserializer.deserialize_str(ExpiresAtVisitor)
     }
 }
+type Codes = BTreeMap<String, Vec<(Code, ExpiresAt)>>;
+
 #[derive(Debug)]
 pub struct CodeLocalStorage {
     file: PathBuf,
-    codes: BTreeMap<String, Vec<(Code, ExpiresAt)>>,
+    policy: CodePolicy,
+    codes: Codes,
 }
 
 impl CodeLocalStorage {
     pub fn store_codes(&self) -> Result<(), LocalStorageError> {
-        // FIXME: This will overwrite changes, made by the server in the meantime of running the cli.
+        let _lock = FileLock::acquire(&self.file)?;
         tracing::debug! {?self.file,"store codes in file"};
-        let yaml = serde_yaml::to_string(&self.codes)?;
-
-        if self.file.exists() {
-            remove_file(&self.file)?;
-        }
-        let mut file = File::create(&self.file)?;
-        file.write_all(yaml.as_bytes())?;
+        write_yaml(&self.file, &self.codes)
+    }
 
+    /// Reads the codes again, so codes created or used by another process are seen.
+    fn load_codes(&mut self) -> Result<(), LocalStorageError> {
+        self.codes = read_yaml(&self.file)?;
         Ok(())
     }
 
-    fn load_codes(&mut self) -> Result<(), LocalStorageError> {
-        let mut file = File::open(&self.file)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
-        let val = serde_yaml::from_str(&contents)?;
-        Ok(val)
+    /// Changes the codes on the latest state of the file and stores them under the lock,
+    /// so codes created or used by another process in the meantime are kept.
+    fn update<R>(
+        &mut self,
+        change: impl FnOnce(&mut Codes) -> Result<R, LocalStorageError>,
+    ) -> Result<R, LocalStorageError> {
+        tracing::debug! {?self.file,"update codes in file"};
+        let (result, codes) = update_yaml(&self.file, |codes: &mut Codes| {
+            let result = change(codes)?;
+            Ok((result, codes.clone()))
+        })?;
+        self.codes = codes;
+        Ok(result)
+    }
+
+    fn generate(&self) -> String {
+        let alphabet = &self.policy.alphabet;
+        (0..self.policy.length)
+            .map(|_| alphabet[OsRng.gen_range(0..alphabet.len())])
+            .collect()
+    }
+}
+
+/// Checks the code against all codes of the user.
+fn validate(codes: &Codes, email: &EMail, validate_code: &str) -> Result<(), LocalStorageError> {
+    let codes = codes.get(&email.0).ok_or(LocalStorageError::UserNotFound)?;
+
+    // check all codes, so the position of the match is not visible in the timing
+    let expires = codes
+        .iter()
+        .fold(None, |found, (code, expires)| {
+            if code.matches(validate_code) {
+                Some(expires)
+            } else {
+                found
+            }
+        })
+        .ok_or(LocalStorageError::CodeNotValid)?;
+
+    (*expires >= ExpiresAt(Utc::now()))
+        .then(|| ())
+        .ok_or(LocalStorageError::CodeExpired)
+}
+
+fn remove(codes: &mut Codes, email: &EMail, code: &str) {
+    if let Some(codes) = codes.get_mut(&email.0) {
+        codes.retain(|(iter_code, _expire)| !iter_code.matches(code));
     }
 }
 
 impl Storage for CodeLocalStorage {}
 impl CodeStorage for CodeLocalStorage {
-    fn create(config_file: &std::path::PathBuf) -> Result<Box<Self>, crate::LocalStorageError> {
-        let config = read_config(config_file)?;
-
-        let mut file = PathBuf::from(config.api.data_dir);
+    fn create(config: &Config) -> Result<Box<Self>, LocalStorageError> {
+        let mut file = PathBuf::from(&config.api.data_dir);
         file.push(".codes.yaml");
 
-        let codes = match load_codes(&file) {
-            Ok(v) => v,
-            Err(_) => BTreeMap::new(),
-        };
+        let codes = read_yaml(&file).unwrap_or_default();
 
         let storage = CodeLocalStorage {
             file: file.clone(),
+            policy: config.api.codes.clone(),
             codes,
         };
 
         Ok(Box::new(storage))
     }
 
-    fn validate_code(&self, email: &EMail, validate_code: &str) -> Result<(), LocalStorageError> {
-        // FIXME: If cli creates codes, the running server does not recognize it, because they are using two different code_storages.
-        // This is bad, because every new code needs a server restart.
-        let validate_code = Code(validate_code.to_string());
-        let codes = self
-            .codes
-            .get(&email.0)
-            .ok_or(LocalStorageError::UserNotFound)?;
+    fn reload(&mut self) -> Result<(), LocalStorageError> {
+        self.load_codes()
+    }
+
+    fn flush(&self) -> Result<(), LocalStorageError> {
+        // every change is written and synced under the lock right away
+        Ok(())
+    }
 
-        let expires = &codes
-            .iter()
-            .filter(|(code, _)| validate_code == *code)
-            .next()
-            .ok_or(LocalStorageError::CodeNotValid)?
-            .1;
+    fn validate_code(
+        &mut self,
+        email: &EMail,
+        validate_code: &str,
+    ) -> Result<(), LocalStorageError> {
+        // the cli creates codes in its own process, so the file is the truth
+        self.load_codes()?;
+        validate(&self.codes, email, validate_code)
+    }
 
-        (*expires >= ExpiresAt(Utc::now()))
-            .then(|| ())
-            .ok_or(LocalStorageError::CodeExpired)
+    fn redeem_code(&mut self, email: &EMail, code: &str) -> Result<(), LocalStorageError> {
+        // validating and removing under one lock, so no other process uses the code in between
+        self.update(|codes| {
+            validate(codes, email, code)?;
+            remove(codes, email, code);
+            Ok(())
+        })
     }
 
     fn create_code(&mut self, email: &crate::EMail) -> Result<Box<String>, LocalStorageError> {
-        const CODE_SIZE: usize = 8;
-        let runes = "abcdefghijklmnopqrstuvwxyz".to_string();
-        let mut rng = rand::thread_rng();
-
-        let mut code = Vec::new();
+        let code = self.generate();
+        // the config bounds the ttl, but a policy of another source must not panic here
+        let expiration =
+            Duration::from_std(std::time::Duration::from_secs(self.policy.ttl_seconds))
+                .ok()
+                .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+                .ok_or(LocalStorageError::CodeTtlNotValid)?;
+        let max_per_user = self.policy.max_per_user;
+
+        self.update(|codes| {
+            let now = ExpiresAt(Utc::now());
+            let codes = codes.entry(email.0.to_string()).or_default();
+            codes.retain(|(_, expire)| *expire >= now);
+            if codes.len() >= max_per_user {
+                return Err(LocalStorageError::CodeLimitReached);
+            }
+            codes.push((Code(code.clone()), ExpiresAt(expiration)));
+            Ok(())
+        })?;
 
-        for _ in 0..CODE_SIZE {
-            let rn: usize = rng.gen_range(0..CODE_SIZE);
-            let val = &runes[rn..rn + 1];
-            code.push(val);
-        }
+        Ok(Box::new(code))
+    }
 
-        let code = code.join("").to_uppercase();
-        let expiration = Utc::now() + Duration::hours(3);
-        let val = (Code(code.clone()), ExpiresAt(expiration));
+    fn clean_codes(&mut self) -> Result<(), LocalStorageError> {
+        self.update(|codes| {
+            codes.retain(|_, v| {
+                v.retain(|(_, expire)| *expire >= ExpiresAt(Utc::now()));
+
+                v.len() > 0
+            });
+            Ok(())
+        })
+    }
 
-        self.codes
-            .entry(email.0.to_string())
-            .or_insert_with(|| vec![])
-            .push(val);
+    fn remove_code(&mut self, email: &EMail, code: &str) -> Result<(), LocalStorageError> {
+        self.update(|codes| {
+            remove(codes, email, code);
+            Ok(())
+        })
+    }
+}
 
-        self.store_codes()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(Box::new(code))
+    fn storage(dir: &tempfile::TempDir, policy: CodePolicy) -> CodeLocalStorage {
+        CodeLocalStorage {
+            file: dir.path().join(".codes.yaml"),
+            policy,
+            codes: Codes::new(),
+        }
     }
 
-    fn clean_codes(&mut self) -> Result<(), LocalStorageError> {
-        self.codes.retain(|_, v| {
-            v.retain(|(_, expire)| *expire < ExpiresAt(Utc::now()));
+    fn email() -> EMail {
+        EMail::create("user@example.com").unwrap()
+    }
 
-            v.len() > 0
-        });
+    #[test]
+    fn code_characters_are_uniform() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = CodePolicy {
+            length: 64,
+            alphabet: "ABCD".chars().collect(),
+            ..CodePolicy::default()
+        };
+        let storage = storage(&dir, policy);
+
+        let mut counts = BTreeMap::new();
+        let draws = 1000;
+        for _ in 0..draws {
+            let code = storage.generate();
+            assert_eq!(code.chars().count(), 64);
+            for c in code.chars() {
+                *counts.entry(c).or_insert(0usize) += 1;
+            }
+        }
+        // 16000 expected per character, 5% off is far beyond chance
+        assert_eq!(counts.keys().collect::<String>(), "ABCD");
+        let expected = draws * 64 / 4;
+        for (c, count) in counts {
+            assert!(
+                count.abs_diff(expected) < expected / 20,
+                "{} was drawn {} times",
+                c,
+                count
+            );
+        }
+    }
 
-        self.store_codes()?;
-        Ok(())
+    #[test]
+    fn code_is_redeemed_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(&dir, CodePolicy::default());
+        let code = storage.create_code(&email()).unwrap();
+
+        storage.validate_code(&email(), &code).unwrap();
+        storage.redeem_code(&email(), &code).unwrap();
+        assert!(matches!(
+            storage.redeem_code(&email(), &code),
+            Err(LocalStorageError::CodeNotValid)
+        ));
+        assert!(matches!(
+            storage.validate_code(&email(), &code),
+            Err(LocalStorageError::CodeNotValid)
+        ));
     }
 
-    fn remove_code(&mut self, email: &EMail, code: &str) -> Result<(), LocalStorageError> {
-        let codes = self
-            .codes
-            .entry(email.0.to_string())
-            .or_insert_with(|| vec![]);
+    #[test]
+    fn redeemed_code_is_gone_for_other_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut server = storage(&dir, CodePolicy::default());
+        let mut cli = storage(&dir, CodePolicy::default());
+        let code = cli.create_code(&email()).unwrap();
 
-        codes.retain(|(iter_code, _expire)| Code(code.to_string()) != *iter_code);
+        server.redeem_code(&email(), &code).unwrap();
+        assert!(cli.redeem_code(&email(), &code).is_err());
+    }
 
-        self.store_codes()?;
-        Ok(())
+    #[test]
+    fn wrong_and_expired_codes_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut storage = storage(&dir, CodePolicy::default());
+        assert!(matches!(
+            storage.redeem_code(&email(), "ABCDEFGH"),
+            Err(LocalStorageError::UserNotFound)
+        ));
+
+        let code = storage.create_code(&email()).unwrap();
+        assert!(matches!(
+            storage.redeem_code(&email(), &code[1..]),
+            Err(LocalStorageError::CodeNotValid)
+        ));
+
+        storage
+            .update(|codes| {
+                for (_, expires) in codes.values_mut().flatten() {
+                    *expires = ExpiresAt(Utc::now() - Duration::seconds(1));
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert!(matches!(
+            storage.redeem_code(&email(), &code),
+            Err(LocalStorageError::CodeExpired)
+        ));
     }
-}
 
-fn load_codes(
-    file: &PathBuf,
-) -> Result<BTreeMap<String, Vec<(Code, ExpiresAt)>>, LocalStorageError> {
-    let mut file = File::open(file)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+    #[test]
+    fn codes_per_user_are_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = CodePolicy {
+            max_per_user: 2,
+            ..CodePolicy::default()
+        };
+        let mut storage = storage(&dir, policy);
+        storage.create_code(&email()).unwrap();
+        storage.create_code(&email()).unwrap();
+        assert!(matches!(
+            storage.create_code(&email()),
+            Err(LocalStorageError::CodeLimitReached)
+        ));
+    }
 
-    let val = serde_yaml::from_str(&contents)?;
-    Ok(val)
+    #[test]
+    fn huge_ttl_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = CodePolicy {
+            ttl_seconds: u64::MAX,
+            ..CodePolicy::default()
+        };
+        assert!(matches!(
+            storage(&dir, policy).create_code(&email()),
+            Err(LocalStorageError::CodeTtlNotValid)
+        ));
+    }
 }
@@ -0,0 +1,15 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Represents a paired tablet of a user.
+/// The id is created new for every pairing and carried in the jwt of the device,
+/// so removing the device invalidates all its tokens.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    pub id: String,
+    pub description: String,
+    pub paired_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub firmware_version: Option<String>,
+}
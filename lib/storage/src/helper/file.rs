@@ -0,0 +1,79 @@
+use fs2::FileExt;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::LocalStorageError;
+
+/// An exclusive lock for a file, which is shared by all processes like the server and the cli.
+/// The lock is held on `<file>.lock`, because the file itself is replaced on every write.
+pub struct FileLock(File);
+
+impl FileLock {
+    pub fn acquire(file: &Path) -> io::Result<Self> {
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(with_suffix(file, ".lock"))?;
+        lock.lock_exclusive()?;
+        Ok(Self(lock))
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+fn with_suffix(file: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(file.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Writes a temporary file and renames it, so readers never see a half written file.
+pub fn write_atomic(file: &Path, contents: &[u8]) -> io::Result<()> {
+    let tmp = with_suffix(file, ".tmp");
+    let mut out = File::create(&tmp)?;
+    out.write_all(contents)?;
+    out.sync_all()?;
+    fs::rename(tmp, file)
+}
+
+/// Reads the yaml file, a missing file is taken as empty.
+pub fn read_yaml<T: DeserializeOwned + Default>(file: &Path) -> Result<T, LocalStorageError> {
+    let mut contents = String::new();
+    match File::open(file) {
+        Ok(mut f) => f.read_to_string(&mut contents)?,
+        Err(v) if v.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(v) => return Err(v.into()),
+    };
+    Ok(serde_yaml::from_str(&contents)?)
+}
+
+pub fn write_yaml<T: Serialize + ?Sized>(file: &Path, value: &T) -> Result<(), LocalStorageError> {
+    Ok(write_atomic(
+        file,
+        serde_yaml::to_string(value)?.as_bytes(),
+    )?)
+}
+
+/// Reads, changes and writes the yaml file under the lock, so changes of other
+/// processes in the meantime are not overwritten. Nothing is written on an error.
+pub fn update_yaml<T, R, F>(file: &Path, change: F) -> Result<R, LocalStorageError>
+where
+    T: Serialize + DeserializeOwned + Default,
+    F: FnOnce(&mut T) -> Result<R, LocalStorageError>,
+{
+    let _lock = FileLock::acquire(file)?;
+    let mut value = read_yaml(file)?;
+    let result = change(&mut value)?;
+    write_yaml(file, &value)?;
+    Ok(result)
+}
@@ -1,3 +1,5 @@
 mod email;
+mod file;
 
 pub use email::{validate_email, EMail, EMailError};
+pub(crate) use file::{read_yaml, update_yaml, write_atomic, write_yaml, FileLock};
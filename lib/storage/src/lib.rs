@@ -1,13 +1,31 @@
+mod attempt_local_storage;
+mod audit_local_storage;
 mod code_local_storage;
+mod device;
 mod helper;
 mod local_storage;
+mod registry;
+mod second_factor;
 mod storage;
 mod user_local_storage;
 mod userprofile;
+mod webhook;
 
+pub use attempt_local_storage::{AttemptLocalStorage, Attempts};
+pub use audit_local_storage::{
+    AuditAction, AuditEvent, AuditFilter, AuditLocalStorage, AuditSource,
+};
 pub use code_local_storage::CodeLocalStorage;
+pub use device::Device;
 pub use helper::{validate_email, EMail, EMailError};
-pub use local_storage::{CodeStorage, LocalStorageError, UserStorage};
+pub use local_storage::{
+    AttemptStorage, AuditStorage, CodeStorage, LocalStorageError, UserStorage,
+};
+pub use registry::{
+    AttemptBackend, AuditBackend, Backend, CodeBackend, Registry, Storages, UserBackend,
+};
+pub use second_factor::{SecondFactor, SecondFactorCheck};
 pub use storage::{Storage, StoragesError};
 pub use user_local_storage::UserLocalStorage;
-pub use userprofile::{UserFile, UserProfile};
+pub use userprofile::{Password, UserFile, UserProfile};
+pub use webhook::UserWebhook;
@@ -1,15 +1,19 @@
-use std::path::PathBuf;
+use config::Config;
 
 use crate::userprofile::UserProfileError;
+use crate::Attempts;
+use crate::Device;
+use crate::SecondFactor;
+use crate::SecondFactorCheck;
 use crate::Storage;
 use crate::UserFile;
+use crate::UserWebhook;
+use crate::{AuditEvent, AuditFilter};
 use crate::{EMail, EMailError};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum LocalStorageError {
-    #[error("ConfigParser error occurred")]
-    ConfigError(#[from] config::ConfigError),
     #[error("Io error occurred")]
     IoError(#[from] std::io::Error),
     #[error("Given user email not found")]
@@ -18,6 +22,8 @@ pub enum LocalStorageError {
     UserAlreadyExists,
     #[error("Yaml error occurred")]
     YamlError(#[from] serde_yaml::Error),
+    #[error("Json error occurred")]
+    JsonError(#[from] serde_json::Error),
     #[error("Yaml error occurred")]
     UserProfileError(#[from] UserProfileError),
     #[error("EMail error occurred")]
@@ -26,13 +32,27 @@ pub enum LocalStorageError {
     CodeNotValid,
     #[error("Code already expired")]
     CodeExpired,
+    #[error("Too many unused codes for this user")]
+    CodeLimitReached,
+    #[error("Code expiration is out of range")]
+    CodeTtlNotValid,
+    #[error("Given device not found")]
+    DeviceNotFound,
+    #[error("Given webhook not found")]
+    WebhookNotFound,
 }
 
 pub trait UserStorage: Storage + Send + Sync + 'static + std::fmt::Debug {
-    fn create(config_file: &PathBuf) -> Result<Box<Self>, LocalStorageError>
+    fn create(config: &Config) -> Result<Box<Self>, LocalStorageError>
     where
         Self: Sized;
+    /// Reads the users again, after another process like the cli changed them.
+    fn reload(&mut self) -> Result<(), LocalStorageError>;
+    /// Writes everything, which is still buffered, before the server exits.
+    fn flush(&self) -> Result<(), LocalStorageError>;
     fn get_user(&self, email: &EMail) -> Result<Box<dyn UserFile>, LocalStorageError>;
+    /// All users, ordered by email.
+    fn list_users(&self) -> Result<Vec<Box<dyn UserFile>>, LocalStorageError>;
     fn delete_user(&self, email: &EMail) -> Result<(), LocalStorageError>;
     fn create_user(
         &self,
@@ -49,14 +69,94 @@ pub trait UserStorage: Storage + Send + Sync + 'static + std::fmt::Debug {
         is_admin: &bool,
         sync15: &bool,
     ) -> Result<(), LocalStorageError>;
+
+    /// Checks the password of the user. A plaintext password is upgraded to a hash on success.
+    fn verify_password(&self, email: &EMail, password: &str) -> Result<bool, LocalStorageError>;
+    /// Changes only the admin flag, the password and everything else stays.
+    fn set_admin(&self, email: &EMail, is_admin: bool) -> Result<(), LocalStorageError>;
+
+    fn get_devices(&self, email: &EMail) -> Result<Vec<Device>, LocalStorageError>;
+    fn add_device(&self, email: &EMail, device: Device) -> Result<(), LocalStorageError>;
+    /// Updates last-seen and ip of the device.
+    fn touch_device(
+        &self,
+        email: &EMail,
+        id: &str,
+        ip: Option<String>,
+    ) -> Result<(), LocalStorageError>;
+    /// Changes the description, which the device lists show.
+    fn rename_device(
+        &self,
+        email: &EMail,
+        id: &str,
+        description: &str,
+    ) -> Result<(), LocalStorageError>;
+    /// Removes the device, so all of its tokens are not valid anymore.
+    fn revoke_device(&self, email: &EMail, id: &str) -> Result<(), LocalStorageError>;
+
+    fn get_second_factor(&self, email: &EMail) -> Result<Option<SecondFactor>, LocalStorageError>;
+    fn store_second_factor(
+        &self,
+        email: &EMail,
+        second_factor: &SecondFactor,
+    ) -> Result<(), LocalStorageError>;
+    /// Checks the code and stores the used step or recovery code under one lock, so
+    /// concurrent requests cannot use a code twice. With `confirm`, an unconfirmed second
+    /// factor is confirmed by a code of the authenticator app. Without, a confirmed one
+    /// accepts a code of the app or a recovery code.
+    fn verify_second_factor(
+        &self,
+        email: &EMail,
+        code: &str,
+        confirm: bool,
+    ) -> Result<SecondFactorCheck, LocalStorageError>;
+    /// Removes the second factor, so the user can enrol again. Returns false, if there was none.
+    fn reset_second_factor(&self, email: &EMail) -> Result<bool, LocalStorageError>;
+
+    fn get_webhooks(&self, email: &EMail) -> Result<Vec<UserWebhook>, LocalStorageError>;
+    fn add_webhook(&self, email: &EMail, webhook: UserWebhook) -> Result<(), LocalStorageError>;
+    fn remove_webhook(&self, email: &EMail, id: &str) -> Result<(), LocalStorageError>;
 }
 
 pub trait CodeStorage: Storage + Send + Sync + 'static + std::fmt::Debug {
-    fn create(config_file: &PathBuf) -> Result<Box<Self>, LocalStorageError>
+    fn create(config: &Config) -> Result<Box<Self>, LocalStorageError>
     where
         Self: Sized;
-    fn validate_code(&self, email: &EMail, code: &str) -> Result<(), LocalStorageError>;
+    /// Reads the codes again, after another process like the cli changed them.
+    fn reload(&mut self) -> Result<(), LocalStorageError>;
+    /// Checks the code without using it.
+    fn validate_code(&mut self, email: &EMail, code: &str) -> Result<(), LocalStorageError>;
+    /// Validates and removes the code, so every code can be used only once.
+    fn redeem_code(&mut self, email: &EMail, code: &str) -> Result<(), LocalStorageError>;
     fn create_code(&mut self, email: &EMail) -> Result<Box<String>, LocalStorageError>;
     fn remove_code(&mut self, email: &EMail, code: &str) -> Result<(), LocalStorageError>;
     fn clean_codes(&mut self) -> Result<(), LocalStorageError>;
+    /// Writes everything, which is still buffered, before the server exits.
+    fn flush(&self) -> Result<(), LocalStorageError>;
+}
+
+/// Counts failed logins per key, so the server can lock out brute-force attempts.
+pub trait AttemptStorage: Storage + Send + Sync + 'static + std::fmt::Debug {
+    fn create(config: &Config) -> Result<Box<Self>, LocalStorageError>
+    where
+        Self: Sized;
+    fn get_attempts(&self, key: &str) -> Result<Option<Attempts>, LocalStorageError>;
+    fn store_attempts(&self, key: &str, attempts: &Attempts) -> Result<(), LocalStorageError>;
+    /// Removes the attempts of the key. Returns false, if there were none.
+    fn clear_attempts(&self, key: &str) -> Result<bool, LocalStorageError>;
+    fn list_attempts(&self) -> Result<Vec<(String, Attempts)>, LocalStorageError>;
+    /// Writes everything, which is still buffered, before the server exits.
+    fn flush(&self) -> Result<(), LocalStorageError>;
+}
+
+/// An append-only log of the actions, which changed users, codes, devices or documents.
+pub trait AuditStorage: Storage + Send + Sync + 'static + std::fmt::Debug {
+    fn create(config: &Config) -> Result<Box<Self>, LocalStorageError>
+    where
+        Self: Sized;
+    fn append(&self, event: &AuditEvent) -> Result<(), LocalStorageError>;
+    /// Returns the matching events, oldest first.
+    fn query(&self, filter: &AuditFilter) -> Result<Vec<AuditEvent>, LocalStorageError>;
+    /// Writes everything, which is still buffered, before the server exits.
+    fn flush(&self) -> Result<(), LocalStorageError>;
 }
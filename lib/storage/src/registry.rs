@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+
+use config::Config;
+use toml::Value;
+
+use crate::{
+    AttemptLocalStorage, AttemptStorage, AuditLocalStorage, AuditStorage, CodeLocalStorage,
+    CodeStorage, LocalStorageError, StoragesError, UserLocalStorage, UserStorage,
+};
+
+/// What a backend gets to create its storage.
+pub struct Backend<'a> {
+    pub config: &'a Config,
+    /// the table `[STORAGE.<NAME>]` of the backend, if there is one
+    pub options: Option<&'a Value>,
+}
+
+pub type UserBackend = fn(&Backend) -> Result<Box<dyn UserStorage>, LocalStorageError>;
+pub type CodeBackend = fn(&Backend) -> Result<Box<dyn CodeStorage>, LocalStorageError>;
+pub type AttemptBackend = fn(&Backend) -> Result<Box<dyn AttemptStorage>, LocalStorageError>;
+pub type AuditBackend = fn(&Backend) -> Result<Box<dyn AuditStorage>, LocalStorageError>;
+
+/// The storages, as they are configured in `[STORAGE]`.
+pub struct Storages {
+    pub user: Box<dyn UserStorage>,
+    pub code: Box<dyn CodeStorage>,
+    pub attempt: Box<dyn AttemptStorage>,
+    pub audit: Box<dyn AuditStorage>,
+}
+
+/// The backends by the name, which `[STORAGE]` refers to. `Default` has the yaml files as "local".
+#[derive(Clone)]
+pub struct Registry {
+    users: BTreeMap<String, UserBackend>,
+    codes: BTreeMap<String, CodeBackend>,
+    attempts: BTreeMap<String, AttemptBackend>,
+    audit: BTreeMap<String, AuditBackend>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry
+            .register_users("local", |b| Ok(UserLocalStorage::create(b.config)?))
+            .register_codes("local", |b| Ok(CodeLocalStorage::create(b.config)?))
+            .register_attempts("local", |b| Ok(AttemptLocalStorage::create(b.config)?))
+            .register_audit("local", |b| Ok(AuditLocalStorage::create(b.config)?));
+        registry
+    }
+}
+
+impl Registry {
+    pub fn empty() -> Self {
+        Self {
+            users: BTreeMap::new(),
+            codes: BTreeMap::new(),
+            attempts: BTreeMap::new(),
+            audit: BTreeMap::new(),
+        }
+    }
+
+    /// Names are case insensitive, a backend with the same name is replaced.
+    pub fn register_users(&mut self, name: &str, backend: UserBackend) -> &mut Self {
+        self.users.insert(name.to_lowercase(), backend);
+        self
+    }
+
+    pub fn register_codes(&mut self, name: &str, backend: CodeBackend) -> &mut Self {
+        self.codes.insert(name.to_lowercase(), backend);
+        self
+    }
+
+    pub fn register_attempts(&mut self, name: &str, backend: AttemptBackend) -> &mut Self {
+        self.attempts.insert(name.to_lowercase(), backend);
+        self
+    }
+
+    pub fn register_audit(&mut self, name: &str, backend: AuditBackend) -> &mut Self {
+        self.audit.insert(name.to_lowercase(), backend);
+        self
+    }
+
+    /// Creates every storage with the backend, which the config chooses.
+    pub fn build(&self, config: &Config) -> Result<Storages, StoragesError> {
+        let storage = &config.storage;
+        let backend = |name: &str| Backend {
+            config,
+            options: storage.options.get(name),
+        };
+
+        // all names are checked, before any storage is created
+        let user = lookup(&self.users, "users", &storage.users)?;
+        let code = lookup(&self.codes, "codes", &storage.codes)?;
+        let attempt = lookup(&self.attempts, "attempts", &storage.attempts)?;
+        let audit = lookup(&self.audit, "audit", &storage.audit)?;
+
+        Ok(Storages {
+            user: user(&backend(&storage.users))?,
+            code: code(&backend(&storage.codes))?,
+            attempt: attempt(&backend(&storage.attempts))?,
+            audit: audit(&backend(&storage.audit))?,
+        })
+    }
+}
+
+fn lookup<T: Copy>(
+    backends: &BTreeMap<String, T>,
+    storage: &'static str,
+    name: &str,
+) -> Result<T, StoragesError> {
+    backends
+        .get(name)
+        .copied()
+        .ok_or_else(|| StoragesError::UnknownBackend(storage, name.to_string()))
+}
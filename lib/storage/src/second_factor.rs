@@ -0,0 +1,231 @@
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::Password;
+
+const SECRET_SIZE: usize = 20;
+const DIGITS: usize = 6;
+const STEP_SECONDS: u64 = 30;
+/// Codes of the step before and after the current one are accepted, to allow for clock drift.
+const SKEW_STEPS: u64 = 1;
+const RECOVERY_CODES: usize = 10;
+const RECOVERY_CODE_SIZE: usize = 10;
+
+/// The outcome of [`crate::UserStorage::verify_second_factor`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecondFactorCheck {
+    /// there is no second factor, or it is not in the state for this check
+    Missing,
+    Wrong,
+    Totp,
+    RecoveryCode {
+        left: usize,
+    },
+}
+
+/// The TOTP second factor of a user with its recovery codes.
+/// Only hashes of the recovery codes are stored, they are shown once on enrolment.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SecondFactor {
+    /// base32 encoded
+    secret: String,
+    /// false until the user proved, that the authenticator app works
+    pub confirmed: bool,
+    recovery_codes: Vec<String>,
+    /// the last step, which was used, so a code cannot be used twice
+    last_step: Option<u64>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl std::fmt::Debug for SecondFactor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SecondFactor")
+            .field("secret", &"<redacted>")
+            .field("confirmed", &self.confirmed)
+            .field("recovery_codes", &self.recovery_codes.len())
+            .field("last_step", &self.last_step)
+            .field("created_at", &self.created_at)
+            .finish()
+    }
+}
+
+impl SecondFactor {
+    /// Creates an unconfirmed second factor. Returns it with the plaintext recovery codes.
+    pub fn generate() -> (Self, Vec<String>) {
+        let mut secret = [0u8; SECRET_SIZE];
+        OsRng.fill_bytes(&mut secret);
+
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| {
+                let code: String = OsRng
+                    .sample_iter(&Alphanumeric)
+                    .take(RECOVERY_CODE_SIZE)
+                    .map(|c| char::from(c).to_ascii_lowercase())
+                    .collect();
+                format!("{}-{}", &code[..5], &code[5..])
+            })
+            .collect();
+
+        let second_factor = Self {
+            secret: Secret::Raw(secret.to_vec()).to_encoded().to_string(),
+            confirmed: false,
+            recovery_codes: codes
+                .iter()
+                .map(|c| match Password::hash(c) {
+                    Password::Hash(hash) | Password::Plaintext(hash) => hash,
+                })
+                .collect(),
+            last_step: None,
+            created_at: Utc::now(),
+        };
+        (second_factor, codes)
+    }
+
+    /// Issuer and account are only used for the provisioning uri.
+    fn totp(&self, issuer: Option<&str>, account: &str) -> Option<TOTP> {
+        let secret = Secret::Encoded(self.secret.clone()).to_bytes().ok()?;
+        TOTP::new(
+            Algorithm::SHA1,
+            DIGITS,
+            SKEW_STEPS as u8,
+            STEP_SECONDS,
+            secret,
+            issuer.map(|i| i.replace(':', "")),
+            account.replace(':', ""),
+        )
+        .ok()
+    }
+
+    /// The `otpauth://` uri for authenticator apps, usually shown as QR code.
+    pub fn provisioning_uri(&self, issuer: &str, account: &str) -> Option<String> {
+        Some(self.totp(Some(issuer), account)?.get_url())
+    }
+
+    /// Checks the code for the current time. A used code is not accepted again.
+    /// Returns true, if the code was valid. The second factor has to be stored afterwards.
+    pub fn verify(&mut self, code: &str) -> bool {
+        self.verify_at(code, Utc::now().timestamp().max(0) as u64)
+    }
+
+    fn verify_at(&mut self, code: &str, now: u64) -> bool {
+        let totp = match self.totp(None, "") {
+            Some(v) => v,
+            None => return false,
+        };
+        let current = now / STEP_SECONDS;
+
+        let matched = (current.saturating_sub(SKEW_STEPS)..=current + SKEW_STEPS)
+            .filter(|step| self.last_step.is_none_or(|last| *step > last))
+            .find(|step| {
+                let expected = totp.generate(step * STEP_SECONDS);
+                bool::from(expected.as_bytes().ct_eq(code.trim().as_bytes()))
+            });
+
+        match matched {
+            Some(step) => {
+                self.last_step = Some(step);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Checks the recovery code and removes it, so every code works once.
+    /// The second factor has to be stored afterwards.
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let code = code.trim().to_ascii_lowercase();
+        let position = self
+            .recovery_codes
+            .iter()
+            .position(|hash| Password::Hash(hash.clone()).verify(&code));
+
+        match position {
+            Some(index) => {
+                self.recovery_codes.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn recovery_codes_left(&self) -> usize {
+        self.recovery_codes.len()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn current_code(&self) -> String {
+        self.totp(None, "")
+            .unwrap()
+            .generate(Utc::now().timestamp() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A time in the middle of a step.
+    const NOW: u64 = 1_700_000_000 / STEP_SECONDS * STEP_SECONDS + 15;
+
+    fn code(second_factor: &SecondFactor, time: u64) -> String {
+        second_factor.totp(None, "").unwrap().generate(time)
+    }
+
+    #[test]
+    fn codes_of_neighbouring_steps_are_accepted() {
+        let (second_factor, _) = SecondFactor::generate();
+        for offset in [-30i64, 0, 30] {
+            let mut second_factor = second_factor.clone();
+            let time = NOW.checked_add_signed(offset).unwrap();
+            assert!(second_factor.verify_at(&code(&second_factor, time), NOW));
+        }
+        for offset in [-60i64, 60] {
+            let mut second_factor = second_factor.clone();
+            let time = NOW.checked_add_signed(offset).unwrap();
+            assert!(!second_factor.verify_at(&code(&second_factor, time), NOW));
+        }
+    }
+
+    #[test]
+    fn code_is_not_accepted_twice() {
+        let (mut second_factor, _) = SecondFactor::generate();
+        let current = code(&second_factor, NOW);
+        assert!(second_factor.verify_at(&current, NOW));
+        assert!(!second_factor.verify_at(&current, NOW));
+        assert!(!second_factor.verify_at(&current, NOW + STEP_SECONDS));
+    }
+
+    #[test]
+    fn older_steps_are_not_accepted_after_a_newer_one() {
+        let (mut second_factor, _) = SecondFactor::generate();
+        let previous = code(&second_factor, NOW - STEP_SECONDS);
+        assert!(second_factor.verify_at(&code(&second_factor, NOW), NOW));
+        assert!(!second_factor.verify_at(&previous, NOW));
+        // the next step still works
+        let next = code(&second_factor, NOW + STEP_SECONDS);
+        assert!(second_factor.verify_at(&next, NOW + STEP_SECONDS));
+    }
+
+    #[test]
+    fn wrong_codes_are_rejected() {
+        let (mut second_factor, _) = SecondFactor::generate();
+        assert!(!second_factor.verify_at("", NOW));
+        assert!(!second_factor.verify_at("12345", NOW));
+        let current = code(&second_factor, NOW);
+        assert!(!second_factor.verify_at(&format!("{}0", current), NOW));
+        assert!(second_factor.verify_at(&format!(" {} ", current), NOW));
+    }
+
+    #[test]
+    fn recovery_code_works_once() {
+        let (mut second_factor, codes) = SecondFactor::generate();
+        assert_eq!(second_factor.recovery_codes_left(), RECOVERY_CODES);
+        assert!(second_factor.use_recovery_code(&codes[3].to_ascii_uppercase()));
+        assert!(!second_factor.use_recovery_code(&codes[3]));
+        assert_eq!(second_factor.recovery_codes_left(), RECOVERY_CODES - 1);
+        assert!(!second_factor.use_recovery_code("aaaaa-aaaaa"));
+    }
+}
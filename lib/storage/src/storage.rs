@@ -8,4 +8,6 @@ pub trait Storage {}
 pub enum StoragesError {
     #[error("Error occurred in LocalStorage")]
     LocalStorageError(#[from] LocalStorageError),
+    #[error("No backend `{1}` is registered for the {0} storage")]
+    UnknownBackend(&'static str, String),
 }
@@ -1,12 +1,18 @@
-use config::read_config;
+use config::Config;
 use serde_yaml::Value;
 use std::{
-    fs::{create_dir_all, remove_dir_all, remove_file, File},
-    io::{Read, Write},
+    fs::{create_dir_all, read_dir, remove_dir_all, remove_file, File},
+    io::Read,
     path::PathBuf,
 };
 
-use crate::{local_storage::LocalStorageError, EMail, Storage, UserFile, UserProfile, UserStorage};
+use crate::{
+    helper::{read_yaml, update_yaml, write_atomic, write_yaml, FileLock},
+    local_storage::LocalStorageError,
+    Device, EMail, Password, SecondFactor, SecondFactorCheck, Storage, UserFile, UserProfile,
+    UserStorage, UserWebhook,
+};
+use chrono::{DateTime, Utc};
 
 #[derive(Debug)]
 pub struct UserLocalStorage {
@@ -22,29 +28,175 @@ fn get_user_profile(dir: PathBuf, email: &EMail) -> PathBuf {
     dir.push(".userprofile");
     dir
 }
+fn get_user_webhooks(dir: PathBuf, email: &EMail) -> PathBuf {
+    let mut dir = get_user_folder(dir, email);
+    dir.push(".webhooks.yaml");
+    dir
+}
+fn get_user_second_factor(dir: PathBuf, email: &EMail) -> PathBuf {
+    let mut dir = get_user_folder(dir, email);
+    dir.push(".totp.yaml");
+    dir
+}
+
+fn get_user_devices(dir: PathBuf, email: &EMail) -> PathBuf {
+    let mut dir = get_user_folder(dir, email);
+    dir.push(".devices.yaml");
+    dir
+}
+
+impl UserLocalStorage {
+    fn load_profile(&self, email: &EMail) -> Result<UserProfile, LocalStorageError> {
+        let userprofile = get_user_profile(self.dir.clone(), email);
+        tracing::debug! {?userprofile,"get user profile"};
+
+        let mut file = File::open(userprofile)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        let val: Value = serde_yaml::from_str(&contents)?;
+        Ok(UserProfile::from_yaml(val)?)
+    }
+
+    fn store_profile(&self, email: &EMail, profile: &UserProfile) -> Result<(), LocalStorageError> {
+        let file = get_user_profile(self.dir.clone(), email);
+        Ok(write_atomic(&file, profile.to_yaml().as_bytes())?)
+    }
+
+    /// Changes the devices on the latest state of the file, so changes of the cli are kept.
+    fn update_devices<R>(
+        &self,
+        email: &EMail,
+        change: impl FnOnce(&mut Vec<Device>) -> Result<R, LocalStorageError>,
+    ) -> Result<R, LocalStorageError> {
+        if !get_user_folder(self.dir.clone(), email).exists() {
+            return Err(LocalStorageError::UserNotFound);
+        }
+
+        let file = get_user_devices(self.dir.clone(), email);
+        tracing::debug! {?file, "store devices"};
+        update_yaml(&file, change)
+    }
+
+    /// Changes the webhooks on the latest state of the file, so changes of the cli are kept.
+    fn update_webhooks<R>(
+        &self,
+        email: &EMail,
+        change: impl FnOnce(&mut Vec<UserWebhook>) -> Result<R, LocalStorageError>,
+    ) -> Result<R, LocalStorageError> {
+        if !get_user_folder(self.dir.clone(), email).exists() {
+            return Err(LocalStorageError::UserNotFound);
+        }
+
+        let file = get_user_webhooks(self.dir.clone(), email);
+        tracing::debug! {?file, "store webhooks"};
+        update_yaml(&file, change)
+    }
+}
 
 impl Storage for UserLocalStorage {}
 impl UserStorage for UserLocalStorage {
-    fn create(config_file: &PathBuf) -> Result<Box<Self>, LocalStorageError> {
-        let config = read_config(config_file)?;
-
+    fn create(config: &Config) -> Result<Box<Self>, LocalStorageError> {
         let storage = UserLocalStorage {
-            dir: PathBuf::from(config.api.data_dir),
+            dir: PathBuf::from(&config.api.data_dir),
         };
 
         Ok(Box::new(storage))
     }
 
+    fn reload(&mut self) -> Result<(), LocalStorageError> {
+        // every call reads the files, so there is nothing cached
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), LocalStorageError> {
+        // every change is written and synced right away
+        Ok(())
+    }
+
     fn get_user(&self, email: &EMail) -> Result<Box<dyn UserFile>, LocalStorageError> {
-        let userprofile = get_user_profile(self.dir.clone(), &email);
-        tracing::debug! {?userprofile,"get user profile"};
+        Ok(Box::new(self.load_profile(email)?))
+    }
 
-        let mut file = File::open(userprofile)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
+    fn list_users(&self) -> Result<Vec<Box<dyn UserFile>>, LocalStorageError> {
+        let mut users: Vec<Box<dyn UserFile>> = vec![];
+        if !self.dir.exists() {
+            return Ok(users);
+        }
 
-        let val: Value = serde_yaml::from_str(&contents)?;
-        Ok(Box::new(UserProfile::from_yaml(val)?))
+        for entry in read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // .codes.yaml, .attempts.yaml, .audit.log and their .lock and .tmp files
+            if name.starts_with('.') || !entry.file_type()?.is_dir() {
+                continue;
+            }
+            let email = match EMail::create(&name) {
+                Ok(v) => v,
+                Err(_) => {
+                    tracing::debug! {?name, "skip folder, which is no email"};
+                    continue;
+                }
+            };
+            if !get_user_profile(self.dir.clone(), &email).exists() {
+                tracing::debug! {?name, "skip folder without user profile"};
+                continue;
+            }
+
+            let mut profile = match self.load_profile(&email) {
+                Ok(v) => v,
+                Err(v) => {
+                    tracing::warn! {?v, ?email, "skip user, the profile cannot be read"};
+                    continue;
+                }
+            };
+            // users of older versions have no created, the birth of their folder is close
+            if profile.created.is_none() {
+                profile.created = entry
+                    .metadata()
+                    .and_then(|v| v.created())
+                    .ok()
+                    .map(DateTime::<Utc>::from);
+            }
+            users.push(Box::new(profile));
+        }
+
+        users.sort_by_key(|v| v.get_email());
+        Ok(users)
+    }
+
+    fn verify_password(&self, email: &EMail, password: &str) -> Result<bool, LocalStorageError> {
+        let profile = match self.load_profile(email) {
+            Ok(v) => v,
+            Err(v) => {
+                Password::verify_dummy(password);
+                return Err(v);
+            }
+        };
+        if !profile.verify_password(password) {
+            return Ok(false);
+        }
+
+        // the profile is read again under the lock, so an edit in the meantime is not lost
+        let _lock = FileLock::acquire(&get_user_profile(self.dir.clone(), email))?;
+        let mut profile = self.load_profile(email)?;
+        if profile.upgrade_password(password) {
+            tracing::debug! {?email, "upgrade plaintext password to hash"};
+            self.store_profile(email, &profile)?;
+        }
+        Ok(true)
+    }
+
+    fn set_admin(&self, email: &EMail, is_admin: bool) -> Result<(), LocalStorageError> {
+        let _lock = FileLock::acquire(&get_user_profile(self.dir.clone(), email))?;
+        let mut profile = self.load_profile(email)?;
+        if profile.is_admin == is_admin {
+            return Ok(());
+        }
+
+        tracing::debug! {?email, is_admin, "change admin flag of user"};
+        profile.is_admin = is_admin;
+        self.store_profile(email, &profile)
     }
 
     fn create_user(
@@ -57,7 +209,7 @@ impl UserStorage for UserLocalStorage {
         tracing::debug! {?email,"Try to create new user"};
 
         let user = UserProfile::new(email.clone(), password.to_string(), *is_admin, *sync15);
-        let folder = get_user_folder(self.dir.clone(), &email);
+        let folder = get_user_folder(self.dir.clone(), email);
 
         if !folder.exists() {
             tracing::debug! {?folder,"Folder for user or parents not exists"};
@@ -65,9 +217,9 @@ impl UserStorage for UserLocalStorage {
         }
 
         let profile = get_user_profile(self.dir.clone(), email);
+        let _lock = FileLock::acquire(&profile)?;
         if !profile.exists() {
-            let mut file = File::create(profile)?;
-            file.write_all(user.to_yaml().as_bytes())?;
+            self.store_profile(email, &user)?;
             println!("User created");
             Ok(Box::new(user))
         } else {
@@ -76,7 +228,7 @@ impl UserStorage for UserLocalStorage {
     }
 
     fn delete_user(&self, email: &EMail) -> Result<(), LocalStorageError> {
-        let folder = get_user_folder(self.dir.clone(), &email);
+        let folder = get_user_folder(self.dir.clone(), email);
         tracing::debug! {?folder, "delete user"};
         remove_dir_all(folder)?;
         println!("User removed");
@@ -90,12 +242,277 @@ impl UserStorage for UserLocalStorage {
         is_admin: &bool,
         sync15: &bool,
     ) -> Result<(), LocalStorageError> {
-        let userprofile = get_user_profile(self.dir.clone(), &email);
+        let userprofile = get_user_profile(self.dir.clone(), email);
         tracing::debug! {?userprofile, "edit user"};
-        remove_file(userprofile)?;
-        self.create_user(email, password, is_admin, sync15)?;
+        if !userprofile.exists() {
+            return Err(LocalStorageError::UserNotFound);
+        }
+        // replaced in one step, so the server never sees the user missing
+        let _lock = FileLock::acquire(&userprofile)?;
+        let mut user = UserProfile::new(email.clone(), password.to_string(), *is_admin, *sync15);
+        // an edit does not make the user new, older profiles stay without
+        user.created = self.load_profile(email).ok().and_then(|v| v.created);
+        self.store_profile(email, &user)?;
 
         println!("User edited");
         Ok(())
     }
+
+    fn get_devices(&self, email: &EMail) -> Result<Vec<Device>, LocalStorageError> {
+        read_yaml(&get_user_devices(self.dir.clone(), email))
+    }
+
+    fn add_device(&self, email: &EMail, device: Device) -> Result<(), LocalStorageError> {
+        self.update_devices(email, |devices| {
+            devices.push(device);
+            Ok(())
+        })
+    }
+
+    fn touch_device(
+        &self,
+        email: &EMail,
+        id: &str,
+        ip: Option<String>,
+    ) -> Result<(), LocalStorageError> {
+        self.update_devices(email, |devices| {
+            let device = devices
+                .iter_mut()
+                .find(|d| d.id == id)
+                .ok_or(LocalStorageError::DeviceNotFound)?;
+            device.last_seen = Utc::now();
+            if ip.is_some() {
+                device.ip = ip;
+            }
+            Ok(())
+        })
+    }
+
+    fn rename_device(
+        &self,
+        email: &EMail,
+        id: &str,
+        description: &str,
+    ) -> Result<(), LocalStorageError> {
+        self.update_devices(email, |devices| {
+            let device = devices
+                .iter_mut()
+                .find(|d| d.id == id)
+                .ok_or(LocalStorageError::DeviceNotFound)?;
+            tracing::debug! {?email, %id, %description, "rename device"};
+            device.description = description.to_string();
+            Ok(())
+        })
+    }
+
+    fn revoke_device(&self, email: &EMail, id: &str) -> Result<(), LocalStorageError> {
+        self.update_devices(email, |devices| {
+            let len = devices.len();
+            devices.retain(|d| d.id != id);
+            if devices.len() == len {
+                return Err(LocalStorageError::DeviceNotFound);
+            }
+            tracing::debug! {?email, %id, "revoke device"};
+            Ok(())
+        })
+    }
+
+    fn get_webhooks(&self, email: &EMail) -> Result<Vec<UserWebhook>, LocalStorageError> {
+        read_yaml(&get_user_webhooks(self.dir.clone(), email))
+    }
+
+    fn add_webhook(&self, email: &EMail, webhook: UserWebhook) -> Result<(), LocalStorageError> {
+        self.update_webhooks(email, |webhooks| {
+            webhooks.push(webhook);
+            Ok(())
+        })
+    }
+
+    fn remove_webhook(&self, email: &EMail, id: &str) -> Result<(), LocalStorageError> {
+        self.update_webhooks(email, |webhooks| {
+            let len = webhooks.len();
+            webhooks.retain(|w| w.id != id);
+            if webhooks.len() == len {
+                return Err(LocalStorageError::WebhookNotFound);
+            }
+            tracing::debug! {?email, %id, "remove webhook"};
+            Ok(())
+        })
+    }
+    fn get_second_factor(&self, email: &EMail) -> Result<Option<SecondFactor>, LocalStorageError> {
+        let file = get_user_second_factor(self.dir.clone(), email);
+        if !file.exists() {
+            return Ok(None);
+        }
+
+        let mut file = File::open(file)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        Ok(Some(serde_yaml::from_str(&contents)?))
+    }
+
+    fn store_second_factor(
+        &self,
+        email: &EMail,
+        second_factor: &SecondFactor,
+    ) -> Result<(), LocalStorageError> {
+        if !get_user_folder(self.dir.clone(), email).exists() {
+            return Err(LocalStorageError::UserNotFound);
+        }
+
+        let file = get_user_second_factor(self.dir.clone(), email);
+        tracing::debug! {?file, "store second factor"};
+        let _lock = FileLock::acquire(&file)?;
+        write_yaml(&file, second_factor)
+    }
+
+    fn verify_second_factor(
+        &self,
+        email: &EMail,
+        code: &str,
+        confirm: bool,
+    ) -> Result<SecondFactorCheck, LocalStorageError> {
+        if !get_user_folder(self.dir.clone(), email).exists() {
+            return Err(LocalStorageError::UserNotFound);
+        }
+
+        let file = get_user_second_factor(self.dir.clone(), email);
+        let _lock = FileLock::acquire(&file)?;
+        let mut second_factor = match self.get_second_factor(email)? {
+            Some(v) if v.confirmed != confirm => v,
+            _ => return Ok(SecondFactorCheck::Missing),
+        };
+
+        let check = if second_factor.verify(code) {
+            second_factor.confirmed = true;
+            SecondFactorCheck::Totp
+        } else if !confirm && second_factor.use_recovery_code(code) {
+            SecondFactorCheck::RecoveryCode {
+                left: second_factor.recovery_codes_left(),
+            }
+        } else {
+            return Ok(SecondFactorCheck::Wrong);
+        };
+        tracing::debug! {?file, ?check, "store used second factor"};
+        write_yaml(&file, &second_factor)?;
+        Ok(check)
+    }
+
+    fn reset_second_factor(&self, email: &EMail) -> Result<bool, LocalStorageError> {
+        let file = get_user_second_factor(self.dir.clone(), email);
+        if !file.exists() {
+            return Ok(false);
+        }
+
+        tracing::debug! {?file, "reset second factor"};
+        let _lock = FileLock::acquire(&file)?;
+        remove_file(file)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage(dir: &tempfile::TempDir) -> (UserLocalStorage, EMail) {
+        let storage = UserLocalStorage {
+            dir: dir.path().to_path_buf(),
+        };
+        let email = EMail::create("user@example.com").unwrap();
+        std::fs::create_dir_all(get_user_folder(storage.dir.clone(), &email)).unwrap();
+        (storage, email)
+    }
+
+    #[test]
+    fn second_factor_is_confirmed_then_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, email) = storage(&dir);
+        assert_eq!(
+            storage
+                .verify_second_factor(&email, "123456", false)
+                .unwrap(),
+            SecondFactorCheck::Missing
+        );
+
+        let (second_factor, recovery_codes) = SecondFactor::generate();
+        storage.store_second_factor(&email, &second_factor).unwrap();
+        let code = second_factor.current_code();
+        // recovery codes cannot confirm the enrolment
+        assert_eq!(
+            storage
+                .verify_second_factor(&email, &recovery_codes[0], true)
+                .unwrap(),
+            SecondFactorCheck::Wrong
+        );
+        assert_eq!(
+            storage.verify_second_factor(&email, &code, false).unwrap(),
+            SecondFactorCheck::Missing
+        );
+        assert_eq!(
+            storage.verify_second_factor(&email, &code, true).unwrap(),
+            SecondFactorCheck::Totp
+        );
+        assert_eq!(
+            storage.verify_second_factor(&email, &code, true).unwrap(),
+            SecondFactorCheck::Missing
+        );
+        // the code of the confirmation is used up
+        assert_eq!(
+            storage.verify_second_factor(&email, &code, false).unwrap(),
+            SecondFactorCheck::Wrong
+        );
+        assert!(
+            storage
+                .get_second_factor(&email)
+                .unwrap()
+                .unwrap()
+                .confirmed
+        );
+    }
+
+    #[test]
+    fn recovery_code_is_used_once_by_concurrent_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, email) = storage(&dir);
+        let (mut second_factor, recovery_codes) = SecondFactor::generate();
+        second_factor.confirmed = true;
+        storage.store_second_factor(&email, &second_factor).unwrap();
+
+        let checks: Vec<SecondFactorCheck> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        storage
+                            .verify_second_factor(&email, &recovery_codes[0], false)
+                            .unwrap()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|v| v.join().unwrap()).collect()
+        });
+        let used = SecondFactorCheck::RecoveryCode {
+            left: recovery_codes.len() - 1,
+        };
+        assert_eq!(checks.iter().filter(|v| **v == used).count(), 1);
+        assert_eq!(
+            checks
+                .iter()
+                .filter(|v| **v == SecondFactorCheck::Wrong)
+                .count(),
+            3
+        );
+    }
+
+    #[test]
+    fn verify_needs_the_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let (storage, _) = storage(&dir);
+        let other = EMail::create("other@example.com").unwrap();
+        assert!(matches!(
+            storage.verify_second_factor(&other, "123456", false),
+            Err(LocalStorageError::UserNotFound)
+        ));
+    }
 }
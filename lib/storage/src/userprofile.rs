@@ -1,6 +1,12 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::OnceLock};
 
 use crate::{EMail, EMailError};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
 use serde_yaml::Value;
 use thiserror::Error;
 
@@ -25,6 +31,10 @@ pub trait UserFile: Debug {
     fn using_sync15(&self) -> bool;
     fn get_email(&self) -> String;
     fn is_admin(&self) -> bool;
+    /// When the user was created, unknown for users of older versions.
+    fn created(&self) -> Option<DateTime<Utc>>;
+    /// Checks the given password against the stored one.
+    fn verify_password(&self, password: &str) -> bool;
     fn from_yaml(yaml: Value) -> Result<Self, UserProfileError>
     where
         Self: Sized;
@@ -32,70 +42,183 @@ pub trait UserFile: Debug {
 
 pub trait UserLocalFile: UserFile {}
 
+/// The stored password of a user.
+/// Profiles written by older versions hold the password in plaintext,
+/// which will be replaced by an argon2id hash on the next successful login.
+pub enum Password {
+    Hash(String),
+    Plaintext(String),
+}
+
+impl Password {
+    /// Hashes the given password with argon2id and a random salt.
+    pub fn hash(password: &str) -> Self {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .expect("argon2 cannot hash password with default params")
+            .to_string();
+        Password::Hash(hash)
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        match self {
+            Password::Hash(hash) => match PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(v) => {
+                    tracing::debug! {?v, "stored password hash is not valid"};
+                    false
+                }
+            },
+            Password::Plaintext(plain) => {
+                // compare all bytes, so the time does not depend on the matching prefix
+                plain.len() == password.len()
+                    && plain
+                        .bytes()
+                        .zip(password.bytes())
+                        .fold(0, |acc, (a, b)| acc | (a ^ b))
+                        == 0
+            }
+        }
+    }
+
+    pub fn is_plaintext(&self) -> bool {
+        matches!(self, Password::Plaintext(_))
+    }
+
+    /// Verifies against a hash, which matches no password. Takes as long as a real check,
+    /// so the time of a login does not tell, whether the email exists.
+    pub fn verify_dummy(password: &str) -> bool {
+        static DUMMY: OnceLock<Password> = OnceLock::new();
+        DUMMY
+            .get_or_init(|| Password::hash(&random_secret()))
+            .verify(password);
+        false
+    }
+}
+
+/// A random password for the dummy hash, nobody can log in with it.
+fn random_secret() -> String {
+    OsRng
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+impl Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
 #[derive(Debug)]
 pub struct UserProfile {
     pub email: EMail,
-    pub password: String,
+    pub password: Password,
     pub is_admin: bool,
     pub sync15: bool,
+    pub created: Option<DateTime<Utc>>,
+}
+
+impl UserProfile {
+    /// Replaces a plaintext password with its hash.
+    /// Returns true, if the profile was changed and needs to be stored again.
+    pub fn upgrade_password(&mut self, password: &str) -> bool {
+        if self.password.is_plaintext() && self.password.verify(password) {
+            self.password = Password::hash(password);
+            return true;
+        }
+        false
+    }
 }
 
 impl UserFile for UserProfile {
     fn new(email: EMail, password: String, is_admin: bool, sync15: bool) -> Self {
         Self {
             email,
-            password,
+            password: Password::hash(&password),
             is_admin,
             sync15,
+            created: Some(Utc::now()),
         }
     }
 
     fn to_yaml(&self) -> String {
+        let password = match &self.password {
+            Password::Hash(hash) => format!("password_hash: '{}'", hash),
+            Password::Plaintext(plain) => format!("password: {}", plain),
+        };
+        let created = match &self.created {
+            Some(v) => format!("\ncreated: '{}'", v.to_rfc3339()),
+            None => String::new(),
+        };
         format!(
-            "email: {}\npassword: {}\nis_admin: {}\nsync15: {}",
-            self.email.0, self.password, self.is_admin, self.sync15
+            "email: {}\n{}\nis_admin: {}\nsync15: {}{}",
+            self.email.0, password, self.is_admin, self.sync15, created
         )
     }
 
     fn to_json(&self) -> String {
         format!(
-            "{{\"email\":{},\"password\":{},\"is_admin\":{},\"sync15\":{}}}",
-            self.email.0, self.password, self.is_admin, self.sync15
+            "{{\"email\":\"{}\",\"is_admin\":{},\"sync15\":{}}}",
+            self.email.0, self.is_admin, self.sync15
         )
     }
 
     fn from_yaml(yaml: Value) -> Result<Self, UserProfileError> {
         let email = yaml
             .get("email")
-            .ok_or_else(|| UserProfileError::MissingKey("email"))?
+            .ok_or(UserProfileError::MissingKey("email"))?
             .as_str()
-            .ok_or_else(|| UserProfileError::InvalidType("email", "String"))?
+            .ok_or(UserProfileError::InvalidType("email", "String"))?
             .to_string();
 
-        let password = yaml
-            .get("password")
-            .ok_or_else(|| UserProfileError::MissingKey("password"))?
-            .as_str()
-            .ok_or_else(|| UserProfileError::InvalidType("password", "String"))?
-            .to_string();
+        let password = match (yaml.get("password_hash"), yaml.get("password")) {
+            (Some(hash), _) => Password::Hash(
+                hash.as_str()
+                    .ok_or(UserProfileError::InvalidType("password_hash", "String"))?
+                    .to_string(),
+            ),
+            (None, Some(plain)) => Password::Plaintext(
+                plain
+                    .as_str()
+                    .ok_or(UserProfileError::InvalidType("password", "String"))?
+                    .to_string(),
+            ),
+            (None, None) => return Err(UserProfileError::MissingKey("password_hash")),
+        };
 
         let is_admin = yaml
             .get("is_admin")
-            .ok_or_else(|| UserProfileError::MissingKey("is_admin"))?
+            .ok_or(UserProfileError::MissingKey("is_admin"))?
             .as_bool()
-            .ok_or_else(|| UserProfileError::InvalidType("is_admin", "Boolean"))?;
+            .ok_or(UserProfileError::InvalidType("is_admin", "Boolean"))?;
 
         let sync15 = yaml
             .get("sync15")
-            .ok_or_else(|| UserProfileError::MissingKey("sync15"))?
+            .ok_or(UserProfileError::MissingKey("sync15"))?
             .as_bool()
-            .ok_or_else(|| UserProfileError::InvalidType("sync15", "String"))?;
+            .ok_or(UserProfileError::InvalidType("sync15", "String"))?;
+
+        let created = match yaml.get("created") {
+            Some(v) => Some(
+                v.as_str()
+                    .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+                    .ok_or(UserProfileError::InvalidType("created", "DateTime"))?
+                    .with_timezone(&Utc),
+            ),
+            None => None,
+        };
 
         Ok(Self {
             email: EMail::create(&email)?,
             password,
             is_admin,
             sync15,
+            created,
         })
     }
 
@@ -110,5 +233,80 @@ impl UserFile for UserProfile {
     fn is_admin(&self) -> bool {
         self.is_admin
     }
+
+    fn created(&self) -> Option<DateTime<Utc>> {
+        self.created
+    }
+
+    fn verify_password(&self, password: &str) -> bool {
+        self.password.verify(password)
+    }
 }
 impl UserLocalFile for UserProfile {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(password: Password) -> UserProfile {
+        UserProfile {
+            email: EMail::create("user@example.com").unwrap(),
+            password,
+            is_admin: false,
+            sync15: false,
+            created: None,
+        }
+    }
+
+    #[test]
+    fn hash_verifies_only_its_password() {
+        let password = Password::hash("secret");
+        assert!(!password.is_plaintext());
+        assert!(password.verify("secret"));
+        assert!(!password.verify("Secret"));
+        assert!(!password.verify(""));
+    }
+
+    #[test]
+    fn plaintext_is_upgraded_on_the_right_password() {
+        let mut profile = profile(Password::Plaintext("secret".to_string()));
+        assert!(!profile.upgrade_password("wrong"));
+        assert!(profile.password.is_plaintext());
+
+        assert!(profile.upgrade_password("secret"));
+        assert!(!profile.password.is_plaintext());
+        assert!(profile.verify_password("secret"));
+        assert!(!profile.verify_password("wrong"));
+        // already a hash, nothing to store again
+        assert!(!profile.upgrade_password("secret"));
+    }
+
+    #[test]
+    fn plaintext_compares_the_whole_password() {
+        let password = Password::Plaintext("secret".to_string());
+        assert!(password.verify("secret"));
+        assert!(!password.verify("secre"));
+        assert!(!password.verify("secrets"));
+    }
+
+    #[test]
+    fn yaml_keeps_hash_and_reads_plaintext() {
+        let yaml = "email: user@example.com\npassword: secret\nis_admin: true\nsync15: false";
+        let profile = UserProfile::from_yaml(serde_yaml::from_str(yaml).unwrap()).unwrap();
+        assert!(profile.password.is_plaintext());
+
+        let mut profile = profile;
+        profile.upgrade_password("secret");
+        let yaml = profile.to_yaml();
+        assert!(yaml.contains("password_hash: '$argon2id$"));
+        assert!(!yaml.contains("secret"));
+        let read = UserProfile::from_yaml(serde_yaml::from_str(&yaml).unwrap()).unwrap();
+        assert!(read.verify_password("secret"));
+    }
+
+    #[test]
+    fn dummy_matches_nothing() {
+        assert!(!Password::verify_dummy("secret"));
+        assert!(!Password::verify_dummy(""));
+    }
+}
@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+
+/// Represents a webhook, which a user registered for the events of his own library.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserWebhook {
+    pub id: String,
+    pub url: String,
+    pub secret: String,
+    /// names of the events, which should be sent. Empty means all events.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
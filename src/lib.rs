@@ -1,58 +1,83 @@
 use anyhow::{Ok, Result};
-use config::read_config;
-use config::Config;
+use config::{Config, Layered};
+use server::LogFilter;
 use std::path::PathBuf;
-use storage::CodeStorage;
-use storage::UserStorage;
-
-pub struct ServerBuilder<U, C>
-where
-    U: UserStorage,
-    C: CodeStorage,
-{
+use storage::{Registry, Storages};
+
+pub struct ServerBuilder {
     path: PathBuf,
-    user_storage: Box<U>,
-    code_storage: Box<C>,
+    backends: Registry,
+    log_filter: Option<LogFilter>,
 }
 
-impl<U: UserStorage, C: CodeStorage> std::fmt::Display for ServerBuilder<U, C> {
+impl std::fmt::Display for ServerBuilder {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "config path: {}", self.path.display())
     }
 }
 
-impl<U: UserStorage, C: CodeStorage> ServerBuilder<U, C> {
-    pub fn new(path: PathBuf, user_storage: Box<U>, code_storage: Box<C>) -> Self {
+impl ServerBuilder {
+    pub fn new(path: PathBuf) -> Self {
         ServerBuilder {
             path,
-            user_storage,
-            code_storage,
+            backends: Registry::default(),
+            log_filter: None,
         }
     }
 
-    pub fn build(self) -> Result<Server<U, C>> {
+    /// The storage backends, which `[STORAGE]` can choose from. Defaults to the local ones.
+    pub fn backends(mut self, backends: Registry) -> Self {
+        self.backends = backends;
+        self
+    }
+
+    /// Lets the server apply LOGLEVEL, also when the config is reloaded.
+    pub fn log_filter(mut self, log_filter: LogFilter) -> Self {
+        self.log_filter = Some(log_filter);
+        self
+    }
+
+    pub fn build(self) -> Result<Server> {
         println!("Creating server with the following arguments.\n{}\n", self);
+        let layered = Layered::load(&self.path)?;
+        for v in &layered.warnings {
+            tracing::warn! {%v, "config warning"};
+        }
+        let config = layered.config;
+        println!(
+            "storages: users {}, codes {}, attempts {}, audit {}\n",
+            config.storage.users,
+            config.storage.codes,
+            config.storage.attempts,
+            config.storage.audit
+        );
         Ok(Server {
-            config: read_config(&self.path)?,
-            user_storage: self.user_storage,
-            code_storage: self.code_storage,
+            storages: self.backends.build(&config)?,
+            config,
+            path: self.path,
+            log_filter: self.log_filter,
         })
     }
 }
 
-pub struct Server<U, C>
-where
-    U: UserStorage,
-    C: CodeStorage,
-{
+pub struct Server {
+    path: PathBuf,
     config: Config,
-    user_storage: Box<U>,
-    code_storage: Box<C>,
+    log_filter: Option<LogFilter>,
+    storages: Storages,
 }
 
-impl<U: UserStorage, C: CodeStorage> Server<U, C> {
+impl Server {
     pub fn execute(self) -> Result<()> {
-        server::run(self.config, self.user_storage, self.code_storage)?;
+        server::run(
+            self.path,
+            self.config,
+            self.log_filter,
+            self.storages.user,
+            self.storages.code,
+            self.storages.attempt,
+            self.storages.audit,
+        )?;
         Ok(())
     }
 }
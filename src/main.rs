@@ -1,15 +1,13 @@
 use cli::{CLIError, CliArgs, CLI};
 use rmcloud::ServerBuilder;
-use storage::{CodeLocalStorage, UserLocalStorage};
+use storage::Registry;
 
 fn main() -> anyhow::Result<()> {
-    // Here you can specify, which storage should be used.
-    // TODO: the Storage should be handled by the config.toml instead of this thingy here
-    let (args, user_storage, code_storage): (
-        CliArgs,
-        Box<UserLocalStorage>,
-        Box<CodeLocalStorage>,
-    ) = match CLI::parse_args() {
+    let log_filter = CLI::init_logging();
+
+    // further backends are registered here, [STORAGE] in config.toml chooses between them
+    let backends = Registry::default();
+    let args: CliArgs = match CLI::parse_args(&backends) {
         Ok(v) => v,
         Err(CLIError::CommandFound) => return Ok(()), // hide the error, if CLI process something successfully
         Err(v) => return Err(v.into()),
@@ -35,7 +33,9 @@ fn main() -> anyhow::Result<()> {
 "#
     );
 
-    ServerBuilder::new(args.config_path, user_storage, code_storage)
+    ServerBuilder::new(args.config_path)
+        .backends(backends)
+        .log_filter(log_filter)
         .build()?
         .execute()?;
 
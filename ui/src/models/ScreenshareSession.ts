@@ -0,0 +1,28 @@
+/*
+struct SessionInfo {
+    id: String,
+    created_at: DateTime<Utc>,
+    viewers: usize,
+}
+*/
+class ScreenshareSession {
+  id: string;
+  created_at: string;
+  viewers: number;
+
+  constructor(id: string, created_at: string, viewers: number) {
+    this.id = id;
+    this.created_at = created_at;
+    this.viewers = viewers;
+  }
+
+  static fromJSONList(serialized: string): ScreenshareSession[] {
+    let sessions: ScreenshareSession[] = JSON.parse(serialized);
+
+    return sessions.map(
+      (s) => new ScreenshareSession(s.id, s.created_at, s.viewers)
+    );
+  }
+}
+
+export { ScreenshareSession };
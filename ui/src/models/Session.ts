@@ -0,0 +1,109 @@
+/*
+struct LoginResponse {
+    email: String,
+    is_admin: bool,
+    csrf_token: String,
+    second_factor: Option<&'static str>,
+}
+*/
+class Session {
+  email: string;
+  is_admin: boolean;
+  csrf_token: string;
+  // "verify" or "enroll", while the second factor is missing
+  second_factor: string | null;
+
+  constructor(
+    email: string,
+    is_admin: boolean,
+    csrf_token: string,
+    second_factor: string | null
+  ) {
+    this.email = email;
+    this.is_admin = is_admin;
+    this.csrf_token = csrf_token;
+    this.second_factor = second_factor;
+  }
+
+  toObject() {
+    return {
+      email: this.email,
+      is_admin: this.is_admin,
+      csrf_token: this.csrf_token,
+      second_factor: this.second_factor,
+    };
+  }
+
+  serialize() {
+    return JSON.stringify(this.toObject());
+  }
+
+  static fromJSON(serialized: string): Session {
+    let session: ReturnType<Session["toObject"]> = JSON.parse(serialized);
+
+    return new Session(
+      session.email,
+      session.is_admin,
+      session.csrf_token,
+      session.second_factor ?? null
+    );
+  }
+}
+
+/*
+struct SessionInfo {
+    handle: String,
+    email: String,
+    created_at: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+    current: bool,
+}
+*/
+class SessionInfo {
+  handle: string;
+  email: string;
+  created_at: string;
+  last_seen: string;
+  ip: string | null;
+  user_agent: string | null;
+  current: boolean;
+
+  constructor(
+    handle: string,
+    email: string,
+    created_at: string,
+    last_seen: string,
+    ip: string | null,
+    user_agent: string | null,
+    current: boolean
+  ) {
+    this.handle = handle;
+    this.email = email;
+    this.created_at = created_at;
+    this.last_seen = last_seen;
+    this.ip = ip;
+    this.user_agent = user_agent;
+    this.current = current;
+  }
+
+  static fromJSONList(serialized: string): SessionInfo[] {
+    let sessions: SessionInfo[] = JSON.parse(serialized);
+
+    return sessions.map(
+      (s) =>
+        new SessionInfo(
+          s.handle,
+          s.email,
+          s.created_at,
+          s.last_seen,
+          s.ip,
+          s.user_agent,
+          s.current
+        )
+    );
+  }
+}
+
+export { Session, SessionInfo };
@@ -1,2 +1,4 @@
 export * from "./About";
 export * from "./User";
+export * from "./ScreenshareSession";
+export * from "./Session";
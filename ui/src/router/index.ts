@@ -38,16 +38,32 @@ const router = createRouter({
     {
       path: "/users",
       name: "users",
+      meta: { admin: true },
       // route level code-splitting
       // this generates a separate chunk (About.[hash].js) for this route
       // which is lazy-loaded when the route is visited.
       component: () => import("../views/UserListView.vue"),
     },
+    {
+      path: "/screenshare",
+      name: "screenshare",
+      component: () => import("../views/ScreenshareView.vue"),
+    },
     {
       path: "/login",
       name: "login",
       component: () => import("../views/LoginView.vue"),
     },
+    {
+      path: "/second-factor",
+      name: "second-factor",
+      component: () => import("../views/SecondFactorView.vue"),
+    },
+    {
+      path: "/code-forgotten",
+      name: "code-forgotten",
+      component: () => import("../views/CodeForgottenView.vue"),
+    },
 
     { path: "/:pathMatch(.*)*", name: "NotFound", component: NotFound },
   ],
@@ -55,14 +71,28 @@ const router = createRouter({
 
 router.beforeEach(async (to) => {
   // redirect to login page if not logged in and trying to access a restricted page
-  const publicPages = ["/login"];
+  const publicPages = ["/login", "/code-forgotten"];
   const authRequired = !publicPages.includes(to.path);
   const auth = useAuthStore();
 
-  if (authRequired && !auth.user) {
+  if (auth.secondFactorPending()) {
+    return to.path === "/second-factor" || to.path === "/login"
+      ? true
+      : "/second-factor";
+  }
+  if (to.path === "/second-factor") {
+    return "/login";
+  }
+
+  if (authRequired && !auth.authenticated()) {
     auth.returnUrl = to.fullPath || "";
     return "/login";
   }
+
+  // the server checks is_admin for every admin request, this only hides the pages
+  if (to.meta.admin && !auth.isAdmin()) {
+    return "/";
+  }
 });
 
 export default router;
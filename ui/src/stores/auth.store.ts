@@ -3,83 +3,129 @@ import { defineStore } from "pinia";
 
 import router from "~/router";
 import { ElMessage } from "element-plus";
-import { User } from "~/models";
+import { Session, User } from "~/models";
 
 let user: User | null = null;
+let session: Session | null = null;
 let cron: NodeJS.Timer;
 
-const localUser = localStorage.getItem("user");
-if (localUser !== null) {
-  user = User.fromJSON(localUser);
+// The session itself lives in a http-only cookie, only the csrf token and
+// the profile are kept here to stay logged in between page refreshes.
+const localSession = localStorage.getItem("session");
+if (localSession !== null) {
+  session = Session.fromJSON(localSession);
 }
 
+axios.interceptors.request.use((config) => {
+  if (session !== null) {
+    config.headers = { ...config.headers, "X-CSRF-Token": session.csrf_token };
+  }
+  return config;
+});
+
 const authenticated: boolean = false;
 
 export const useAuthStore = defineStore({
   id: "auth",
   state: () => ({
-    // initialize state from local storage to enable user to stay logged in
     user,
+    session,
     authenticated,
     returnUrl: "",
     cron,
   }),
   actions: {
     authenticated() {
-      return this.user !== null;
+      return this.session !== null && this.session.second_factor === null;
+    },
+    secondFactorPending() {
+      return this.session !== null && this.session.second_factor !== null;
     },
-    login(code: string, email: string) {
+    isAdmin() {
+      return this.session?.is_admin === true;
+    },
+    login(email: string, password: string) {
       axios
-        .post(`/login`, {
-          code,
+        .post(`/auth/login`, {
           email,
+          password,
         })
-        .then((user) => {
-          // update pinia state
-          this.user = User.fromJSON(user.data);
-          // store user details and jwt in local storage to keep user logged in between page refreshes
-          console.log(this.user);
-
-          localStorage.setItem("user", this.user.serialize());
-
-          ElMessage.success("Your code was valid. You are logged in now.");
-          // redirect to previous url or default to home page
-          router.push(this.returnUrl || "/");
+        .then((res) => {
+          this.finishLogin(Session.fromJSON(res.data));
           return true;
         })
-        .catch((res) => {
-          ElMessage.error("Login failed. Your code was invalid.");
+        .catch(() => {
+          ElMessage.error("Login failed. Email or password was wrong.");
           return false;
         });
     },
-    logout() {
+    // After the login at the identity provider, the server already set the
+    // session cookie, only the csrf token has to be fetched.
+    loginWithProvider() {
+      axios
+        .get("/auth/me")
+        .then((res) => {
+          this.finishLogin(Session.fromJSON(res.data));
+        })
+        .catch(() => {
+          ElMessage.error("Login at the identity provider failed.");
+        });
+    },
+    // Admins have to pass the second factor, before the session can be used.
+    finishLogin(newSession: Session) {
+      this.setSession(newSession);
+      if (newSession.second_factor !== null) {
+        router.push("/second-factor");
+        return;
+      }
+      this.refreshToken();
+
+      ElMessage.success("You are logged in now.");
+      // redirect to previous url or default to home page
+      router.push(this.returnUrl || "/");
+    },
+    setSession(newSession: Session | null) {
+      this.session = newSession;
+      session = newSession;
+      if (newSession === null) {
+        localStorage.removeItem("session");
+      } else {
+        localStorage.setItem("session", newSession.serialize());
+      }
+    },
+    clear() {
       this.user = null;
-      localStorage.removeItem("user");
+      this.setSession(null);
+      clearInterval(this.cron);
       router.push("/login");
     },
-    check() {
+    logout() {
+      axios.post("/auth/logout").finally(() => this.clear());
+    },
+    // The api expects a jwt, which the server issues for the logged in session.
+    refreshToken() {
       axios
-        .post("/jwt", { jwt: this.user?.jwt })
-        .then((user) => {
-          this.user = User.fromJSON(user.data);
-          localStorage.setItem("user", this.user.serialize());
+        .post("/auth/token")
+        .then((res) => {
+          this.user = User.fromJSON(res.data);
         })
         .catch(() => {
-          if (this.user != null) {
+          if (this.session != null) {
             ElMessage.error(
               "Your login session is not valid anymore. Please login again."
             );
           }
-          clearInterval(this.cron);
-          this.logout();
+          this.clear();
           return false;
         });
     },
     start_check() {
-      this.check();
+      if (!this.authenticated()) {
+        return;
+      }
+      this.refreshToken();
       this.cron = setInterval(() => {
-        console.log("run");
-        this.check();
+        this.refreshToken();
       }, 5 * 60 * 1000);
     },
   },